@@ -1,4 +1,4 @@
-use std::fmt::Debug;
+use std::fmt::{Debug, Display};
 
 #[derive(Clone, PartialEq, Eq, Hash, Copy)]
 pub struct Loc(pub usize, pub usize);
@@ -25,6 +25,13 @@ impl Debug for Loc {
     }
 }
 
+// 行:桁(どちらも1から数える)
+impl Display for Loc {
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.0, self.1)
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct Annot<T> {
     pub value: T,
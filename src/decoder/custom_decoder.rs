@@ -0,0 +1,129 @@
+use super::*;
+
+impl<R> Decoder<R> where R: Read {
+    // 名前以降、セクションの終わりまでを中身として読む
+    pub(super) fn decode_customsection(&mut self, after: SectionId) -> Result<Custom, Error> {
+        let name = self.decode_name()?;
+        let data = self.read_bytes(self.limit - self.offset, "custom section contents")?;
+        Ok(Custom { name, data, after })
+    }
+
+    // nameセクションから識別子を復元する
+    // 独自セクションの中身は検証の対象外なので、壊れている場合は識別子なしとして扱う
+    pub(super) fn decode_names(&mut self, module: &mut Module) {
+        let mut context = Context::default();
+        let mut func_contexts = vec![];
+
+        for import in &module.imports {
+            match &import.2 {
+                ImportDesc::Func(typeidx) => {
+                    context.funcs.push(None);
+                    let params = module.types.get(*typeidx as usize).map_or(0, |ft| ft.0.len());
+                    func_contexts.push(Context { locals: vec![None; params], ..Context::default() });
+                },
+                ImportDesc::Table(_) => context.tables.push(None),
+                ImportDesc::Mem(_) => context.mems.push(None),
+                ImportDesc::Global(_) => context.globals.push(None),
+            }
+        }
+        for func in &module.funcs {
+            context.funcs.push(None);
+            func_contexts.push(Context { locals: vec![None; func.1.len()], ..Context::default() });
+        }
+        context.types = vec![None; module.types.len()];
+        context.tables.extend(module.tables.iter().map(|_| None));
+        context.mems.extend(module.mems.iter().map(|_| None));
+        context.globals.extend(module.globals.iter().map(|_| None));
+        context.typedefs = module.types.clone();
+
+        if let Some(custom) = module.customs.iter().find(|c| c.name == "name") {
+            let mut names = Decoder::new(&custom.data[..]);
+            let mut new_context = context.clone();
+            let mut new_func_contexts = func_contexts.clone();
+            let mut module_id = None;
+            if names.decode_namesubsections(custom.data.len(), &mut module_id, &mut new_context, &mut new_func_contexts).is_ok() {
+                context = new_context;
+                func_contexts = new_func_contexts;
+                if module_id.is_some() { module.id = module_id; }
+            }
+        }
+
+        self.context = context;
+        self.func_contexts = func_contexts;
+    }
+
+    fn decode_namesubsections(&mut self, len: usize, module_id: &mut Option<Id>, context: &mut Context, func_contexts: &mut Vec<Context>) -> Result<(), Error> {
+        while self.offset < len {
+            let id = self.read_byte("name subsection id")?;
+            let size = self.decode_unsigned(32, "name subsection size")? as usize;
+            let begin = self.offset;
+
+            match id {
+                0 => *module_id = Some(self.decode_name()?),
+                1 => self.decode_namemap(&mut context.funcs)?,
+                2 | 3 => self.decode_indirectnamemap(id, func_contexts)?,
+                4 => self.decode_namemap(&mut context.types)?,
+                5 => self.decode_namemap(&mut context.tables)?,
+                6 => self.decode_namemap(&mut context.mems)?,
+                7 => self.decode_namemap(&mut context.globals)?,
+                _ => self.skip_bytes(size)?,
+            }
+
+            if self.offset - begin != size {
+                let kind = DecodeErrorKind::SectionSizeMismatch(size, self.offset - begin);
+                return Err(self.err(kind, self.offset, "end of name subsection"));
+            }
+        }
+        Ok(())
+    }
+
+    fn decode_namemap(&mut self, ids: &mut Vec<Option<Id>>) -> Result<(), Error> {
+        for (idx, name) in self.decode_vec(|d| Ok((d.decode_u32()?, d.decode_name()?)))? {
+            let idx = idx as usize;
+            if ids.len() <= idx { ids.resize(idx + 1, None); }
+            ids[idx] = Some(name);
+        }
+        Ok(())
+    }
+
+    // サブセクション2はlocals、3はlabels
+    fn decode_indirectnamemap(&mut self, subsection: Byte, func_contexts: &mut Vec<Context>) -> Result<(), Error> {
+        let len = self.decode_u32()?;
+        for _ in 0..len {
+            let funcidx = self.decode_u32()? as usize;
+            if func_contexts.len() <= funcidx { func_contexts.resize(funcidx + 1, Context::default()); }
+            let func_context = &mut func_contexts[funcidx];
+            let ids = if subsection == 2 { &mut func_context.locals } else { &mut func_context.labels };
+            self.decode_namemap(ids)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_decode_names() {
+    let bytes = [
+        0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00,
+        0x00, 0x05, 0x04, 0x6D, 0x65, 0x74, 0x61, // "meta"
+        0x01, 0x05, 0x01, 0x60, 0x01, 0x7F, 0x00,
+        0x03, 0x02, 0x01, 0x00,
+        0x0A, 0x06, 0x01, 0x04, 0x01, 0x01, 0x7E, 0x0B,
+        0x00, 0x1A, 0x04, 0x6E, 0x61, 0x6D, 0x65, // "name"
+            0x00, 0x02, 0x01, 0x6D,                   // module "m"
+            0x01, 0x04, 0x01, 0x00, 0x01, 0x66,       // func 0 "f"
+            0x02, 0x09, 0x01, 0x00, 0x02,             // func 0 locals
+                0x00, 0x01, 0x70,                     // 0 "p"
+                0x01, 0x01, 0x6C,                     // 1 "l"
+    ];
+    let mut decoder = Decoder::new(&bytes[..]);
+    let module = decoder.decode().unwrap();
+
+    assert_eq!(module.id, Some("m".to_string()));
+    assert_eq!(module.customs.len(), 2);
+    assert_eq!(module.customs[0], Custom { name: "meta".into(), data: vec![], after: SectionId::Custom });
+    assert_eq!(module.customs[1].name, "name");
+    assert_eq!(module.customs[1].after, SectionId::Code);
+
+    assert_eq!(decoder.context.funcs, vec![Some("f".to_string())]);
+    assert_eq!(decoder.func_contexts[0].locals, vec![Some("p".to_string()), Some("l".to_string())]);
+}
@@ -0,0 +1,41 @@
+use std::fmt::Debug;
+
+use context::TypeIndex;
+use mod2wasm::SectionId;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeErrorKind {
+    UnexpectedEnd,
+    Io,
+    InvalidLeb128,
+    InvalidUtf8,
+    InvalidOpcode(u8),
+    InvalidByte(u8),
+    InvalidSectionId(u8),
+    SectionOutOfOrder(SectionId),
+    SectionSizeMismatch(usize, usize),  // declared, actual
+    FuncCodeMismatch(usize, usize),  // funcセクションの長さ, codeセクションの長さ
+    TooManyLocals,
+    TooDeeplyNested,
+    UnknownType(TypeIndex),
+}
+
+// offsetはストリーム先頭からのバイト位置
+// sectionはデコード中だったセクション(ヘッダやセクションidの読み込み中ならNone)
+#[derive(Clone, PartialEq)]
+pub struct DecodeError {
+    pub kind: DecodeErrorKind,
+    pub offset: usize,
+    pub section: Option<SectionId>,
+    pub expected: &'static str,
+}
+
+impl Debug for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}<{:#x}", self.kind, self.offset)?;
+        if let Some(id) = self.section {
+            write!(f, " in {:?} section", id)?;
+        }
+        write!(f, ", expected {}>", self.expected)
+    }
+}
@@ -0,0 +1,314 @@
+use super::*;
+
+// ブロックの入れ子の深さの上限
+// デコードはスタックを使わないが、デコードした式を使う側は再帰で辿るので深さを抑えておく
+const MAX_DEPTH: usize = 1000;
+
+// 読みかけのブロック
+// outerはブロックの外側の命令列、thenはelseまで読んだifの命令列
+struct OpenBlock {
+    opcode: Byte,
+    rt: ResultType,
+    outer: Vec<Instr>,
+    then: Option<Vec<Instr>>,
+}
+
+macro_rules! memarg {
+    ($this:ident, $instr:expr) => {{
+        let align = $this.decode_u32()?;
+        let offset = $this.decode_u32()?;
+        $instr(MemArg { align, offset })
+    }};
+}
+
+impl<R> Decoder<R> where R: Read {
+    // 'end'(0x0B)までを読む
+    // 入れ子のブロックは再帰せずに、読みかけのブロックを積んで読む
+    pub(super) fn decode_expr(&mut self) -> Result<Expr, Error> {
+        let mut blocks: Vec<OpenBlock> = vec![];
+        let mut instrs = vec![];
+        loop {
+            let opcode = self.read_byte("instruction")?;
+            match opcode {
+                0x02 | 0x03 | 0x04 => {
+                    if blocks.len() >= MAX_DEPTH {
+                        return Err(self.err(DecodeErrorKind::TooDeeplyNested, self.offset - 1, "at most 1000 nested blocks"));
+                    }
+                    let rt = self.decode_blocktype()?;
+                    let outer = std::mem::take(&mut instrs);
+                    blocks.push(OpenBlock { opcode, rt, outer, then: None });
+                },
+                0x05 => match blocks.last_mut() {
+                    Some(block) if block.opcode == 0x04 && block.then.is_none() => {
+                        block.then = Some(std::mem::take(&mut instrs));
+                    },
+                    _ => return Err(self.err(DecodeErrorKind::InvalidOpcode(opcode), self.offset - 1, "end (0x0B)")),
+                },
+                0x0B => {
+                    let block = match blocks.pop() {
+                        Some(block) => block,
+                        None => return Ok(Expr(instrs)),
+                    };
+                    let body = Expr(std::mem::replace(&mut instrs, block.outer));
+                    instrs.push(match (block.opcode, block.then) {
+                        (0x02, _) => Instr::Block(block.rt, body),
+                        (0x03, _) => Instr::Loop(block.rt, body),
+                        (_, Some(then)) => Instr::If(block.rt, Expr(then), body),
+                        (_, None) => Instr::If(block.rt, body, Expr::default()),
+                    });
+                },
+                _ => instrs.push(self.decode_instr(opcode)?),
+            }
+        }
+    }
+
+    fn decode_blocktype(&mut self) -> Result<ResultType, Error> {
+        match self.read_byte("blocktype")? {
+            0x40 => Ok(vec![]),
+            0x7F => Ok(vec![ValType::I32]),
+            0x7E => Ok(vec![ValType::I64]),
+            0x7D => Ok(vec![ValType::F32]),
+            0x7C => Ok(vec![ValType::F64]),
+            b => Err(self.invalid_byte(b, "blocktype")),
+        }
+    }
+
+    fn decode_zero_byte(&mut self) -> Result<(), Error> {
+        match self.read_byte("zero byte")? {
+            0x00 => Ok(()),
+            b => Err(self.invalid_byte(b, "zero byte")),
+        }
+    }
+
+    fn decode_instr(&mut self, opcode: Byte) -> Result<Instr, Error> {
+        let instr = match opcode {
+            // Control Instructions
+            0x00 => Instr::Unreachable,
+            0x01 => Instr::Nop,
+            0x0C => Instr::Br(self.decode_u32()?),
+            0x0D => Instr::BrIf(self.decode_u32()?),
+            0x0E => {
+                let labels = self.decode_vec(Self::decode_u32)?;
+                Instr::BrTable(labels, self.decode_u32()?)
+            },
+            0x0F => Instr::Return,
+            0x10 => Instr::Call(self.decode_u32()?),
+            0x11 => {
+                let typeidx = self.decode_u32()?;
+                self.decode_zero_byte()?;
+                Instr::CallIndirect(typeidx)
+            },
+
+            // Parametric Instructions
+            0x1A => Instr::Drop,
+            0x1B => Instr::Select,
+
+            // Variable Instructions
+            0x20 => Instr::LocalGet(self.decode_u32()?),
+            0x21 => Instr::LocalSet(self.decode_u32()?),
+            0x22 => Instr::LocalTee(self.decode_u32()?),
+            0x23 => Instr::GlobalGet(self.decode_u32()?),
+            0x24 => Instr::GlobalSet(self.decode_u32()?),
+
+            // Memory Instructions
+            0x28 => memarg!(self, |m| Instr::Load(ValType::I32, m)),
+            0x29 => memarg!(self, |m| Instr::Load(ValType::I64, m)),
+            0x2A => memarg!(self, |m| Instr::Load(ValType::F32, m)),
+            0x2B => memarg!(self, |m| Instr::Load(ValType::F64, m)),
+            0x2C => memarg!(self, |m| Instr::ILoad8(ValSize::V32, ValSign::S, m)),
+            0x2D => memarg!(self, |m| Instr::ILoad8(ValSize::V32, ValSign::U, m)),
+            0x2E => memarg!(self, |m| Instr::ILoad16(ValSize::V32, ValSign::S, m)),
+            0x2F => memarg!(self, |m| Instr::ILoad16(ValSize::V32, ValSign::U, m)),
+            0x30 => memarg!(self, |m| Instr::ILoad8(ValSize::V64, ValSign::S, m)),
+            0x31 => memarg!(self, |m| Instr::ILoad8(ValSize::V64, ValSign::U, m)),
+            0x32 => memarg!(self, |m| Instr::ILoad16(ValSize::V64, ValSign::S, m)),
+            0x33 => memarg!(self, |m| Instr::ILoad16(ValSize::V64, ValSign::U, m)),
+            0x34 => memarg!(self, |m| Instr::I64Load32(ValSign::S, m)),
+            0x35 => memarg!(self, |m| Instr::I64Load32(ValSign::U, m)),
+            0x36 => memarg!(self, |m| Instr::Store(ValType::I32, m)),
+            0x37 => memarg!(self, |m| Instr::Store(ValType::I64, m)),
+            0x38 => memarg!(self, |m| Instr::Store(ValType::F32, m)),
+            0x39 => memarg!(self, |m| Instr::Store(ValType::F64, m)),
+            0x3A => memarg!(self, |m| Instr::IStore8(ValSize::V32, m)),
+            0x3B => memarg!(self, |m| Instr::IStore16(ValSize::V32, m)),
+            0x3C => memarg!(self, |m| Instr::IStore8(ValSize::V64, m)),
+            0x3D => memarg!(self, |m| Instr::IStore16(ValSize::V64, m)),
+            0x3E => memarg!(self, Instr::I64Store32),
+            0x3F => { self.decode_zero_byte()?; Instr::MemorySize },
+            0x40 => { self.decode_zero_byte()?; Instr::MemoryGrow },
+
+            // Numeric Instructions
+            0x41 => Instr::I32Const(self.decode_s32()? as u32),
+            0x42 => Instr::I64Const(self.decode_s64()? as u64),
+            0x43 => Instr::F32Const(self.decode_f32()?),
+            0x44 => Instr::F64Const(self.decode_f64()?),
+
+            0x45 => Instr::ITestOp(ValSize::V32, ITestOp::Eqz),
+            0x46 => Instr::IRelOp(ValSize::V32, IRelOp::Eq),
+            0x47 => Instr::IRelOp(ValSize::V32, IRelOp::Ne),
+            0x48 => Instr::IRelOp(ValSize::V32, IRelOp::Lt(ValSign::S)),
+            0x49 => Instr::IRelOp(ValSize::V32, IRelOp::Lt(ValSign::U)),
+            0x4A => Instr::IRelOp(ValSize::V32, IRelOp::Gt(ValSign::S)),
+            0x4B => Instr::IRelOp(ValSize::V32, IRelOp::Gt(ValSign::U)),
+            0x4C => Instr::IRelOp(ValSize::V32, IRelOp::Le(ValSign::S)),
+            0x4D => Instr::IRelOp(ValSize::V32, IRelOp::Le(ValSign::U)),
+            0x4E => Instr::IRelOp(ValSize::V32, IRelOp::Ge(ValSign::S)),
+            0x4F => Instr::IRelOp(ValSize::V32, IRelOp::Ge(ValSign::U)),
+
+            0x50 => Instr::ITestOp(ValSize::V64, ITestOp::Eqz),
+            0x51 => Instr::IRelOp(ValSize::V64, IRelOp::Eq),
+            0x52 => Instr::IRelOp(ValSize::V64, IRelOp::Ne),
+            0x53 => Instr::IRelOp(ValSize::V64, IRelOp::Lt(ValSign::S)),
+            0x54 => Instr::IRelOp(ValSize::V64, IRelOp::Lt(ValSign::U)),
+            0x55 => Instr::IRelOp(ValSize::V64, IRelOp::Gt(ValSign::S)),
+            0x56 => Instr::IRelOp(ValSize::V64, IRelOp::Gt(ValSign::U)),
+            0x57 => Instr::IRelOp(ValSize::V64, IRelOp::Le(ValSign::S)),
+            0x58 => Instr::IRelOp(ValSize::V64, IRelOp::Le(ValSign::U)),
+            0x59 => Instr::IRelOp(ValSize::V64, IRelOp::Ge(ValSign::S)),
+            0x5A => Instr::IRelOp(ValSize::V64, IRelOp::Ge(ValSign::U)),
+
+            0x5B => Instr::FRelOp(ValSize::V32, FRelOp::Eq),
+            0x5C => Instr::FRelOp(ValSize::V32, FRelOp::Ne),
+            0x5D => Instr::FRelOp(ValSize::V32, FRelOp::Lt),
+            0x5E => Instr::FRelOp(ValSize::V32, FRelOp::Gt),
+            0x5F => Instr::FRelOp(ValSize::V32, FRelOp::Le),
+            0x60 => Instr::FRelOp(ValSize::V32, FRelOp::Ge),
+
+            0x61 => Instr::FRelOp(ValSize::V64, FRelOp::Eq),
+            0x62 => Instr::FRelOp(ValSize::V64, FRelOp::Ne),
+            0x63 => Instr::FRelOp(ValSize::V64, FRelOp::Lt),
+            0x64 => Instr::FRelOp(ValSize::V64, FRelOp::Gt),
+            0x65 => Instr::FRelOp(ValSize::V64, FRelOp::Le),
+            0x66 => Instr::FRelOp(ValSize::V64, FRelOp::Ge),
+
+            0x67 => Instr::IUnOp(ValSize::V32, IUnOp::Clz),
+            0x68 => Instr::IUnOp(ValSize::V32, IUnOp::Ctz),
+            0x69 => Instr::IUnOp(ValSize::V32, IUnOp::Popcnt),
+            0x6A => Instr::IBinOp(ValSize::V32, IBinOp::Add),
+            0x6B => Instr::IBinOp(ValSize::V32, IBinOp::Sub),
+            0x6C => Instr::IBinOp(ValSize::V32, IBinOp::Mul),
+            0x6D => Instr::IBinOp(ValSize::V32, IBinOp::Div(ValSign::S)),
+            0x6E => Instr::IBinOp(ValSize::V32, IBinOp::Div(ValSign::U)),
+            0x6F => Instr::IBinOp(ValSize::V32, IBinOp::Rem(ValSign::S)),
+            0x70 => Instr::IBinOp(ValSize::V32, IBinOp::Rem(ValSign::U)),
+            0x71 => Instr::IBinOp(ValSize::V32, IBinOp::And),
+            0x72 => Instr::IBinOp(ValSize::V32, IBinOp::Or),
+            0x73 => Instr::IBinOp(ValSize::V32, IBinOp::Xor),
+            0x74 => Instr::IBinOp(ValSize::V32, IBinOp::Shl),
+            0x75 => Instr::IBinOp(ValSize::V32, IBinOp::Shr(ValSign::S)),
+            0x76 => Instr::IBinOp(ValSize::V32, IBinOp::Shr(ValSign::U)),
+            0x77 => Instr::IBinOp(ValSize::V32, IBinOp::Rotl),
+            0x78 => Instr::IBinOp(ValSize::V32, IBinOp::Rotr),
+
+            0x79 => Instr::IUnOp(ValSize::V64, IUnOp::Clz),
+            0x7A => Instr::IUnOp(ValSize::V64, IUnOp::Ctz),
+            0x7B => Instr::IUnOp(ValSize::V64, IUnOp::Popcnt),
+            0x7C => Instr::IBinOp(ValSize::V64, IBinOp::Add),
+            0x7D => Instr::IBinOp(ValSize::V64, IBinOp::Sub),
+            0x7E => Instr::IBinOp(ValSize::V64, IBinOp::Mul),
+            0x7F => Instr::IBinOp(ValSize::V64, IBinOp::Div(ValSign::S)),
+            0x80 => Instr::IBinOp(ValSize::V64, IBinOp::Div(ValSign::U)),
+            0x81 => Instr::IBinOp(ValSize::V64, IBinOp::Rem(ValSign::S)),
+            0x82 => Instr::IBinOp(ValSize::V64, IBinOp::Rem(ValSign::U)),
+            0x83 => Instr::IBinOp(ValSize::V64, IBinOp::And),
+            0x84 => Instr::IBinOp(ValSize::V64, IBinOp::Or),
+            0x85 => Instr::IBinOp(ValSize::V64, IBinOp::Xor),
+            0x86 => Instr::IBinOp(ValSize::V64, IBinOp::Shl),
+            0x87 => Instr::IBinOp(ValSize::V64, IBinOp::Shr(ValSign::S)),
+            0x88 => Instr::IBinOp(ValSize::V64, IBinOp::Shr(ValSign::U)),
+            0x89 => Instr::IBinOp(ValSize::V64, IBinOp::Rotl),
+            0x8A => Instr::IBinOp(ValSize::V64, IBinOp::Rotr),
+
+            0x8B => Instr::FUnOp(ValSize::V32, FUnOp::Abs),
+            0x8C => Instr::FUnOp(ValSize::V32, FUnOp::Neg),
+            0x8D => Instr::FUnOp(ValSize::V32, FUnOp::Ceil),
+            0x8E => Instr::FUnOp(ValSize::V32, FUnOp::Floor),
+            0x8F => Instr::FUnOp(ValSize::V32, FUnOp::Trunc),
+            0x90 => Instr::FUnOp(ValSize::V32, FUnOp::Nearest),
+            0x91 => Instr::FUnOp(ValSize::V32, FUnOp::Sqrt),
+            0x92 => Instr::FBinOp(ValSize::V32, FBinOp::Add),
+            0x93 => Instr::FBinOp(ValSize::V32, FBinOp::Sub),
+            0x94 => Instr::FBinOp(ValSize::V32, FBinOp::Mul),
+            0x95 => Instr::FBinOp(ValSize::V32, FBinOp::Div),
+            0x96 => Instr::FBinOp(ValSize::V32, FBinOp::Min),
+            0x97 => Instr::FBinOp(ValSize::V32, FBinOp::Max),
+            0x98 => Instr::FBinOp(ValSize::V32, FBinOp::Copysign),
+
+            0x99 => Instr::FUnOp(ValSize::V64, FUnOp::Abs),
+            0x9A => Instr::FUnOp(ValSize::V64, FUnOp::Neg),
+            0x9B => Instr::FUnOp(ValSize::V64, FUnOp::Ceil),
+            0x9C => Instr::FUnOp(ValSize::V64, FUnOp::Floor),
+            0x9D => Instr::FUnOp(ValSize::V64, FUnOp::Trunc),
+            0x9E => Instr::FUnOp(ValSize::V64, FUnOp::Nearest),
+            0x9F => Instr::FUnOp(ValSize::V64, FUnOp::Sqrt),
+            0xA0 => Instr::FBinOp(ValSize::V64, FBinOp::Add),
+            0xA1 => Instr::FBinOp(ValSize::V64, FBinOp::Sub),
+            0xA2 => Instr::FBinOp(ValSize::V64, FBinOp::Mul),
+            0xA3 => Instr::FBinOp(ValSize::V64, FBinOp::Div),
+            0xA4 => Instr::FBinOp(ValSize::V64, FBinOp::Min),
+            0xA5 => Instr::FBinOp(ValSize::V64, FBinOp::Max),
+            0xA6 => Instr::FBinOp(ValSize::V64, FBinOp::Copysign),
+
+            0xA7 => Instr::CvtOp(CvtOp::I32WrapFromI64),
+            0xA8 => Instr::CvtOp(CvtOp::ITruncFromF(ValSize::V32, ValSize::V32, ValSign::S)),
+            0xA9 => Instr::CvtOp(CvtOp::ITruncFromF(ValSize::V32, ValSize::V32, ValSign::U)),
+            0xAA => Instr::CvtOp(CvtOp::ITruncFromF(ValSize::V32, ValSize::V64, ValSign::S)),
+            0xAB => Instr::CvtOp(CvtOp::ITruncFromF(ValSize::V32, ValSize::V64, ValSign::U)),
+            0xAC => Instr::CvtOp(CvtOp::I64ExtendFromI32(ValSign::S)),
+            0xAD => Instr::CvtOp(CvtOp::I64ExtendFromI32(ValSign::U)),
+            0xAE => Instr::CvtOp(CvtOp::ITruncFromF(ValSize::V64, ValSize::V32, ValSign::S)),
+            0xAF => Instr::CvtOp(CvtOp::ITruncFromF(ValSize::V64, ValSize::V32, ValSign::U)),
+            0xB0 => Instr::CvtOp(CvtOp::ITruncFromF(ValSize::V64, ValSize::V64, ValSign::S)),
+            0xB1 => Instr::CvtOp(CvtOp::ITruncFromF(ValSize::V64, ValSize::V64, ValSign::U)),
+            0xB2 => Instr::CvtOp(CvtOp::FConvertFromI(ValSize::V32, ValSize::V32, ValSign::S)),
+            0xB3 => Instr::CvtOp(CvtOp::FConvertFromI(ValSize::V32, ValSize::V32, ValSign::U)),
+            0xB4 => Instr::CvtOp(CvtOp::FConvertFromI(ValSize::V32, ValSize::V64, ValSign::S)),
+            0xB5 => Instr::CvtOp(CvtOp::FConvertFromI(ValSize::V32, ValSize::V64, ValSign::U)),
+            0xB6 => Instr::CvtOp(CvtOp::F32DemoteFromF64),
+            0xB7 => Instr::CvtOp(CvtOp::FConvertFromI(ValSize::V64, ValSize::V32, ValSign::S)),
+            0xB8 => Instr::CvtOp(CvtOp::FConvertFromI(ValSize::V64, ValSize::V32, ValSign::U)),
+            0xB9 => Instr::CvtOp(CvtOp::FConvertFromI(ValSize::V64, ValSize::V64, ValSign::S)),
+            0xBA => Instr::CvtOp(CvtOp::FConvertFromI(ValSize::V64, ValSize::V64, ValSign::U)),
+            0xBB => Instr::CvtOp(CvtOp::F64PromoteFromF32),
+            0xBC => Instr::CvtOp(CvtOp::IReinterpretFromF(ValSize::V32)),
+            0xBD => Instr::CvtOp(CvtOp::IReinterpretFromF(ValSize::V64)),
+            0xBE => Instr::CvtOp(CvtOp::FReinterpretFromI(ValSize::V32)),
+            0xBF => Instr::CvtOp(CvtOp::FReinterpretFromI(ValSize::V64)),
+
+            _ => return Err(self.err(DecodeErrorKind::InvalidOpcode(opcode), self.offset - 1, "instruction")),
+        };
+        Ok(instr)
+    }
+}
+
+#[test]
+fn test_decode_expr() {
+    let bytes = [
+        0x02, 0x40,
+            0x04, 0x7F, 0x41, 0x01, 0x05, 0x41, 0x02, 0x0B,
+            0x1A,
+        0x0B,
+        0x28, 0x02, 0x08,
+        0x0B,
+    ];
+    let expr = Decoder::new(&bytes[..]).decode_expr().unwrap();
+    assert_eq!(expr, Expr(vec![
+        Instr::Block(vec![], Expr(vec![
+            Instr::If(vec![ValType::I32], Expr(vec![Instr::I32Const(1)]), Expr(vec![Instr::I32Const(2)])),
+            Instr::Drop,
+        ])),
+        Instr::Load(ValType::I32, MemArg { align: 2, offset: 8 }),
+    ]));
+
+    // elseのないif
+    let expr = Decoder::new(&[0x04u8, 0x40, 0x01, 0x0B, 0x0B][..]).decode_expr().unwrap();
+    assert_eq!(expr, Expr(vec![Instr::If(vec![], Expr(vec![Instr::Nop]), Expr(vec![]))]));
+
+    // 'else'で終わるexprは不正
+    assert!(Decoder::new(&[0x01u8, 0x05][..]).decode_expr().is_err());
+    // if以外のブロックや、2つ目の'else'も不正
+    assert!(Decoder::new(&[0x02u8, 0x40, 0x05, 0x0B, 0x0B][..]).decode_expr().is_err());
+    assert!(Decoder::new(&[0x04u8, 0x40, 0x05, 0x05, 0x0B, 0x0B][..]).decode_expr().is_err());
+    // 未定義のopcode
+    assert!(Decoder::new(&[0xFFu8, 0x0B][..]).decode_expr().is_err());
+}
@@ -0,0 +1,108 @@
+use std::rc::Rc;
+use std::cell::OnceCell;
+use std::fmt::Debug;
+
+use super::*;
+
+// 遅延デコードする関数本体
+// bytesはcodeセクションの中身全体で、rangeはその中の本体(localsの後からendまで)の位置
+// baseはcodeセクションの中身の、ストリーム先頭からのバイト位置(エラー位置の計算に使う)
+// cloneしてもデコード結果は共有される
+#[derive(Clone)]
+pub struct LazyExpr {
+    bytes: Rc<Vec<Byte>>,
+    range: Range<usize>,
+    base: usize,
+    cache: Rc<OnceCell<Result<Expr, Error>>>,
+}
+
+impl LazyExpr {
+    pub(super) fn new(bytes: Rc<Vec<Byte>>, range: Range<usize>, base: usize) -> Self {
+        LazyExpr { bytes, range, base, cache: Rc::new(OnceCell::new()) }
+    }
+
+    // 最初に呼ばれた時にデコードし、以降はその結果を返す
+    pub fn expr(&self) -> Result<&Expr, Error> {
+        match self.cache.get_or_init(|| self.decode()) {
+            Ok(expr) => Ok(expr),
+            Err(e) => Err(e.clone()),
+        }
+    }
+
+    // デコード前のバイト列(末尾のendを含む)
+    pub fn bytes(&self) -> &[Byte] {
+        &self.bytes[self.range.clone()]
+    }
+
+    pub fn is_decoded(&self) -> bool {
+        self.cache.get().is_some()
+    }
+
+    fn decode(&self) -> Result<Expr, Error> {
+        let mut decoder = Decoder::new(self.bytes());
+        decoder.offset = self.base + self.range.start;
+        decoder.limit = self.base + self.range.end;
+        decoder.section = Some(SectionId::Code);
+
+        let expr = decoder.decode_expr()?;
+
+        if decoder.offset != decoder.limit {
+            let kind = DecodeErrorKind::SectionSizeMismatch(self.range.len(), decoder.offset - self.base - self.range.start);
+            return Err(decoder.err(kind, decoder.offset, "end of function body"));
+        }
+        Ok(expr)
+    }
+}
+
+impl PartialEq for LazyExpr {
+    fn eq(&self, other: &Self) -> bool {
+        self.expr() == other.expr()
+    }
+}
+
+impl Debug for LazyExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.cache.get() {
+            Some(Ok(expr)) => write!(f, "{:?}", expr),
+            _ => write!(f, "Lazy<{:#x}..{:#x}>", self.base + self.range.start, self.base + self.range.end),
+        }
+    }
+}
+
+#[test]
+fn test_decode_lazy() {
+    let bytes = [
+        0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00,
+        0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+        0x03, 0x03, 0x02, 0x00, 0x00,
+        // code section: (local i32) i32.const 1 drop / 不正なオペコード
+        0x0A, 0x0D, 0x02,
+            0x07, 0x01, 0x01, 0x7F, 0x41, 0x01, 0x1A, 0x0B,
+            0x03, 0x00, 0xFF, 0x0B,
+    ];
+    let module = Decoder::new_lazy(&bytes[..]).decode().unwrap();
+    assert_eq!(module.funcs[0].1, vec![ValType::I32]);
+
+    match &module.funcs[0].2 {
+        FuncBody::Lazy(lazy) => {
+            assert!(!lazy.is_decoded());
+            assert_eq!(lazy.bytes(), &[0x41, 0x01, 0x1A, 0x0B]);
+            let cloned = lazy.clone();
+            assert_eq!(lazy.expr().unwrap(), &Expr(vec![Instr::I32Const(1), Instr::Drop]));
+            assert!(cloned.is_decoded());
+        },
+        body => panic!("lazy body expected: {:?}", body),
+    }
+
+    match module.funcs[1].2.expr() {
+        Err(Error::Decode(e)) => {
+            assert_eq!(e.kind, DecodeErrorKind::InvalidOpcode(0xFF));
+            assert_eq!(e.offset, 0x20);
+            assert_eq!(e.section, Some(SectionId::Code));
+        },
+        r => panic!("unexpected result: {:?}", r),
+    }
+
+    // 先にデコードしてもエラーになる
+    assert!(Decoder::new(&bytes[..]).decode().is_err());
+}
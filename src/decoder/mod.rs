@@ -1,26 +1,403 @@
-// use parser::*;
-
-// type Byte = u8;
-
-// fn decode_to_integer(bytes: &[u8]) -> usize {
-//     let mut value = 0usize;
-//     for byte in bytes {
-//         if *byte < 0x80 {
-//             value += *byte as usize;
-//             break;
-//         } else {
-//             use std::convert::TryInto;
-//             let temp = *byte - 0x80;
-//             let temp: usize = temp.try_into().unwrap();
-//             value += temp;
-//         }
-//     }
-//     value
-// }
-
-// #[test]
-// fn test() {
-//     assert_eq!(decode_to_integer(&[0]), 0);
-//     assert_eq!(decode_to_integer(&[100]), 100);
-//     assert_eq!(decode_to_integer(&[200]), 100);
-// }
\ No newline at end of file
+mod error;
+mod value_decoder;
+mod section_decoder;
+mod instr_decoder;
+mod custom_decoder;
+mod lazy_expr;
+
+use std::io::Read;
+use std::ops::Range;
+
+use error::Error;
+use instr::*;
+use context::*;
+use parser::*;
+use mod2wasm::SectionId;
+
+pub use self::error::*;
+pub use self::lazy_expr::*;
+
+type Byte = u8;
+
+pub struct Decoder<R>
+where R: Read {
+    reader: R,
+    offset: usize,
+    // デコード中のセクションの終端と、そのid
+    limit: usize,
+    section: Option<SectionId>,
+    // nameセクションから復元した識別子
+    // func_contextsは関数のインデックス空間(importを含む)ごとのlocalsとlabels
+    pub context: Context,
+    pub func_contexts: Vec<Context>,
+    // trueなら関数本体はFuncBody::Lazyとして範囲だけ記録し、使う時にデコードする
+    lazy: bool,
+}
+
+// ストリーミングでデコードしたセクション
+// rangeはセクションの中身(idとsizeを除く)の、ストリーム先頭からのバイト範囲
+#[derive(Debug)]
+pub struct Section {
+    pub id: SectionId,
+    pub range: Range<usize>,
+    pub payload: Payload,
+}
+
+#[derive(Debug)]
+pub enum Payload {
+    Custom(Custom),
+    Type(Vec<FuncType>),
+    Import(Vec<Import>),
+    Func(Vec<TypeIndex>),
+    Table(Vec<Table>),
+    Memory(Vec<Memory>),
+    Global(Vec<Global>),
+    Export(Vec<Export>),
+    Start(Start),
+    Elem(Vec<Elem>),
+    Code(Vec<Code>),
+    Data(Vec<Data>),
+    Skipped,
+}
+
+// codeセクションの各エントリ
+// Funcと違い、localsにparamsは含まない
+// rangeは関数本体(sizeを除く)の、ストリーム先頭からのバイト範囲
+#[derive(Debug, Clone, PartialEq)]
+pub struct Code {
+    pub locals: Vec<ValType>,
+    pub body: FuncBody,
+    pub range: Range<usize>,
+}
+
+impl<R> Decoder<R> where R: Read {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            offset: 0,
+            limit: usize::MAX,
+            section: None,
+            context: Context::default(),
+            func_contexts: vec![],
+            lazy: false,
+        }
+    }
+
+    // 関数本体のデコードを、最初に使われる時まで遅らせる
+    pub fn new_lazy(reader: R) -> Self {
+        Self { lazy: true, ..Self::new(reader) }
+    }
+
+    pub fn decode(&mut self) -> Result<Module, Error> {
+        let mut module = Module::default();
+        let mut typeindices = vec![];
+        let mut codes = vec![];
+        let mut func_offset = 0;
+
+        self.decode_sections(&[], |section| {
+            if section.id == SectionId::Func { func_offset = section.range.start; }
+            match section.payload {
+                Payload::Type(types) => module.types = types,
+                Payload::Import(imports) => module.imports = imports,
+                Payload::Func(indices) => typeindices = indices,
+                Payload::Table(tables) => module.tables = tables,
+                Payload::Memory(mems) => module.mems = mems,
+                Payload::Global(globals) => module.globals = globals,
+                Payload::Export(exports) => module.exports = exports,
+                Payload::Start(start) => module.start = Some(start),
+                Payload::Elem(elems) => module.elems = elems,
+                Payload::Code(entries) => codes = entries,
+                Payload::Data(data) => module.data = data,
+                Payload::Custom(custom) => module.customs.push(custom),
+                Payload::Skipped => {},
+            }
+            Ok(())
+        })?;
+
+        // codeセクションがなければ、funcセクションも空でなければならない
+        if codes.len() != typeindices.len() {
+            let kind = DecodeErrorKind::FuncCodeMismatch(typeindices.len(), codes.len());
+            return Err(self.err(kind, self.offset, "as many code entries as functions"));
+        }
+
+        // テキストフォーマットのパーサーと同じく、Funcのlocalsにはparamsも含める
+        for (typeidx, code) in typeindices.into_iter().zip(codes) {
+            let functype = match module.types.get(typeidx as usize) {
+                Some(functype) => functype,
+                None => {
+                    self.section = Some(SectionId::Func);
+                    return Err(self.err(DecodeErrorKind::UnknownType(typeidx), func_offset, "type index"));
+                },
+            };
+            let mut locals = functype.0.clone();
+            locals.extend(code.locals);
+            module.funcs.push(Func(typeidx, locals, code.body));
+        }
+
+        self.decode_names(&mut module);
+
+        Ok(module)
+    }
+
+    // セクションを1つ読むごとにfを呼び出す
+    // skipに含まれるセクションは中身をデコードせずに読み飛ばし、Payload::Skippedとして渡す
+    pub fn decode_sections<F>(&mut self, skip: &[SectionId], mut f: F) -> Result<(), Error>
+    where F: FnMut(Section) -> Result<(), Error> {
+        self.decode_magic()?;
+        self.decode_version()?;
+
+        let mut last_id = SectionId::Custom;
+
+        while let Some(b) = self.read_section_id()? {
+            let id_offset = self.offset - 1;
+            let id = match SectionId::from_byte(b) {
+                Some(id) => id,
+                None => return Err(self.err(DecodeErrorKind::InvalidSectionId(b), id_offset, "section id")),
+            };
+
+            // 独自セクション以外は、idの昇順に一度ずつしか現れない
+            if id != SectionId::Custom {
+                if id <= last_id {
+                    return Err(self.err(DecodeErrorKind::SectionOutOfOrder(id), id_offset, "section in order"));
+                }
+                last_id = id;
+            }
+
+            let size = self.decode_unsigned(32, "section size")? as usize;
+            let begin = self.offset;
+            self.limit = begin.saturating_add(size);
+            self.section = Some(id);
+
+            let payload = if skip.contains(&id) {
+                self.skip_bytes(size)?;
+                Payload::Skipped
+            } else {
+                match id {
+                    SectionId::Custom => Payload::Custom(self.decode_customsection(last_id)?),
+                    SectionId::Type => Payload::Type(self.decode_typesection()?),
+                    SectionId::Import => Payload::Import(self.decode_importsection()?),
+                    SectionId::Func => Payload::Func(self.decode_funcsection()?),
+                    SectionId::Table => Payload::Table(self.decode_tablesection()?),
+                    SectionId::Memory => Payload::Memory(self.decode_memorysection()?),
+                    SectionId::Global => Payload::Global(self.decode_globalsection()?),
+                    SectionId::Export => Payload::Export(self.decode_exportsection()?),
+                    SectionId::Start => Payload::Start(self.decode_startsection()?),
+                    SectionId::Elem => Payload::Elem(self.decode_elementsection()?),
+                    SectionId::Code => Payload::Code(self.decode_codesection()?),
+                    SectionId::Data => Payload::Data(self.decode_datasection()?),
+                }
+            };
+
+            if self.offset - begin != size {
+                let kind = DecodeErrorKind::SectionSizeMismatch(size, self.offset - begin);
+                return Err(self.err(kind, self.offset, "end of section"));
+            }
+            self.limit = usize::MAX;
+            self.section = None;
+
+            f(Section { id, range: begin..self.offset, payload })?;
+        }
+
+        Ok(())
+    }
+
+    fn decode_magic(&mut self) -> Result<(), Error> {
+        self.decode_fixed_bytes(b"\0asm", "magic number \\0asm")
+    }
+
+    fn decode_version(&mut self) -> Result<(), Error> {
+        self.decode_fixed_bytes(&[0x01, 0x00, 0x00, 0x00], "version 1")
+    }
+
+    fn decode_fixed_bytes(&mut self, expected_bytes: &[Byte], expected: &'static str) -> Result<(), Error> {
+        let begin = self.offset;
+        let bytes = self.read_bytes(expected_bytes.len(), expected)?;
+        match bytes.iter().zip(expected_bytes).position(|(b, e)| b != e) {
+            Some(i) => Err(self.err(DecodeErrorKind::InvalidByte(bytes[i]), begin + i, expected)),
+            None => Ok(()),
+        }
+    }
+
+    fn read_section_id(&mut self) -> Result<Option<Byte>, Error> {
+        let mut buf = [0; 1];
+        match self.reader.read(&mut buf) {
+            Ok(0) => Ok(None),
+            Ok(_) => {
+                self.offset += 1;
+                Ok(Some(buf[0]))
+            },
+            Err(_) => Err(self.err(DecodeErrorKind::Io, self.offset, "section id")),
+        }
+    }
+
+    fn err(&self, kind: DecodeErrorKind, offset: usize, expected: &'static str) -> Error {
+        Error::Decode(DecodeError { kind, offset, section: self.section, expected })
+    }
+}
+
+#[test]
+fn test_decode_empty_module() {
+    let bytes = b"\0asm\x01\0\0\0";
+    let module = Decoder::new(&bytes[..]).decode().unwrap();
+    assert!(module.types.is_empty());
+    assert!(module.funcs.is_empty());
+
+    assert!(Decoder::new(&b"\0asm\x02\0\0\0"[..]).decode().is_err());
+    assert!(Decoder::new(&b"\0wasm"[..]).decode().is_err());
+}
+
+#[test]
+fn test_decode_func() {
+    let bytes = [
+        0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00,
+        // type section: (func (param i32) (result i32))
+        0x01, 0x06, 0x01, 0x60, 0x01, 0x7F, 0x01, 0x7F,
+        // func section
+        0x03, 0x02, 0x01, 0x00,
+        // export section: "f" func 0
+        0x07, 0x05, 0x01, 0x01, 0x66, 0x00, 0x00,
+        // code section: (local i64) local.get 0 i32.const -1 i32.add
+        0x0A, 0x0B, 0x01, 0x09, 0x01, 0x01, 0x7E, 0x20, 0x00, 0x41, 0x7F, 0x6A, 0x0B,
+    ];
+    let module = Decoder::new(&bytes[..]).decode().unwrap();
+    assert_eq!(module.types, vec![(vec![ValType::I32], vec![ValType::I32])]);
+    assert_eq!(module.funcs, vec![Func(0, vec![ValType::I32, ValType::I64], FuncBody::Expr(Expr(vec![
+        Instr::LocalGet(0),
+        Instr::I32Const(0xFFFFFFFF),
+        Instr::IBinOp(ValSize::V32, IBinOp::Add),
+    ])))]);
+    assert_eq!(module.exports.len(), 1);
+    assert_eq!(module.exports[0].0, "f");
+}
+
+#[test]
+fn test_decode_section_size_mismatch() {
+    let bytes = [
+        0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00,
+        0x01, 0x05, 0x01, 0x60, 0x00, 0x00, 0x00,
+    ];
+    match Decoder::new(&bytes[..]).decode() {
+        Err(Error::Decode(e)) => {
+            assert_eq!(e.kind, DecodeErrorKind::SectionSizeMismatch(5, 4));
+            assert_eq!(e.offset, 14);
+            assert_eq!(e.section, Some(SectionId::Type));
+        },
+        r => panic!("unexpected result: {:?}", r),
+    }
+}
+
+#[cfg(test)]
+fn assert_malformed(bytes: &[u8], kind: DecodeErrorKind, offset: usize, section: Option<SectionId>) {
+    match Decoder::new(bytes).decode() {
+        Err(Error::Decode(e)) => {
+            assert_eq!(e.kind, kind);
+            assert_eq!(e.offset, offset);
+            assert_eq!(e.section, section);
+        },
+        r => panic!("unexpected result: {:?}", r),
+    }
+}
+
+#[test]
+fn test_decode_malformed() {
+    // 途中で終わっているバージョン
+    assert_malformed(b"\0asm\x01\0", DecodeErrorKind::UnexpectedEnd, 6, None);
+
+    // 冗長なLEB128
+    assert_malformed(&[
+        0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00,
+        0x03, 0x06, 0x01, 0x80, 0x80, 0x80, 0x80, 0x10,
+    ], DecodeErrorKind::InvalidLeb128, 11, Some(SectionId::Func));
+
+    // 不正なUTF-8の名前
+    assert_malformed(&[
+        0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00,
+        0x07, 0x06, 0x01, 0x02, 0xC3, 0x28, 0x00, 0x00,
+    ], DecodeErrorKind::InvalidUtf8, 12, Some(SectionId::Export));
+
+    // 不正なオペコード
+    assert_malformed(&[
+        0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00,
+        0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+        0x03, 0x02, 0x01, 0x00,
+        0x0A, 0x05, 0x01, 0x03, 0x00, 0xFF, 0x0B,
+    ], DecodeErrorKind::InvalidOpcode(0xFF), 23, Some(SectionId::Code));
+
+    // 関数本体のサイズを超えて読もうとした
+    assert_malformed(&[
+        0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00,
+        0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+        0x03, 0x02, 0x01, 0x00,
+        0x0A, 0x05, 0x01, 0x02, 0x00, 0x01, 0x0B,
+    ], DecodeErrorKind::UnexpectedEnd, 24, Some(SectionId::Code));
+
+    // 巨大な数のローカル変数(確保する前にエラーにする)
+    assert_malformed(&[
+        0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00,
+        0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+        0x03, 0x02, 0x01, 0x00,
+        0x0A, 0x0A, 0x01, 0x08, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F, 0x7F, 0x0B,
+    ], DecodeErrorKind::TooManyLocals, 29, Some(SectionId::Code));
+    assert_malformed(&[
+        0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00,
+        0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+        0x03, 0x02, 0x01, 0x00,
+        0x0A, 0x0C, 0x01, 0x0A, 0x02, 0xA8, 0xC3, 0x01, 0x7F, 0xA9, 0xC3, 0x01, 0x7E, 0x0B,
+    ], DecodeErrorKind::TooManyLocals, 31, Some(SectionId::Code));
+
+    // 入れ子が深すぎるブロック
+    let nested = |n: usize| {
+        let body = [vec![0x00], [0x02, 0x40].repeat(n), vec![0x0B; n + 1]].concat();
+        let size = body.len() as u32;
+        [
+            vec![0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00],
+            vec![0x01, 0x04, 0x01, 0x60, 0x00, 0x00],
+            vec![0x03, 0x02, 0x01, 0x00],
+            vec![0x0A, ((size + 3) & 0x7F) as u8 | 0x80, ((size + 3) >> 7) as u8, 0x01],
+            vec![(size & 0x7F) as u8 | 0x80, (size >> 7) as u8],
+            body,
+        ].concat()
+    };
+    assert_malformed(&nested(1001), DecodeErrorKind::TooDeeplyNested, 25 + 2000, Some(SectionId::Code));
+    assert!(Decoder::new(&nested(1000)[..]).decode().is_ok());
+}
+
+#[test]
+fn test_decode_sections() {
+    let bytes = [
+        0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00,
+        0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+        0x03, 0x02, 0x01, 0x00,
+        0x07, 0x05, 0x01, 0x01, 0x66, 0x00, 0x00,
+        0x0A, 0x04, 0x01, 0x02, 0x00, 0x0B,
+    ];
+    let mut sections = vec![];
+    Decoder::new(&bytes[..]).decode_sections(&[SectionId::Code], |section| {
+        sections.push(section);
+        Ok(())
+    }).unwrap();
+
+    let ids = sections.iter().map(|s| (s.id, s.range.clone())).collect::<Vec<_>>();
+    assert_eq!(ids, vec![
+        (SectionId::Type, 10..14),
+        (SectionId::Func, 16..18),
+        (SectionId::Export, 20..25),
+        (SectionId::Code, 27..31),
+    ]);
+    match &sections[2].payload {
+        Payload::Export(exports) => assert_eq!(exports[0].0, "f"),
+        _ => panic!("export section expected"),
+    }
+    match &sections[3].payload {
+        Payload::Skipped => {},
+        _ => panic!("code section should be skipped"),
+    }
+
+    // 順序が不正なセクション
+    let bytes = [
+        0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00,
+        0x03, 0x01, 0x00,
+        0x01, 0x01, 0x00,
+    ];
+    assert!(Decoder::new(&bytes[..]).decode_sections(&[], |_| Ok(())).is_err());
+}
@@ -0,0 +1,144 @@
+use std::rc::Rc;
+
+use super::*;
+
+// 1つの関数のローカル変数(引数を除く)の数の上限
+const MAX_LOCALS: u64 = 50_000;
+
+impl<R> Decoder<R> where R: Read {
+    pub(super) fn decode_typesection(&mut self) -> Result<Vec<FuncType>, Error> {
+        self.decode_vec(Self::decode_functype)
+    }
+
+    pub(super) fn decode_importsection(&mut self) -> Result<Vec<Import>, Error> {
+        self.decode_vec(Self::decode_import)
+    }
+
+    pub(super) fn decode_funcsection(&mut self) -> Result<Vec<TypeIndex>, Error> {
+        self.decode_vec(Self::decode_u32)
+    }
+
+    pub(super) fn decode_tablesection(&mut self) -> Result<Vec<Table>, Error> {
+        self.decode_vec(|d| Ok(Table(d.decode_tabletype()?)))
+    }
+
+    pub(super) fn decode_memorysection(&mut self) -> Result<Vec<Memory>, Error> {
+        self.decode_vec(|d| Ok(Memory(d.decode_memtype()?)))
+    }
+
+    pub(super) fn decode_globalsection(&mut self) -> Result<Vec<Global>, Error> {
+        self.decode_vec(|d| {
+            let gt = d.decode_globaltype()?;
+            let expr = d.decode_expr()?;
+            Ok(Global(gt, expr))
+        })
+    }
+
+    pub(super) fn decode_exportsection(&mut self) -> Result<Vec<Export>, Error> {
+        self.decode_vec(Self::decode_export)
+    }
+
+    pub(super) fn decode_startsection(&mut self) -> Result<Start, Error> {
+        Ok(Start(self.decode_u32()?))
+    }
+
+    pub(super) fn decode_elementsection(&mut self) -> Result<Vec<Elem>, Error> {
+        self.decode_vec(|d| {
+            let table = d.decode_u32()?;
+            let offset = d.decode_expr()?;
+            let init = d.decode_vec(Self::decode_u32)?;
+            Ok(Elem { table, offset, init })
+        })
+    }
+
+    pub(super) fn decode_codesection(&mut self) -> Result<Vec<Code>, Error> {
+        if !self.lazy {
+            return self.decode_vec(|d| d.decode_code(|d| Ok(FuncBody::Expr(d.decode_expr()?))));
+        }
+
+        // セクション全体を読み込んでおき、各本体はその中の範囲だけを記録する
+        let base = self.offset;
+        let bytes = Rc::new(self.read_bytes(self.limit - self.offset, "code section")?);
+        let mut decoder = Decoder::new(&bytes[..]);
+        decoder.offset = base;
+        decoder.limit = self.offset;
+        decoder.section = self.section;
+
+        let codes = decoder.decode_vec(|d| d.decode_code(|d| {
+            let range = d.offset - base..d.limit - base;
+            d.skip_bytes(d.limit - d.offset)?;
+            Ok(FuncBody::Lazy(LazyExpr::new(bytes.clone(), range, base)))
+        }))?;
+
+        if decoder.offset != self.offset {
+            let kind = DecodeErrorKind::SectionSizeMismatch(self.offset - base, decoder.offset - base);
+            return Err(decoder.err(kind, decoder.offset, "end of section"));
+        }
+        Ok(codes)
+    }
+
+    pub(super) fn decode_datasection(&mut self) -> Result<Vec<Data>, Error> {
+        self.decode_vec(|d| {
+            let memidx = d.decode_u32()?;
+            let offset = d.decode_expr()?;
+            let len = d.decode_unsigned(32, "data length")? as usize;
+            let init = d.read_bytes(len, "data")?;
+            Ok(Data { data: memidx, offset, init })
+        })
+    }
+
+    fn decode_import(&mut self) -> Result<Import, Error> {
+        let module = self.decode_name()?;
+        let name = self.decode_name()?;
+        let desc = match self.read_byte("import kind")? {
+            0x00 => ImportDesc::Func(self.decode_u32()?),
+            0x01 => ImportDesc::Table(self.decode_tabletype()?),
+            0x02 => ImportDesc::Mem(self.decode_memtype()?),
+            0x03 => ImportDesc::Global(self.decode_globaltype()?),
+            b => return Err(self.invalid_byte(b, "import kind (0x00 to 0x03)")),
+        };
+        Ok(Import(module, name, desc))
+    }
+
+    fn decode_export(&mut self) -> Result<Export, Error> {
+        let name = self.decode_name()?;
+        let desc = match self.read_byte("export kind")? {
+            0x00 => ExportDesc::Func(self.decode_u32()?),
+            0x01 => ExportDesc::Table(self.decode_u32()?),
+            0x02 => ExportDesc::Mem(self.decode_u32()?),
+            0x03 => ExportDesc::Global(self.decode_u32()?),
+            b => return Err(self.invalid_byte(b, "export kind (0x00 to 0x03)")),
+        };
+        Ok(Export(name, desc))
+    }
+
+    // 本体はbodyで読む
+    fn decode_code<F>(&mut self, body: F) -> Result<Code, Error>
+    where F: FnOnce(&mut Self) -> Result<FuncBody, Error> {
+        let size = self.decode_unsigned(32, "function body size")? as usize;
+        let begin = self.offset;
+        let section_limit = self.limit;
+        self.limit = std::cmp::min(section_limit, begin.saturating_add(size));
+
+        // 数個のバイトで巨大な数のローカル変数を宣言できるので、確保する前に上限を確かめる
+        let mut locals = vec![];
+        let mut count = 0u64;
+        for (n, vt) in self.decode_vec(|d| Ok((d.decode_u32()?, d.decode_valtype()?)))? {
+            count += n as u64;
+            if count > MAX_LOCALS {
+                return Err(self.err(DecodeErrorKind::TooManyLocals, self.offset, "at most 50000 locals"));
+            }
+            locals.extend(std::iter::repeat_n(vt, n as usize));
+        }
+
+        let body = body(self)?;
+
+        if self.offset - begin != size {
+            let kind = DecodeErrorKind::SectionSizeMismatch(size, self.offset - begin);
+            return Err(self.err(kind, self.offset, "end of function body"));
+        }
+        self.limit = section_limit;
+
+        Ok(Code { locals, body, range: begin..self.offset })
+    }
+}
@@ -0,0 +1,224 @@
+use super::*;
+
+impl<R> Decoder<R> where R: Read {
+    pub(super) fn read_byte(&mut self, expected: &'static str) -> Result<Byte, Error> {
+        if self.offset >= self.limit {
+            return Err(self.err(DecodeErrorKind::UnexpectedEnd, self.offset, expected));
+        }
+        let mut buf = [0; 1];
+        match self.reader.read_exact(&mut buf) {
+            Ok(()) => {
+                self.offset += 1;
+                Ok(buf[0])
+            },
+            Err(e) => Err(self.io_err(e, expected)),
+        }
+    }
+
+    pub(super) fn read_bytes(&mut self, len: usize, expected: &'static str) -> Result<Vec<Byte>, Error> {
+        let available = std::cmp::min(len, self.limit - self.offset);
+        let mut buf = vec![];
+        if let Err(e) = (&mut self.reader).take(available as u64).read_to_end(&mut buf) {
+            return Err(self.io_err(e, expected));
+        }
+        self.offset += buf.len();
+        if buf.len() != len {
+            return Err(self.err(DecodeErrorKind::UnexpectedEnd, self.offset, expected));
+        }
+        Ok(buf)
+    }
+
+    pub(super) fn skip_bytes(&mut self, len: usize) -> Result<(), Error> {
+        let available = std::cmp::min(len, self.limit - self.offset);
+        let skipped = match std::io::copy(&mut (&mut self.reader).take(available as u64), &mut std::io::sink()) {
+            Ok(skipped) => skipped as usize,
+            Err(e) => return Err(self.io_err(e, "section contents")),
+        };
+        self.offset += skipped;
+        if skipped != len {
+            return Err(self.err(DecodeErrorKind::UnexpectedEnd, self.offset, "section contents"));
+        }
+        Ok(())
+    }
+
+    fn io_err(&self, e: std::io::Error, expected: &'static str) -> Error {
+        let kind = if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            DecodeErrorKind::UnexpectedEnd
+        } else {
+            DecodeErrorKind::Io
+        };
+        self.err(kind, self.offset, expected)
+    }
+
+    pub(super) fn decode_vec<T, F>(&mut self, mut f: F) -> Result<Vec<T>, Error>
+    where F: FnMut(&mut Self) -> Result<T, Error> {
+        let len = self.decode_unsigned(32, "vector length")?;
+        let mut items = vec![];
+        for _ in 0..len {
+            items.push(f(self)?);
+        }
+        Ok(items)
+    }
+
+    pub(super) fn decode_u32(&mut self) -> Result<u32, Error> {
+        Ok(self.decode_unsigned(32, "u32")? as u32)
+    }
+
+    pub(super) fn decode_s32(&mut self) -> Result<i32, Error> {
+        Ok(self.decode_signed(32, "i32")? as i32)
+    }
+
+    pub(super) fn decode_s64(&mut self) -> Result<i64, Error> {
+        self.decode_signed(64, "i64")
+    }
+
+    pub(super) fn decode_f32(&mut self) -> Result<f32, Error> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(&self.read_bytes(4, "f32")?);
+        Ok(f32::from_bits(u32::from_le_bytes(buf)))
+    }
+
+    pub(super) fn decode_f64(&mut self) -> Result<f64, Error> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(&self.read_bytes(8, "f64")?);
+        Ok(f64::from_bits(u64::from_le_bytes(buf)))
+    }
+
+    // LEB128の長さは最大でceil(N/7)バイトで、最後のバイトの使われないビットは0でなければならない
+    pub(super) fn decode_unsigned(&mut self, bits: u32, expected: &'static str) -> Result<u64, Error> {
+        let begin = self.offset;
+        let mut result = 0u64;
+        let mut shift = 0;
+        loop {
+            let b = self.read_byte(expected)?;
+            let rest = bits - shift;
+            if rest < 7 && (b & 0x7F) >> rest != 0 {
+                return Err(self.err(DecodeErrorKind::InvalidLeb128, begin, expected));
+            }
+            result |= ((b & 0x7F) as u64) << shift;
+            if b & 0x80 == 0 { return Ok(result); }
+            shift += 7;
+            if shift >= bits {
+                return Err(self.err(DecodeErrorKind::InvalidLeb128, begin, expected));
+            }
+        }
+    }
+
+    // 最後のバイトの使われないビットは、符号ビットと同じでなければならない
+    fn decode_signed(&mut self, bits: u32, expected: &'static str) -> Result<i64, Error> {
+        let begin = self.offset;
+        let mut result = 0i64;
+        let mut shift = 0;
+        loop {
+            let b = self.read_byte(expected)?;
+            let rest = bits - shift;
+            if rest < 7 {
+                let unused = ((b & 0x7F) as i8) << 1 >> rest;
+                if unused != 0 && unused != -1 {
+                    return Err(self.err(DecodeErrorKind::InvalidLeb128, begin, expected));
+                }
+            }
+            result |= ((b & 0x7F) as i64) << shift;
+            shift += 7;
+            if b & 0x80 == 0 {
+                if shift < 64 && b & 0x40 != 0 {
+                    result |= -1i64 << shift;
+                }
+                return Ok(result);
+            }
+            if shift >= bits {
+                return Err(self.err(DecodeErrorKind::InvalidLeb128, begin, expected));
+            }
+        }
+    }
+
+    pub(super) fn decode_name(&mut self) -> Result<Name, Error> {
+        let len = self.decode_unsigned(32, "name length")? as usize;
+        let begin = self.offset;
+        let bytes = self.read_bytes(len, "name")?;
+        match String::from_utf8(bytes) {
+            Ok(name) => Ok(name),
+            Err(e) => {
+                let offset = begin + e.utf8_error().valid_up_to();
+                Err(self.err(DecodeErrorKind::InvalidUtf8, offset, "UTF-8 name"))
+            },
+        }
+    }
+
+    // 期待したバイトでなかった場合のエラー(直前に読んだバイトを指す)
+    pub(super) fn invalid_byte(&self, b: Byte, expected: &'static str) -> Error {
+        self.err(DecodeErrorKind::InvalidByte(b), self.offset - 1, expected)
+    }
+
+    pub(super) fn decode_valtype(&mut self) -> Result<ValType, Error> {
+        match self.read_byte("valtype")? {
+            0x7F => Ok(ValType::I32),
+            0x7E => Ok(ValType::I64),
+            0x7D => Ok(ValType::F32),
+            0x7C => Ok(ValType::F64),
+            b => Err(self.invalid_byte(b, "valtype")),
+        }
+    }
+
+    pub(super) fn decode_functype(&mut self) -> Result<FuncType, Error> {
+        let b = self.read_byte("functype (0x60)")?;
+        if b != 0x60 { return Err(self.invalid_byte(b, "functype (0x60)")); }
+        let params = self.decode_vec(Self::decode_valtype)?;
+        let results = self.decode_vec(Self::decode_valtype)?;
+        Ok((params, results))
+    }
+
+    pub(super) fn decode_limits(&mut self) -> Result<Limits, Error> {
+        match self.read_byte("limits flag")? {
+            0x00 => Ok(Limits { min: self.decode_u32()?, max: None }),
+            0x01 => {
+                let min = self.decode_u32()?;
+                let max = self.decode_u32()?;
+                Ok(Limits { min, max: Some(max) })
+            },
+            b => Err(self.invalid_byte(b, "limits flag (0x00 or 0x01)")),
+        }
+    }
+
+    pub(super) fn decode_tabletype(&mut self) -> Result<TableType, Error> {
+        let b = self.read_byte("elemtype (0x70)")?;
+        if b != 0x70 { return Err(self.invalid_byte(b, "elemtype (0x70)")); }
+        let limits = self.decode_limits()?;
+        Ok(TableType { limits, elem_type: ElemType::FuncRef })
+    }
+
+    pub(super) fn decode_memtype(&mut self) -> Result<MemType, Error> {
+        Ok(MemType(self.decode_limits()?))
+    }
+
+    pub(super) fn decode_globaltype(&mut self) -> Result<GlobalType, Error> {
+        let vt = self.decode_valtype()?;
+        let mutablity = match self.read_byte("mutability")? {
+            0x00 => Mutablity::Const,
+            0x01 => Mutablity::Var,
+            b => return Err(self.invalid_byte(b, "mutability (0x00 or 0x01)")),
+        };
+        Ok(GlobalType(mutablity, vt))
+    }
+}
+
+#[test]
+fn test_decode_u32() {
+    assert_eq!(Decoder::new(&[1u8][..]).decode_u32().unwrap(), 1);
+    assert_eq!(Decoder::new(&[0x80u8, 0x01][..]).decode_u32().unwrap(), 0x80);
+    assert_eq!(Decoder::new(&[0xE5u8, 0x8E, 0x26][..]).decode_u32().unwrap(), 624485);
+    assert_eq!(Decoder::new(&[0xFFu8, 0xFF, 0xFF, 0xFF, 0x0F][..]).decode_u32().unwrap(), 0xFFFFFFFF);
+    assert!(Decoder::new(&[0xFFu8, 0xFF, 0xFF, 0xFF, 0x1F][..]).decode_u32().is_err());
+    assert!(Decoder::new(&[0x80u8, 0x80, 0x80, 0x80, 0x80, 0x00][..]).decode_u32().is_err());
+    assert!(Decoder::new(&[0x80u8][..]).decode_u32().is_err());
+}
+
+#[test]
+fn test_decode_signed() {
+    assert_eq!(Decoder::new(&[0x7Fu8][..]).decode_s32().unwrap(), -1);
+    assert_eq!(Decoder::new(&[0x3Fu8][..]).decode_s32().unwrap(), 63);
+    assert_eq!(Decoder::new(&[0xC0u8, 0xBB, 0x78][..]).decode_s32().unwrap(), -123456);
+    assert_eq!(Decoder::new(&[0x80u8, 0x80, 0x80, 0x80, 0x78][..]).decode_s32().unwrap(), i32::MIN);
+    assert!(Decoder::new(&[0x80u8, 0x80, 0x80, 0x80, 0x70][..]).decode_s32().is_err());
+    assert_eq!(Decoder::new(&[0x80u8, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x7F][..]).decode_s64().unwrap(), i64::MIN);
+}
@@ -1,4 +1,23 @@
-#[derive(Debug)]
+use decoder::DecodeError;
+use mod2wasm::EncodeError;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Error {
-    Decode,
-}
\ No newline at end of file
+    Decode(DecodeError),
+    Encode(EncodeError),
+    Io(std::io::ErrorKind),
+    // 実行前の検証で見つかった誤り
+    Invalid(&'static str),
+}
+
+impl From<DecodeError> for Error {
+    fn from(e: DecodeError) -> Self { Error::Decode(e) }
+}
+
+impl From<EncodeError> for Error {
+    fn from(e: EncodeError) -> Self { Error::Encode(e) }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self { Error::Io(e.kind()) }
+}
@@ -1,6 +1,7 @@
 use std::io::{Read, Seek};
 use super::error::*;
-use super::Lexer;
+use super::{Lexer, LexResult, Token, TokenKind};
+use annot::Loc;
 
 macro_rules! lex_line_comment { ($this:ident, $reader:expr) => { {
     let mut buf: &mut [u8] = &mut [0;1];
@@ -34,67 +35,71 @@ macro_rules! lex_line_comment { ($this:ident, $reader:expr) => { {
 
 impl<R> Lexer<R> where R: Read + Seek {
 
+// '(;'の後から、対応する';)'まで読み飛ばす
+// 入れ子のコメントは深さで数える
 pub(super) fn lex_block_comment(&mut self) -> Result<(), LexError>
     where R: Read + Seek {
 
-    let mut com_c = self.read()?;
+    let mut depth = 1;
+    let mut prev = 0;
     loop {
+        let com_c = self.read()?;
         match com_c {
-            // maybe start of child block comment
-            b'(' => {
-                self.loc.add_pos();
-
-                let com_c2 = self.read()?;
-                if com_c2 == b';' {
-                    // start child block comment...
-                    self.lex_block_comment()?
-                }
-                com_c = com_c2;
-                continue;
-            }
-
-            // maybe end of block comment
-            b';' => {
-                self.loc.add_pos();
-
-                let com_c2 = self.read()?;
-                self.loc.add_pos();  // anyway add pos
-                if com_c2 == b')' {
-                    // end of block comment
-                    return Ok(());
-                }
-                com_c = com_c2;
-                continue;
-            }
-
             // space (LF)
-            b'\n' => {
-                self.loc.newline();
-            },
+            b'\n' => self.loc.newline(),
 
             // space (CR)
             b'\r' => {},
 
-            // count byte as codepoint (not utf-8 bit pattern)
-            0x00 ..= 0x7F => self.loc.add_pos(),
-            0xC2 ..= 0xDF | 0xE0 ..= 0xEF => {
-                self.loc.add_pos();
-                self.read()?;
-            },
-            0xF0 ..= 0xF7 => {
-                self.loc.add_pos();
-                self.read()?;
-                self.read()?;                
-            },
-
             // EOF
             0xFF => return Err(LexError::eof(self.loc)),
 
-            _ => { self.loc.add_pos(); },
+            // UTF-8の後続バイトは数えない(1文字を1桁とする)
+            0x80 ..= 0xBF => {},
+
+            _ => self.loc.add_pos(),
         }
 
-        com_c = self.read()?;
+        match (prev, com_c) {
+            // start of child block comment
+            (b'(', b';') => depth += 1,
+            // end of block comment
+            (b';', b')') => {
+                depth -= 1;
+                if depth == 0 { return Ok(()); }
+            },
+            _ => {
+                prev = com_c;
+                continue;
+            },
+        }
+        // '(;'と';)'の文字は、次の区切りには使わない
+        prev = 0;
+    }
+}
+
+// '(@name'の後から、対応する')'までのトークンを1つの注釈トークンにする
+// 中身は括弧の対応が取れていれば何でもよい
+pub(super) fn lex_annotation(&mut self, name: String, begin: Loc) -> LexResult {
+    if name.is_empty() {
+        return Err(LexError::invalid_char(self.current, self.loc));
     }
+
+    let mut tokens = vec![];
+    let mut depth = 0;
+    loop {
+        let token = self.next_token_internal()?;
+        match token.value {
+            TokenKind::LeftParen => depth += 1,
+            TokenKind::RightParen if depth == 0 => break,
+            TokenKind::RightParen => depth -= 1,
+            TokenKind::Empty => return Err(LexError::eof(self.loc)),
+            _ => {},
+        }
+        tokens.push(token);
+    }
+
+    Ok(Token::annotation(name, tokens, begin))
 }
 
-}
\ No newline at end of file
+}
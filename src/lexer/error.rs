@@ -37,3 +37,22 @@ impl Debug for LexError {
         write!(f, "{:?}<{:?}>", self.value, self.loc)
     }
 }
+
+impl std::fmt::Display for LexErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LexErrorKind::InvalidChar(c) if c.is_ascii_graphic() => write!(f, "invalid character `{}`", *c as char),
+            LexErrorKind::InvalidChar(c) => write!(f, "invalid byte 0x{:02X}", c),
+            LexErrorKind::Io => write!(f, "failed to read source"),
+            LexErrorKind::Eof => write!(f, "unexpected end of file"),
+        }
+    }
+}
+
+impl std::fmt::Display for LexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.loc, self.value)
+    }
+}
+
+impl std::error::Error for LexError {}
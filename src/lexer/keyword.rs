@@ -24,6 +24,7 @@ pub enum Keyword {
     Offset,
     FuncRef,
     Else,
+    Then,
     End,
 
     ValType(ValType),
@@ -31,6 +32,41 @@ pub enum Keyword {
     Instr(Instr),
 }
 
+// 命令は種類が多いので、名前ではなく"instruction"とだけ表示する
+impl std::fmt::Display for Keyword {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Keyword::Module => "module",
+            Keyword::Type => "type",
+            Keyword::Import => "import",
+            Keyword::Func => "func",
+            Keyword::Table => "table",
+            Keyword::Memory => "memory",
+            Keyword::Global => "global",
+            Keyword::Export => "export",
+            Keyword::Start => "start",
+            Keyword::Elem => "elem",
+            Keyword::Data => "data",
+            Keyword::Local => "local",
+            Keyword::Param => "param",
+            Keyword::Result => "result",
+            Keyword::AnyFunc => "anyfunc",
+            Keyword::Mutable => "mut",
+            Keyword::Offset => "offset",
+            Keyword::FuncRef => "funcref",
+            Keyword::Else => "else",
+            Keyword::Then => "then",
+            Keyword::End => "end",
+            Keyword::ValType(ValType::I32) => "i32",
+            Keyword::ValType(ValType::I64) => "i64",
+            Keyword::ValType(ValType::F32) => "f32",
+            Keyword::ValType(ValType::F64) => "f64",
+            Keyword::Instr(_) => return write!(f, "instruction"),
+        };
+        write!(f, "`{}`", s)
+    }
+}
+
 pub(super) fn vec_to_keyword(s: &[u8]) -> Option<Keyword> {
     match s {
         b"module" => Some(Keyword::Module),
@@ -54,6 +90,7 @@ pub(super) fn vec_to_keyword(s: &[u8]) -> Option<Keyword> {
         b"offset" => Some(Keyword::Offset),
         b"funcref" => Some(Keyword::FuncRef),
         b"else" => Some(Keyword::Else),
+        b"then" => Some(Keyword::Then),
         b"end" => Some(Keyword::End),
 
         b"i32" | b"i64" | b"f32" | b"f64" => vec_to_valtype(s).map(|vt| Keyword::ValType(vt)),
@@ -84,53 +121,57 @@ fn vec_to_instr(s: &[u8]) -> Option<Instr> {
         b"drop" => Some(Instr::Drop),
         b"select" => Some(Instr::Select),
 
-        b"local.get" => Some(Instr::LocalGet(0)),
-        b"local.set" => Some(Instr::LocalSet(0)),
-        b"local.tee" => Some(Instr::LocalTee(0)),
-        b"global.get" => Some(Instr::GlobalGet(0)),
-        b"global.set" => Some(Instr::GlobalSet(0)),
+        // 旧名(get_localなど)も受け付ける
+        b"local.get" | b"get_local" => Some(Instr::LocalGet(0)),
+        b"local.set" | b"set_local" => Some(Instr::LocalSet(0)),
+        b"local.tee" | b"tee_local" => Some(Instr::LocalTee(0)),
+        b"global.get" | b"get_global" => Some(Instr::GlobalGet(0)),
+        b"global.set" | b"set_global" => Some(Instr::GlobalSet(0)),
 
         b"i64.store32" => Some(Instr::I64Store32(memarg)),
         b"memory.size" => Some(Instr::MemorySize),
         b"memory.grow" => Some(Instr::MemoryGrow),
 
-        b"i32.wrap/i64" => Some(Instr::CvtOp(CvtOp::I32WrapFromI64)),
-        b"i64.extend_s/i32" => Some(Instr::CvtOp(CvtOp::I64ExtendFromI32(ValSign::S))),
-        b"i64.extend_u/i32" => Some(Instr::CvtOp(CvtOp::I64ExtendFromI32(ValSign::U))),
-
-        b"i32.trunc_s/f32" => Some(Instr::CvtOp(CvtOp::ITruncFromF(ValSize::V32, ValSize::V32, ValSign::S))),
-        b"i32.trunc_u/f32" => Some(Instr::CvtOp(CvtOp::ITruncFromF(ValSize::V32, ValSize::V32, ValSign::U))),
-        b"i32.trunc_s/f64" => Some(Instr::CvtOp(CvtOp::ITruncFromF(ValSize::V32, ValSize::V64, ValSign::S))),
-        b"i32.trunc_u/f64" => Some(Instr::CvtOp(CvtOp::ITruncFromF(ValSize::V32, ValSize::V64, ValSign::U))),
-        b"i64.trunc_s/f32" => Some(Instr::CvtOp(CvtOp::ITruncFromF(ValSize::V64, ValSize::V32, ValSign::S))),
-        b"i64.trunc_u/f32" => Some(Instr::CvtOp(CvtOp::ITruncFromF(ValSize::V64, ValSize::V32, ValSign::U))),
-        b"i64.trunc_s/f64" => Some(Instr::CvtOp(CvtOp::ITruncFromF(ValSize::V64, ValSize::V64, ValSign::S))),
-        b"i64.trunc_u/f64" => Some(Instr::CvtOp(CvtOp::ITruncFromF(ValSize::V64, ValSize::V64, ValSign::U))),
-
-        b"f32.demote/f64" => Some(Instr::CvtOp(CvtOp::F32DemoteFromF64)),
-        b"f64.promote/f32" => Some(Instr::CvtOp(CvtOp::F64PromoteFromF32)),
-
-        b"f32.convert_s/i32" => Some(Instr::CvtOp(CvtOp::FConvertFromI(ValSize::V32, ValSize::V32, ValSign::S))),
-        b"f32.convert_u/i32" => Some(Instr::CvtOp(CvtOp::FConvertFromI(ValSize::V32, ValSize::V32, ValSign::U))),
-        b"f32.convert_s/i64" => Some(Instr::CvtOp(CvtOp::FConvertFromI(ValSize::V32, ValSize::V64, ValSign::S))),
-        b"f32.convert_u/i64" => Some(Instr::CvtOp(CvtOp::FConvertFromI(ValSize::V32, ValSize::V64, ValSign::U))),
-        b"f64.convert_s/i32" => Some(Instr::CvtOp(CvtOp::FConvertFromI(ValSize::V64, ValSize::V32, ValSign::S))),
-        b"f64.convert_u/i32" => Some(Instr::CvtOp(CvtOp::FConvertFromI(ValSize::V64, ValSize::V32, ValSign::U))),
-        b"f64.convert_s/i64" => Some(Instr::CvtOp(CvtOp::FConvertFromI(ValSize::V64, ValSize::V64, ValSign::S))),
-        b"f64.convert_u/i64" => Some(Instr::CvtOp(CvtOp::FConvertFromI(ValSize::V64, ValSize::V64, ValSign::U))),
-
-        b"i32.reinterpret/f32" => Some(Instr::CvtOp(CvtOp::IReinterpretFromF(ValSize::V32))),
-        b"i64.reinterpret/f64" => Some(Instr::CvtOp(CvtOp::IReinterpretFromF(ValSize::V64))),
-        b"f32.reinterpret/i32" => Some(Instr::CvtOp(CvtOp::FReinterpretFromI(ValSize::V32))),
-        b"f64.reinterpret/i64" => Some(Instr::CvtOp(CvtOp::FReinterpretFromI(ValSize::V64))),
+        // 仕様の名前(i32.wrap_i64など)と、旧名(i32.wrap/i64など)を受け付ける
+        b"i32.wrap/i64" | b"i32.wrap_i64" => Some(Instr::CvtOp(CvtOp::I32WrapFromI64)),
+        b"i64.extend_s/i32" | b"i64.extend_i32_s" => Some(Instr::CvtOp(CvtOp::I64ExtendFromI32(ValSign::S))),
+        b"i64.extend_u/i32" | b"i64.extend_i32_u" => Some(Instr::CvtOp(CvtOp::I64ExtendFromI32(ValSign::U))),
+
+        b"i32.trunc_s/f32" | b"i32.trunc_f32_s" => Some(Instr::CvtOp(CvtOp::ITruncFromF(ValSize::V32, ValSize::V32, ValSign::S))),
+        b"i32.trunc_u/f32" | b"i32.trunc_f32_u" => Some(Instr::CvtOp(CvtOp::ITruncFromF(ValSize::V32, ValSize::V32, ValSign::U))),
+        b"i32.trunc_s/f64" | b"i32.trunc_f64_s" => Some(Instr::CvtOp(CvtOp::ITruncFromF(ValSize::V32, ValSize::V64, ValSign::S))),
+        b"i32.trunc_u/f64" | b"i32.trunc_f64_u" => Some(Instr::CvtOp(CvtOp::ITruncFromF(ValSize::V32, ValSize::V64, ValSign::U))),
+        b"i64.trunc_s/f32" | b"i64.trunc_f32_s" => Some(Instr::CvtOp(CvtOp::ITruncFromF(ValSize::V64, ValSize::V32, ValSign::S))),
+        b"i64.trunc_u/f32" | b"i64.trunc_f32_u" => Some(Instr::CvtOp(CvtOp::ITruncFromF(ValSize::V64, ValSize::V32, ValSign::U))),
+        b"i64.trunc_s/f64" | b"i64.trunc_f64_s" => Some(Instr::CvtOp(CvtOp::ITruncFromF(ValSize::V64, ValSize::V64, ValSign::S))),
+        b"i64.trunc_u/f64" | b"i64.trunc_f64_u" => Some(Instr::CvtOp(CvtOp::ITruncFromF(ValSize::V64, ValSize::V64, ValSign::U))),
+
+        b"f32.demote/f64" | b"f32.demote_f64" => Some(Instr::CvtOp(CvtOp::F32DemoteFromF64)),
+        b"f64.promote/f32" | b"f64.promote_f32" => Some(Instr::CvtOp(CvtOp::F64PromoteFromF32)),
+
+        b"f32.convert_s/i32" | b"f32.convert_i32_s" => Some(Instr::CvtOp(CvtOp::FConvertFromI(ValSize::V32, ValSize::V32, ValSign::S))),
+        b"f32.convert_u/i32" | b"f32.convert_i32_u" => Some(Instr::CvtOp(CvtOp::FConvertFromI(ValSize::V32, ValSize::V32, ValSign::U))),
+        b"f32.convert_s/i64" | b"f32.convert_i64_s" => Some(Instr::CvtOp(CvtOp::FConvertFromI(ValSize::V32, ValSize::V64, ValSign::S))),
+        b"f32.convert_u/i64" | b"f32.convert_i64_u" => Some(Instr::CvtOp(CvtOp::FConvertFromI(ValSize::V32, ValSize::V64, ValSign::U))),
+        b"f64.convert_s/i32" | b"f64.convert_i32_s" => Some(Instr::CvtOp(CvtOp::FConvertFromI(ValSize::V64, ValSize::V32, ValSign::S))),
+        b"f64.convert_u/i32" | b"f64.convert_i32_u" => Some(Instr::CvtOp(CvtOp::FConvertFromI(ValSize::V64, ValSize::V32, ValSign::U))),
+        b"f64.convert_s/i64" | b"f64.convert_i64_s" => Some(Instr::CvtOp(CvtOp::FConvertFromI(ValSize::V64, ValSize::V64, ValSign::S))),
+        b"f64.convert_u/i64" | b"f64.convert_i64_u" => Some(Instr::CvtOp(CvtOp::FConvertFromI(ValSize::V64, ValSize::V64, ValSign::U))),
+
+        b"i32.reinterpret/f32" | b"i32.reinterpret_f32" => Some(Instr::CvtOp(CvtOp::IReinterpretFromF(ValSize::V32))),
+        b"i64.reinterpret/f64" | b"i64.reinterpret_f64" => Some(Instr::CvtOp(CvtOp::IReinterpretFromF(ValSize::V64))),
+        b"f32.reinterpret/i32" | b"f32.reinterpret_i32" => Some(Instr::CvtOp(CvtOp::FReinterpretFromI(ValSize::V32))),
+        b"f64.reinterpret/i64" | b"f64.reinterpret_i64" => Some(Instr::CvtOp(CvtOp::FReinterpretFromI(ValSize::V64))),
 
         _ => {
+            // 命令でない語(予約語)ならNone
             let mut s_iter = s.split(|&b| b == b'.');
-            let vt_b = s_iter.next().unwrap();
-            let instr = s_iter.next().unwrap();
+            let vt_b = s_iter.next()?;
+            let instr = s_iter.next()?;
+            if s_iter.next().is_some() { return None }
 
-            let vt = vec_to_valtype(vt_b).unwrap();
-            let vs = vec_to_valsize(vt_b).unwrap();
+            let vt = vec_to_valtype(vt_b)?;
+            let vs = vec_to_valsize(vt_b)?;
             match instr {
                 b"load" => Some(Instr::Load(vt, memarg)),
                 b"store" => Some(Instr::Store(vt, memarg)),
@@ -160,7 +201,7 @@ fn vec_to_instr(s: &[u8]) -> Option<Instr> {
                 },
 
                 // b"mul" => Some(Instr::IBinOp(vs, IBinOp::Mul)),
-                // b"and" => Some(Instr::IBinOp(vs, IBinOp::And)),
+                b"and" => Some(Instr::IBinOp(vs, IBinOp::And)),
                 b"or" => Some(Instr::IBinOp(vs, IBinOp::Or)),
                 b"xor" => Some(Instr::IBinOp(vs, IBinOp::Xor)),
                 b"shl" => Some(Instr::IBinOp(vs, IBinOp::Shl)),
@@ -197,7 +238,8 @@ fn vec_to_instr(s: &[u8]) -> Option<Instr> {
 
                 _ => {
                     let instr_tokens: Vec<&[u8]> = instr.split(|&b| b == b'_').collect();
-                    let sign = vec_to_valsign(instr_tokens[1]).unwrap();
+                    if instr_tokens.len() != 2 { return None }
+                    let sign = vec_to_valsign(instr_tokens[1])?;
                     match instr_tokens[0] {
                         b"load8" => Some(Instr::ILoad8(vs, sign, memarg)),
                         b"load16" => Some(Instr::ILoad16(vs, sign, memarg)),
@@ -213,7 +255,7 @@ fn vec_to_instr(s: &[u8]) -> Option<Instr> {
                         b"le" => Some(Instr::IRelOp(vs, IRelOp::Le(sign))),
                         b"ge" => Some(Instr::IRelOp(vs, IRelOp::Ge(sign))),
 
-                        _ => panic!("invalid instr name: {:?}", String::from_utf8(s.to_vec())),
+                        _ => None,
                     }
                 }
             }            
@@ -286,4 +328,33 @@ fn vec_to_frelop(s: &[u8]) -> Option<FRelOp> {
 }
 
 fn default_result_type() -> ResultType { vec![] }
-fn default_br_table() -> Vec<LabelIndex> { vec![] }
\ No newline at end of file
+fn default_br_table() -> Vec<LabelIndex> { vec![] }
+
+#[test]
+fn test_old_instr_names() {
+    let kw = |s: &str| vec_to_keyword(s.as_bytes());
+    for (old, new) in &[("get_local", "local.get"), ("set_local", "local.set"), ("tee_local", "local.tee"),
+                        ("get_global", "global.get"), ("set_global", "global.set")] {
+        assert_eq!(kw(old), kw(new), "{}", old);
+        assert!(kw(old).is_some());
+    }
+}
+
+#[test]
+fn test_cvt_instr_names() {
+    let kw = |s: &str| vec_to_keyword(s.as_bytes());
+    for (old, new) in &[("i32.wrap/i64", "i32.wrap_i64"), ("i64.extend_u/i32", "i64.extend_i32_u"),
+                        ("i64.trunc_s/f32", "i64.trunc_f32_s"), ("f32.demote/f64", "f32.demote_f64"),
+                        ("f64.convert_u/i64", "f64.convert_i64_u"), ("f32.reinterpret/i32", "f32.reinterpret_i32")] {
+        assert_eq!(kw(old), kw(new), "{}", new);
+        assert!(kw(new).is_some());
+    }
+}
+
+#[test]
+fn test_unknown_instr_names() {
+    // 知らない命令名は予約語になる
+    for s in &["i32.foo", "i32.foo_s", "i32.wrap_x", "i32.div_s_x", "i32.add.x", "f32.div_x"] {
+        assert_eq!(vec_to_keyword(s.as_bytes()), None, "{}", s);
+    }
+}
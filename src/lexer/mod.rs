@@ -2,15 +2,17 @@ mod error;
 #[macro_use] mod comment;
 #[macro_use] mod keyword;
 mod string;
+mod number;
 mod token;
 
-use std::io::{Read, Seek};
+use std::io::{Read, Seek, SeekFrom, Cursor};
 use annot::{Loc};
 
 pub use self::error::*;
 pub use self::comment::*;
 pub use self::keyword::*;
 pub use self::string::*;
+pub use self::number::*;
 pub use self::token::*;
 
 #[derive(Debug)]
@@ -18,12 +20,30 @@ pub struct Lexer<R: Read + Seek> {
     reader: R,
     current: u8,
     loc: Loc,
-    peeked_byte: u8,
     peeked_token: Option<Token>,
 }
 
 pub type LexResult = Result<Token, LexError>;
 
+// 同じ位置から読み直すために保存する字句解析器の状態
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    pos: u64,
+    current: u8,
+    loc: Loc,
+    peeked_token: Option<Token>,
+}
+
+impl<'a> Lexer<Cursor<&'a [u8]>> {
+    pub fn from_text(text: &'a str) -> Self {
+        Self::from_bytes(text.as_bytes())
+    }
+
+    pub fn from_bytes(bytes: &'a [u8]) -> Self {
+        Self::new(Cursor::new(bytes))
+    }
+}
+
 impl<R> Lexer<R> where R: Read + Seek {
 
 pub fn new(mut reader: R) -> Lexer<R> {
@@ -36,7 +56,6 @@ pub fn new(mut reader: R) -> Lexer<R> {
         reader: reader,
         current: current,
         loc: loc,
-        peeked_byte: 0,
         peeked_token: None
     }
 }
@@ -59,6 +78,33 @@ pub fn peek_token(&mut self) -> LexResult {
     Ok(result.unwrap())
 }
 
+pub fn checkpoint(&mut self) -> Result<Checkpoint, LexError> {
+    Ok(Checkpoint {
+        pos: self.reader.stream_position()?,
+        current: self.current,
+        loc: self.loc,
+        peeked_token: self.peeked_token.clone(),
+    })
+}
+
+pub fn restore(&mut self, checkpoint: Checkpoint) -> Result<(), LexError> {
+    self.reader.seek(SeekFrom::Start(checkpoint.pos))?;
+    self.current = checkpoint.current;
+    self.loc = checkpoint.loc;
+    self.peeked_token = checkpoint.peeked_token;
+    Ok(())
+}
+
+// エラーの後、次の区切り(空白・括弧・文字列)まで読み飛ばして、字句解析を続けられるようにする
+pub fn recover(&mut self) -> Result<(), LexError> {
+    self.peeked_token = None;
+    while !matches!(self.current, b' ' | b'\t' | b'\n' | b'\r' | b'(' | b')' | b'"' | 0xFF) {
+        self.loc.add_pos();
+        self.current = self.read()?;
+    }
+    Ok(())
+}
+
 fn next_token_internal(&mut self) -> LexResult {
 
     loop {
@@ -100,24 +146,17 @@ fn next_token_internal(&mut self) -> LexResult {
                     keyword_c = self.read()?;
                 }
 
-                match keyword.as_slice() {
-                    b"inf" => return Ok(Token::number_f(std::f64::INFINITY, begin)),
-                    b"nan" => return Ok(Token::number_f(std::f64::NAN, begin)),
-                    _ => return vec_to_keyword(keyword.as_slice())
-                                .map_or(Ok(Token::reserved(keyword, begin)),
-                                |kw| Ok(Token::keyword(kw, begin)))                    
+                // inf, nan, nan:0x...
+                if let Some(num) = word_to_number(&keyword) {
+                    return Ok(Token::number(num, begin));
                 }
+                return vec_to_keyword(keyword.as_slice())
+                            .map_or(Ok(Token::reserved(keyword, begin)),
+                            |kw| Ok(Token::keyword(kw, begin)))
             },
 
-            // num or hexnum (uN)
-            b'0' ..= b'9' => return self.lex_number(b'+', self.loc.added(1)),
-
             // number
-            b @ b'+' | b @ b'-' => {
-                self.loc.add_pos();
-                self.current = self.read()?;
-                return self.lex_number(b, self.loc);
-            }
+            b'0' ..= b'9' | b'+' | b'-' => return self.lex_number(),
 
             // string
             b'"' => {
@@ -153,6 +192,24 @@ fn next_token_internal(&mut self) -> LexResult {
                 self.loc.add_pos();
                 let c = self.read()?;
 
+                // annotation
+                if c == b'@' {
+                    self.loc.add_pos();
+                    let begin = self.loc;
+
+                    let mut name = vec![];
+                    let mut name_c = self.read()?;
+                    while is_idchar(name_c) {
+                        self.loc.add_pos();
+                        name.push(name_c);
+                        name_c = self.read()?;
+                    }
+                    self.current = name_c;
+
+                    let res = String::from_utf8(name)?;
+                    return self.lex_annotation(res, begin);
+                }
+
                 if c != b';' {
                     // left paren
                     self.current = c;
@@ -173,184 +230,57 @@ fn next_token_internal(&mut self) -> LexResult {
             },
 
             // reserved
-            _ if is_idchar(self.current) => return Ok(Token::reserved(vec![], self.loc)),
+            _ if is_idchar(self.current) => return self.lex_reserved(),
 
             // EOF
             0xFF => return Ok(Token::empty(self.loc)),
 
             // invalid
-            _ => return Err(self.err(self.current)),
+            _ => return Err(LexError::invalid_char(self.current, self.loc.added(1))),
         };
 
         self.current = self.read()?;
     }
 }
 
-fn lex_bytes(&mut self, frag: &[u8]) -> Result<(), LexError> {
-    for c in frag {
-        self.current = self.read()?;
+// 数値として読めない語は予約語になる
+fn lex_number(&mut self) -> LexResult {
+    self.loc.add_pos();
+    let begin = self.loc;
+
+    let mut word = vec![self.current];
+    let mut c = self.read()?;
+    while is_idchar(c) {
         self.loc.add_pos();
-        if self.current != *c {
-            return Err(self.err(self.current));
-        }
+        word.push(c);
+        c = self.read()?;
     }
-    Ok(())
-}
-
-pub(super) fn lex_number(&mut self, sign: u8, begin: Loc) -> LexResult {
-    match self.current {
-        b'i' => {
-            self.loc.add_pos();
-
-            self.lex_bytes(b"nf")?;
-
-            self.current = self.read()?;
-
-            match sign {
-                b'+' => return Ok(Token::number_f(std::f64::INFINITY, begin)),
-                b'-' => return Ok(Token::number_f(std::f64::NEG_INFINITY, begin)),
-                _ => return Err(self.err(self.current)),
-            }
-            
-        },
-        b'n' => {
-            self.loc.add_pos();
-
-            self.lex_bytes(b"an")?;
+    self.current = c;
 
-            self.current = self.read()?;
-
-            return Ok(Token::number_f(std::f64::NAN, begin));
-        },
-        _ => {},
+    match word_to_number(&word) {
+        Some(num) => Ok(Token::number(num, begin)),
+        None => Ok(Token::reserved(word, begin)),
     }
-    if self.current == b'0' {
-        if self.peek()? == b'x' {
-            self.read()?;
-            self.loc.add_pos();  // for 0
-            self.loc.add_pos();  // for x
-            // hexnum
-            let mut hexnum = 0;
-
-            loop {
-                match self.current {
-                    b'_' => self.loc.add_pos(),
-                    b'0' => { self.loc.add_pos(); hexnum = hexnum * 16 + 0; },
-                    b'1' => { self.loc.add_pos(); hexnum = hexnum * 16 + 1; },
-                    b'2' => { self.loc.add_pos(); hexnum = hexnum * 16 + 2; },
-                    b'3' => { self.loc.add_pos(); hexnum = hexnum * 16 + 3; },
-                    b'4' => { self.loc.add_pos(); hexnum = hexnum * 16 + 4; },
-                    b'5' => { self.loc.add_pos(); hexnum = hexnum * 16 + 5; },
-                    b'6' => { self.loc.add_pos(); hexnum = hexnum * 16 + 6; },
-                    b'7' => { self.loc.add_pos(); hexnum = hexnum * 16 + 7; },
-                    b'8' => { self.loc.add_pos(); hexnum = hexnum * 16 + 8; },
-                    b'9' => { self.loc.add_pos(); hexnum = hexnum * 16 + 9; },
-                    b'A' => { self.loc.add_pos(); hexnum = hexnum * 16 + 10; },
-                    b'B' => { self.loc.add_pos(); hexnum = hexnum * 16 + 11; },
-                    b'C' => { self.loc.add_pos(); hexnum = hexnum * 16 + 12; },
-                    b'D' => { self.loc.add_pos(); hexnum = hexnum * 16 + 13; },
-                    b'E' => { self.loc.add_pos(); hexnum = hexnum * 16 + 14; },
-                    b'F' => { self.loc.add_pos(); hexnum = hexnum * 16 + 15; },
-                    0xFF => return Err(LexError::eof(self.loc)),
-                    _ => break,                
-                }
-                self.current = self.read()?;
-            }
-            
-            match sign {
-                b'+' => return Ok(Token::number_u(hexnum, begin)),
-                b'-' => return Ok(Token::number_i(-(hexnum as isize), begin)),
-                _ => return Err(self.err(self.current)),
-            }    
-        }
-    }
-
-    // num
-    let mut num = 0;
-    let mut frac = 0.0_f64;
-    let mut current = self.current;
-    loop {
-        match current {
-            b'_' => self.loc.add_pos(),
-            b'.' => {
-                // begin frac
-                self.loc.add_pos();                
-                current = self.read()?;
-                let mut digit = 1;
-                loop {
-                    let powed = 10.0f64.powi(digit);
-                    match current {
-                        b'_' => { self.loc.add_pos(); current = self.read()?; continue; },
-                        b'0' => { self.loc.add_pos(); frac = frac + 0.0 / powed; },
-                        b'1' => { self.loc.add_pos(); frac = frac + 1.0 / powed; },
-                        b'2' => { self.loc.add_pos(); frac = frac + 2.0 / powed; },
-                        b'3' => { self.loc.add_pos(); frac = frac + 3.0 / powed; },
-                        b'4' => { self.loc.add_pos(); frac = frac + 4.0 / powed; },
-                        b'5' => { self.loc.add_pos(); frac = frac + 5.0 / powed; },
-                        b'6' => { self.loc.add_pos(); frac = frac + 6.0 / powed; },
-                        b'7' => { self.loc.add_pos(); frac = frac + 7.0 / powed; },
-                        b'8' => { self.loc.add_pos(); frac = frac + 8.0 / powed; },
-                        b'9' => { self.loc.add_pos(); frac = frac + 9.0 / powed; },
-                        0xFF => return Err(LexError::eof(self.loc)),
-                        _ => break,
-                    }
-                    digit += 1;                
-                    current = self.read()?;
-                }
+}
 
-                let mut float = frac + num as f64;
+// キーワードにも数値にもならない語(!や#で始まるものなど)
+fn lex_reserved(&mut self) -> LexResult {
+    self.loc.add_pos();
+    let begin = self.loc;
 
-                match sign {
-                    b'+' => {},
-                    b'-' => { float = -float },
-                    _ => return Err(self.err(self.current)),
-                }    
-                self.current = current;
-                
-                return Ok(Token::number_f(float, begin));
-            }
-            b'0' => { self.loc.add_pos(); num = num * 10 + 0; },
-            b'1' => { self.loc.add_pos(); num = num * 10 + 1; },
-            b'2' => { self.loc.add_pos(); num = num * 10 + 2; },
-            b'3' => { self.loc.add_pos(); num = num * 10 + 3; },
-            b'4' => { self.loc.add_pos(); num = num * 10 + 4; },
-            b'5' => { self.loc.add_pos(); num = num * 10 + 5; },
-            b'6' => { self.loc.add_pos(); num = num * 10 + 6; },
-            b'7' => { self.loc.add_pos(); num = num * 10 + 7; },
-            b'8' => { self.loc.add_pos(); num = num * 10 + 8; },
-            b'9' => { self.loc.add_pos(); num = num * 10 + 9; },
-            0xFF => return Err(LexError::eof(self.loc)),
-            _ => break,
-        }
-        current = self.read()?;
+    let mut word = vec![self.current];
+    let mut c = self.read()?;
+    while is_idchar(c) {
+        self.loc.add_pos();
+        word.push(c);
+        c = self.read()?;
     }
+    self.current = c;
 
-    self.current = current;
-    match sign {
-        b'+' => Ok(Token::number_u(num, begin)),
-        b'-' => Ok(Token::number_i(-(num as isize), begin)),
-        _ => Err(self.err(self.current)),
-    }    
+    Ok(Token::reserved(word, begin))
 }
 
 fn read(&mut self) -> Result<u8, LexError> {
-    if self.peeked_byte == 0 {
-        self.read_internal()
-    } else {
-        let peeked = self.peeked_byte;
-        self.peeked_byte = 0;
-        Ok(peeked)
-    }
-}
-
-fn peek(&mut self) -> Result<u8, LexError> {
-    if self.peeked_byte == 0 {
-        self.peeked_byte = self.read_internal()?;
-    }
-    Ok(self.peeked_byte)
-}
-
-fn read_internal(&mut self) -> Result<u8, LexError> {
     let mut buf: &mut [u8] = &mut [0;1];
     let n = self.reader.read(&mut buf)?;
 
@@ -364,7 +294,7 @@ fn err(&self, c: u8) -> LexError {
 
 }
 
-fn is_idchar(c: u8) -> bool {
+pub(crate) fn is_idchar(c: u8) -> bool {
     match c {
         b'0' ..= b'9' |
         b'A' ..= b'Z' |
@@ -376,10 +306,98 @@ fn is_idchar(c: u8) -> bool {
 }
 
 #[test]
-fn test() {
-    // unsafe {
-    // let u = std::mem::transmute::<i8, u8>(-1);
-    // println!("{:?}", u);
-    // }
-    p!(Token::number_i(-1, Loc::default()));
+fn test_lex_number() {
+    let mut lexer = Lexer::from_text("-0x1_0 1__0 nan:0x7 inf");
+    let mut next = || lexer.next_token().unwrap().value;
+    assert_eq!(next(), TokenKind::Number(Number::Integer("-0x10".to_string())));
+    assert_eq!(next(), TokenKind::Reserved("1__0".to_string()));
+    assert_eq!(next(), TokenKind::Number(Number::FloatingPoint("nan:0x7".to_string())));
+    assert_eq!(next(), TokenKind::Number(Number::FloatingPoint("inf".to_string())));
+}
+
+#[test]
+fn test_lex_reserved() {
+    // 記号で始まる語も、語の終わりまでを1つのトークンとして読む
+    let mut lexer = Lexer::from_text("! #a ^^ x");
+    let mut next = || lexer.next_token().unwrap().value;
+    assert_eq!(next(), TokenKind::Reserved("!".to_string()));
+    assert_eq!(next(), TokenKind::Reserved("#a".to_string()));
+    assert_eq!(next(), TokenKind::Reserved("^^".to_string()));
+    assert_eq!(next(), TokenKind::Reserved("x".to_string()));
+    assert_eq!(next(), TokenKind::Empty);
+}
+
+#[test]
+fn test_lex_string() {
+    let lex = |s: &str| Lexer::from_text(s).next_token().map(|t| t.value);
+    let string = |b: &[u8]| Ok(TokenKind::String(b.to_vec()));
+
+    assert_eq!(lex(r#""a\tb\n\r\"\'\\""#), string(b"a\tb\n\r\"'\\"));
+    assert_eq!(lex(r#""\00\ff\7F""#), string(b"\x00\xff\x7f"));
+    assert_eq!(lex(r#""\u{1F600}\u{41}あ""#), string("\u{1F600}Aあ".as_bytes()));
+    assert!(lex(r#""\u{D800}""#).is_err());
+    assert!(lex(r#""\q""#).is_err());
+    assert!(lex(r#""\0""#).is_err());
+    assert!(lex("\"a\tb\"").is_err());
+    assert!(lex("\"abc").is_err());
+}
+
+#[test]
+fn test_lex_recover() {
+    // エラーの後は、次の区切りから読み直せる
+    let mut lexer = Lexer::from_text("\"a\\qb\" x ,,, y \"c\n(\n\"é\x01\" z");
+    let mut tokens = vec![];
+    loop {
+        match lexer.next_token() {
+            Ok(Token { value: TokenKind::Empty, .. }) => break,
+            Ok(token) => tokens.push(Ok(token.value)),
+            Err(_) => {
+                tokens.push(Err(()));
+                lexer.recover().unwrap();
+            },
+        }
+    }
+    let kw = |s: &str| Ok(TokenKind::Reserved(s.to_string()));
+    assert_eq!(tokens, vec![Err(()), kw("x"), Err(()), kw("y"), Err(()), Ok(TokenKind::LeftParen), Err(()), kw("z")]);
+}
+
+#[test]
+fn test_lex_comment_and_annotation() {
+    let lex = |s: &str| {
+        let mut lexer = Lexer::from_text(s);
+        let mut tokens = vec![];
+        loop {
+            match lexer.next_token().map(|t| t.value) {
+                Ok(TokenKind::Empty) => return Ok(tokens),
+                Ok(token) => tokens.push(token),
+                Err(e) => return Err(e),
+            }
+        }
+    };
+
+    assert_eq!(lex("(; (; ;) ;) 1 (;;) 2 (; ( ; ) (;) ;) ;) 3"), Ok(vec![
+        TokenKind::Number(Number::Integer("1".to_string())),
+        TokenKind::Number(Number::Integer("2".to_string())),
+        TokenKind::Number(Number::Integer("3".to_string())),
+    ]));
+    assert!(lex("(; (; ;) 1").is_err());
+
+    let tokens = lex(r#"(@a x (y "(") (@b)) )"#).unwrap();
+    assert_eq!(tokens.len(), 2);
+    assert_eq!(tokens[1], TokenKind::RightParen);
+    match &tokens[0] {
+        TokenKind::Annotation(name, contents) => {
+            assert_eq!(name, "a");
+            assert_eq!(contents.iter().map(|t| t.value.clone()).collect::<Vec<_>>(), vec![
+                TokenKind::Reserved("x".to_string()),
+                TokenKind::LeftParen,
+                TokenKind::Reserved("y".to_string()),
+                TokenKind::String(b"(".to_vec()),
+                TokenKind::RightParen,
+                TokenKind::Annotation("b".to_string(), vec![]),
+            ]);
+        },
+        token => panic!("{:?}", token),
+    }
+    assert!(lex("(@a (b)").is_err());
 }
@@ -0,0 +1,279 @@
+use std::fmt::Debug;
+use std::convert::TryFrom;
+
+// 数値リテラル
+// 値の範囲と丸めは使われる型で決まるので、字句の構文だけを確かめ、アンダースコアを除いた文字列で持つ
+#[derive(PartialEq, Clone)]
+pub enum Number {
+    Integer(String),
+    FloatingPoint(String),
+}
+
+impl Debug for Number {
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+       match &self {
+           Number::Integer(num) => write!(f, "{}", num),
+           Number::FloatingPoint(num) => write!(f, "{}", num),
+       }
+    }
+}
+
+// 数値リテラルとして読めなければNone
+pub(crate) fn word_to_number(word: &[u8]) -> Option<Number> {
+    let text = String::from_utf8(word.to_vec()).ok()?;
+    let (_, rest) = split_sign(&text);
+
+    if rest == "inf" || rest == "nan" {
+        return Some(Number::FloatingPoint(text));
+    }
+    if let Some(payload) = rest.strip_prefix("nan:0x") {
+        return if digits(payload.as_bytes(), true) == payload.len() && !payload.is_empty() {
+            Some(Number::FloatingPoint(text.replace('_', "")))
+        } else {
+            None
+        };
+    }
+
+    let (hex, body) = match rest.strip_prefix("0x") {
+        Some(body) => (true, body.as_bytes()),
+        None => (false, rest.as_bytes()),
+    };
+
+    // num ('.' frac?)? (exponent sign? num)?
+    let mut pos = digits(body, hex);
+    if pos == 0 { return None; }
+    let mut float = false;
+
+    if body.get(pos) == Some(&b'.') {
+        float = true;
+        pos += 1;
+        pos += digits(&body[pos..], hex);
+    }
+
+    let exponent: &[u8] = if hex { b"pP" } else { b"eE" };
+    if body.get(pos).is_some_and(|c| exponent.contains(c)) {
+        float = true;
+        pos += 1;
+        if let Some(b'+') | Some(b'-') = body.get(pos) { pos += 1; }
+        let n = digits(&body[pos..], false);
+        if n == 0 { return None; }
+        pos += n;
+    }
+
+    if pos != body.len() { return None; }
+
+    let text = text.replace('_', "");
+    if float { Some(Number::FloatingPoint(text)) } else { Some(Number::Integer(text)) }
+}
+
+// 数字の並びの長さ。'_'は数字の間にだけ置ける
+fn digits(s: &[u8], hex: bool) -> usize {
+    let is_digit = |c: u8| if hex { c.is_ascii_hexdigit() } else { c.is_ascii_digit() };
+    let mut len = 0;
+    while len < s.len() {
+        if is_digit(s[len]) {
+            len += 1;
+        } else if s[len] == b'_' && len > 0 && s.get(len + 1).is_some_and(|c| is_digit(*c)) {
+            len += 2;
+        } else {
+            break;
+        }
+    }
+    len
+}
+
+fn split_sign(text: &str) -> (bool, &str) {
+    if let Some(rest) = text.strip_prefix('-') {
+        (true, rest)
+    } else {
+        (false, text.strip_prefix('+').unwrap_or(text))
+    }
+}
+
+impl Number {
+    fn text(&self) -> &str {
+        match self {
+            Number::Integer(text) | Number::FloatingPoint(text) => text,
+        }
+    }
+
+    // 整数の符号と絶対値
+    fn integer(&self) -> Option<(bool, u64)> {
+        let text = match self {
+            Number::Integer(text) => text,
+            Number::FloatingPoint(_) => return None,
+        };
+        let (negative, rest) = split_sign(text);
+        let magnitude = match rest.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => rest.parse::<u64>(),
+        };
+        magnitude.ok().map(|m| (negative, m))
+    }
+
+    // 符号のない整数(uN)。インデックスや制限に使う
+    pub fn to_unsigned(&self) -> Option<u64> {
+        match self.integer() {
+            Some((false, m)) if !self.text().starts_with('+') => Some(m),
+            _ => None,
+        }
+    }
+
+    // i32の定数。-2^31から2^32-1までを2の補数で持つ
+    pub fn to_i32(&self) -> Option<u32> {
+        match self.integer()? {
+            (true, m) if m <= 1 << 31 => Some((m as u32).wrapping_neg()),
+            (false, m) => u32::try_from(m).ok(),
+            _ => None,
+        }
+    }
+
+    // i64の定数。-2^63から2^64-1までを2の補数で持つ
+    pub fn to_i64(&self) -> Option<u64> {
+        match self.integer()? {
+            (true, m) if m <= 1 << 63 => Some(m.wrapping_neg()),
+            (false, m) => Some(m),
+            _ => None,
+        }
+    }
+
+    pub fn to_f32(&self) -> Option<f32> {
+        let (negative, rest) = split_sign(self.text());
+        let bits = match float_bits(rest, 23, 8) {
+            Some(bits) => bits? as u32,
+            None => {
+                let z = rest.parse::<f32>().ok().filter(|z| z.is_finite())?;
+                z.to_bits()
+            },
+        };
+        Some(f32::from_bits(bits | (negative as u32) << 31))
+    }
+
+    pub fn to_f64(&self) -> Option<f64> {
+        let (negative, rest) = split_sign(self.text());
+        let bits = match float_bits(rest, 52, 11) {
+            Some(bits) => bits?,
+            None => rest.parse::<f64>().ok().filter(|z| z.is_finite())?.to_bits(),
+        };
+        Some(f64::from_bits(bits | (negative as u64) << 63))
+    }
+}
+
+// inf、nan、16進数の浮動小数点数を、符号を除いたビット列にする
+// 10進数はstd(正しく丸められる)に任せるので、外側のNoneで返す
+fn float_bits(rest: &str, mantissa: u32, exponent: u32) -> Option<Option<u64>> {
+    let inf = ((1 << exponent) - 1) << mantissa;
+    if rest == "inf" {
+        Some(Some(inf))
+    } else if rest == "nan" {
+        Some(Some(inf | 1 << (mantissa - 1)))
+    } else if let Some(payload) = rest.strip_prefix("nan:0x") {
+        let payload = u64::from_str_radix(payload, 16).ok().filter(|p| *p != 0 && *p < 1 << mantissa);
+        Some(payload.map(|p| inf | p))
+    } else {
+        rest.strip_prefix("0x").map(|hex| hex_float_bits(hex, mantissa, exponent))
+    }
+}
+
+// 仮数部の上位ビットだけを残し、残りはstickyビットにまとめて最近接偶数丸めをする
+fn hex_float_bits(hex: &str, mantissa: u32, exponent: u32) -> Option<u64> {
+    let (significand, exp) = match hex.find(['p', 'P']) {
+        Some(i) => (&hex[..i], hex[i + 1..].parse::<i64>().ok()?),
+        None => (hex, 0),
+    };
+    let (int, frac) = significand.split_once('.').unwrap_or((significand, ""));
+
+    let mut acc: u128 = 0;
+    let mut exp2 = exp;
+    let mut sticky = false;
+    for (i, c) in int.chars().chain(frac.chars()).enumerate() {
+        let d = c.to_digit(16)? as u128;
+        let in_frac = i >= int.len();
+        if acc < 1 << 120 {
+            acc = acc * 16 + d;
+            if in_frac { exp2 -= 4; }
+        } else {
+            sticky |= d != 0;
+            if !in_frac { exp2 += 4; }
+        }
+    }
+    if acc == 0 { return Some(0); }
+
+    let bias = (1i64 << (exponent - 1)) - 1;
+    let emin = 1 - bias;
+    let top = 127 - acc.leading_zeros() as i64;
+    let mut e = top.checked_add(exp2)?;
+
+    // 残すビット数(非正規化数では減る)
+    let keep = if e >= emin { mantissa as i64 + 1 } else { mantissa as i64 + 1 - (emin - e) };
+    let shift = top + 1 - keep;
+    let mut kept = if shift <= 0 {
+        acc << -shift
+    } else if shift >= 128 {
+        0
+    } else {
+        let kept = acc >> shift;
+        let rest = acc & ((1 << shift) - 1);
+        let half = 1 << (shift - 1);
+        if rest > half || (rest == half && (sticky || kept & 1 == 1)) { kept + 1 } else { kept }
+    };
+
+    if e < emin {
+        // 丸めで繰り上がったときは、そのまま最小の正規化数の表現になる
+        return Some(kept as u64);
+    }
+    if kept == 1 << (mantissa + 1) {
+        kept >>= 1;
+        e += 1;
+    }
+    if e > bias { return None; }
+    Some(((e + bias) as u64) << mantissa | (kept as u64 & ((1 << mantissa) - 1)))
+}
+
+#[test]
+fn test_number_literals() {
+    let num = |s: &str| word_to_number(s.as_bytes()).unwrap();
+
+    for bad in &["1__0", "_1", "1_", "0x", "1e", "0x1p", "1.0.0", "nan:0x", "1a", "0x1e+1"] {
+        assert!(word_to_number(bad.as_bytes()).is_none(), "{}", bad);
+    }
+
+    assert_eq!(num("1_000").to_unsigned(), Some(1000));
+    assert_eq!(num("+1").to_unsigned(), None);
+    assert_eq!(num("0xffff_ffff").to_i32(), Some(0xFFFF_FFFF));
+    assert_eq!(num("-1").to_i32(), Some(0xFFFF_FFFF));
+    assert_eq!(num("-0x8000_0000").to_i32(), Some(0x8000_0000));
+    assert_eq!(num("-0x8000_0001").to_i32(), None);
+    assert_eq!(num("0x1_0000_0000").to_i32(), None);
+    assert_eq!(num("-9223372036854775808").to_i64(), Some(1 << 63));
+    assert_eq!(num("18446744073709551615").to_i64(), Some(u64::MAX));
+    assert_eq!(num("18446744073709551616").to_i64(), None);
+    assert_eq!(num("1.5").to_i32(), None);
+
+    assert_eq!(num("0x1.8p1").to_f64(), Some(3.0));
+    assert_eq!(num("-0x1P-2").to_f32(), Some(-0.25));
+    assert_eq!(num("0x10").to_f32(), Some(16.0));
+    assert_eq!(num("1e3").to_f32(), Some(1000.0));
+    assert_eq!(num("1.").to_f64(), Some(1.0));
+    assert_eq!(num("-0.0").to_f64().map(f64::to_bits), Some(1 << 63));
+    assert_eq!(num("-inf").to_f32(), Some(f32::NEG_INFINITY));
+    assert_eq!(num("nan").to_f32().map(f32::to_bits), Some(0x7FC0_0000));
+    assert_eq!(num("-nan:0x1").to_f64().map(f64::to_bits), Some(0xFFF0_0000_0000_0001));
+    assert_eq!(num("nan:0x80_0000").to_f32(), None);
+
+    // f64を経由すると二重に丸められる値
+    assert_eq!(num("1.00000005960464477550").to_f32().map(f32::to_bits), Some(0x3F80_0001));
+    assert_eq!(num("0x1.000001p0").to_f32().map(f32::to_bits), Some(0x3F80_0000));
+    assert_eq!(num("0x1.0000011p0").to_f32().map(f32::to_bits), Some(0x3F80_0001));
+    assert_eq!(num("0x1.000003p0").to_f32().map(f32::to_bits), Some(0x3F80_0002));
+
+    // 非正規化数と範囲外
+    assert_eq!(num("0x1p-149").to_f32().map(f32::to_bits), Some(1));
+    assert_eq!(num("0x1p-150").to_f32().map(f32::to_bits), Some(0));
+    assert_eq!(num("0x1.8p-150").to_f32().map(f32::to_bits), Some(1));
+    assert_eq!(num("0x1.fffffffffffffp-1023").to_f64().map(f64::to_bits), Some(0x0010_0000_0000_0000));
+    assert_eq!(num("0x1.ffffffp127").to_f32(), None);
+    assert_eq!(num("0x1.fffffep127").to_f32(), Some(f32::MAX));
+    assert_eq!(num("1e39").to_f32(), None);
+    assert_eq!(num("0x1p1024").to_f64(), None);
+}
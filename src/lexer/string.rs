@@ -3,36 +3,43 @@ use super::*;
 
 impl<R> Lexer<R> where R: Read + Seek {
 
+// 文字列はバイト列として読む(データ文字列は任意のバイトを持てる)
+// UTF-8であることは、名前として使うときにパーサーで確かめる
 pub(super) fn lex_string(&mut self) -> LexResult {
+    let begin = self.loc;
+    match self.lex_string_body() {
+        Ok(string) => {
+            self.current = self.read()?;
+            Ok(Token::string(string, begin))
+        },
+        Err(e) => {
+            self.skip_string_rest()?;
+            Err(e)
+        },
+    }
+}
 
+fn lex_string_body(&mut self) -> Result<Vec<u8>, LexError> {
     let mut string = vec![];
     let mut string_c = self.read()?;
-    let mut byte_length_of_codepoint = 0;  // 1 ~ 3
     let mut rest_of_byte_of_char = 0;  // 0 ~ 3
     loop {
         match string_c {
             // end of string
-            b'"' => { self.loc.add_pos(); break; },
+            b'"' if rest_of_byte_of_char == 0 => { self.loc.add_pos(); break; },
             // escape sequence
-            b'\\' => {
+            b'\\' if rest_of_byte_of_char == 0 => {
+                self.loc.add_pos();
+                self.lex_escape(&mut string)?;
             },
-            _ if string_c > 0x20 && string_c != 0x7F => {
+            _ if string_c >= 0x20 && string_c != 0x7F && string_c != 0xFF => {
+                // 1文字を1桁と数える
                 if rest_of_byte_of_char == 0 {
-                    // count byte as codepoint (not utf-8 bit pattern)
                     match string_c {
                         0x00 ..= 0x7F => self.loc.add_pos(),
-                        0xC2 ..= 0xDF => {                                        
-                            rest_of_byte_of_char = 1;
-                            byte_length_of_codepoint = 2;
-                        },
-                        0xE0 ..= 0xEF => {
-                            rest_of_byte_of_char = 2;
-                            byte_length_of_codepoint = 2;                                        
-                        },
-                        0xF0 ..= 0xF7 => {
-                            rest_of_byte_of_char = 3;
-                            byte_length_of_codepoint = 3;                                        
-                        },
+                        0xC2 ..= 0xDF => rest_of_byte_of_char = 1,
+                        0xE0 ..= 0xEF => rest_of_byte_of_char = 2,
+                        0xF0 ..= 0xF4 => rest_of_byte_of_char = 3,
                         _ => return Err(self.err(string_c)),
                     }
                 } else {
@@ -40,9 +47,7 @@ pub(super) fn lex_string(&mut self) -> LexResult {
                         0x80 ..= 0xBF => {
                             rest_of_byte_of_char -= 1;
                             if rest_of_byte_of_char == 0 {
-                                for _ in 0..byte_length_of_codepoint {
-                                    self.loc.add_pos();
-                                }
+                                self.loc.add_pos();
                             }
                         }
                         _ => return Err(self.err(string_c)),
@@ -50,14 +55,103 @@ pub(super) fn lex_string(&mut self) -> LexResult {
                 }
                 string.push(string_c);
             },
-            _ => return Err(self.err(string_c)),
+            0xFF => return Err(LexError::eof(self.loc)),
+            _ => {
+                // 改行なら、そこで文字列を打ち切る
+                self.current = string_c;
+                return Err(self.err(string_c));
+            },
         }
         string_c = self.read()?;
     }
-    let res = String::from_utf8(string.to_vec())?;
+    Ok(string)
+}
 
-    self.current = self.read()?;
-    return Ok(Token::string(res, self.loc))
+// 不正な文字列の残りを、閉じる'"'(なければ行末)まで読み飛ばす
+fn skip_string_rest(&mut self) -> Result<(), LexError> {
+    if self.current == b'\n' { return Ok(()); }
+    loop {
+        match self.read()? {
+            b'"' => {
+                self.loc.add_pos();
+                self.current = self.read()?;
+                return Ok(());
+            },
+            b'\\' => { self.read()?; },
+            c @ (b'\n' | 0xFF) => {
+                self.current = c;
+                return Ok(());
+            },
+            _ => self.loc.add_pos(),
+        }
+    }
 }
 
-}
\ No newline at end of file
+// '\'に続くエスケープシーケンス
+fn lex_escape(&mut self, string: &mut Vec<u8>) -> Result<(), LexError> {
+    let c = self.read()?;
+    self.loc.add_pos();
+    match c {
+        b't' => string.push(b'\t'),
+        b'n' => string.push(b'\n'),
+        b'r' => string.push(b'\r'),
+        b'"' => string.push(b'"'),
+        b'\'' => string.push(b'\''),
+        b'\\' => string.push(b'\\'),
+        b'u' => {
+            let c = self.read()?;
+            self.loc.add_pos();
+            if c != b'{' { return Err(self.err(c)); }
+
+            let mut codepoint: u32 = 0;
+            let mut digits = 0;
+            loop {
+                let c = self.read()?;
+                self.loc.add_pos();
+                match c {
+                    b'}' if digits > 0 => break,
+                    b'_' if digits > 0 => {},
+                    _ => {
+                        let d = (c as char).to_digit(16).ok_or_else(|| self.err(c))?;
+                        codepoint = codepoint.checked_mul(16).and_then(|n| n.checked_add(d)).ok_or_else(|| self.err(c))?;
+                        digits += 1;
+                    },
+                }
+            }
+
+            let ch = std::char::from_u32(codepoint).ok_or_else(|| self.err(b'}'))?;
+            let mut buf = [0; 4];
+            string.extend(ch.encode_utf8(&mut buf).as_bytes());
+        },
+        _ => {
+            // 2桁の16進数で1バイト
+            let hi = (c as char).to_digit(16).ok_or_else(|| self.err(c))?;
+            let c = self.read()?;
+            self.loc.add_pos();
+            let lo = (c as char).to_digit(16).ok_or_else(|| self.err(c))?;
+            string.push((hi * 16 + lo) as u8);
+        },
+    }
+    Ok(())
+}
+
+}
+
+#[test]
+fn test_parse_strings() {
+    use ::parser::{Parser, ParseError};
+
+    let mut parser = Parser::from_text(r#"(module (memory 1)
+      (func (export "\u{3042}\t"))
+      (data 0 (offset i32.const 0) "\00\ff\"\\\u{1F600}"))"#);
+    parser.parse().unwrap();
+    assert_eq!(parser.module.exports[0].0, "あ\t");
+    assert_eq!(parser.module.data[0].init, b"\x00\xff\"\\\xF0\x9F\x98\x80");
+
+    // 名前はUTF-8でなければならない
+    let mut parser = Parser::from_text(r#"(module (func (export "\ff")))"#);
+    match parser.parse() {
+        Err(ParseError::InvalidMessage(_, mes)) => assert_eq!(mes, "malformed UTF-8 encoding"),
+        res => panic!("{:?}", res),
+    }
+}
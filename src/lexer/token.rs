@@ -1,21 +1,7 @@
 use std::fmt::Debug;
 use annot::{Annot, Loc};
 use super::keyword::*;
-
-#[derive(PartialEq, Clone)]
-pub enum Number {
-    Integer(usize),
-    FloatingPoint(f64),
-}
-
-impl Debug for Number {
-   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-       match &self {
-           Number::Integer(num) => write!(f, "{:?}", num),
-           Number::FloatingPoint(num) => write!(f, "{:?}", num),        
-       }        
-    }
-}
+use super::number::*;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum TokenKind {
@@ -23,11 +9,29 @@ pub enum TokenKind {
 
     Keyword(Keyword),
     Number(Number),
-    String(String),
+    String(Vec<u8>),
     Id(String), // $で始まる
     LeftParen,
     RightParen,
     Reserved(String),
+    Annotation(String, Vec<Token>), // (@に続く名前と、対応する')'までのトークン
+}
+
+// エラーメッセージで、期待したトークンや見つかったトークンを示す
+impl std::fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenKind::Empty => write!(f, "end of file"),
+            TokenKind::Keyword(kw) => write!(f, "{}", kw),
+            TokenKind::Number(num) => write!(f, "number `{:?}`", num),
+            TokenKind::String(s) => write!(f, "string {:?}", String::from_utf8_lossy(s)),
+            TokenKind::Id(id) => write!(f, "`${}`", id),
+            TokenKind::LeftParen => write!(f, "`(`"),
+            TokenKind::RightParen => write!(f, "`)`"),
+            TokenKind::Reserved(s) => write!(f, "`{}`", s),
+            TokenKind::Annotation(name, _) => write!(f, "annotation `(@{}`", name),
+        }
+    }
 }
 
 pub type Token = Annot<TokenKind>;
@@ -36,17 +40,13 @@ impl Token {
     pub fn empty(loc: Loc) -> Self { Self::new(TokenKind::Empty, loc) }
 
     pub fn keyword(kw: Keyword, loc: Loc) -> Self { Self::new(TokenKind::Keyword(kw), loc) }
-    pub fn number_u(num: usize, loc: Loc) -> Self { Self::new(TokenKind::Number(Number::Integer(num)), loc) }
-    pub fn number_i(num: isize, loc: Loc) -> Self {
-            let n = unsafe { std::mem::transmute::<isize, usize>(num) };
-            Self::new(TokenKind::Number(Number::Integer(n)), loc)
-        }
-    pub fn number_f(num: f64, loc: Loc) -> Self { Self::new(TokenKind::Number(Number::FloatingPoint(num)), loc) }
-    pub fn string(s: String, loc: Loc) -> Self { Self::new(TokenKind::String(s), loc) }
+    pub fn number(num: Number, loc: Loc) -> Self { Self::new(TokenKind::Number(num), loc) }
+    pub fn string(s: Vec<u8>, loc: Loc) -> Self { Self::new(TokenKind::String(s), loc) }
     pub fn id(n: String, loc: Loc) -> Self { Self::new(TokenKind::Id(n), loc) }
     pub fn left_paren(loc: Loc) -> Self { Self::new(TokenKind::LeftParen, loc) }
     pub fn right_paren(loc: Loc) -> Self { Self::new(TokenKind::RightParen, loc) }
     pub fn reserved(s: Vec<u8>, loc: Loc) -> Self { Self::new(TokenKind::Reserved(String::from_utf8(s).unwrap()), loc) }
+    pub fn annotation(s: String, tokens: Vec<Token>, loc: Loc) -> Self { Self::new(TokenKind::Annotation(s, tokens), loc) }
 }
 
 impl Debug for Token {
@@ -54,9 +54,10 @@ impl Debug for Token {
        match &self.value {
            TokenKind::Keyword(kw) => write!(f, "{:?}<{:?}>", kw, self.loc),
            TokenKind::Number(num) => write!(f, "{:?}<{:?}>", num, self.loc),
-           TokenKind::String(s) => write!(f, "{:?}<{:?}>", s, self.loc),
+           TokenKind::String(s) => write!(f, "{:?}<{:?}>", String::from_utf8_lossy(s), self.loc),
            TokenKind::Id(id) => write!(f, "${}<{:?}>", id, self.loc),
            TokenKind::Reserved(r) => write!(f, "Reserved({})<{:?}>", r, self.loc),
+           TokenKind::Annotation(a, tokens) => write!(f, "(@{} {:?})<{:?}>", a, tokens, self.loc),
            _ => write!(f, "{:?}<{:?}>", self.value, self.loc)
        }        
     }
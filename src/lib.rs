@@ -5,18 +5,30 @@ mod context;
 mod lexer;
 mod parser;
 mod mod2wasm;
+mod mod2wat;
 mod decoder;
+mod objdump;
 mod runtime;
 mod error;
+#[cfg(test)] mod roundtrip;
 
 pub use annot::*;
 pub use instr::*;
 pub use lexer::*;
 pub use parser::*;
 pub use mod2wasm::*;
+pub use mod2wat::*;
+pub use decoder::*;
+pub use objdump::*;
 pub use runtime::*;
 pub use error::Error;
 
-pub fn module_decode() -> Result<Module, Error> {
-    Err(Error::Decode)
+use std::io::Read;
+pub fn module_decode<R: Read>(reader: R) -> Result<Module, Error> {
+    Decoder::new(reader).decode()
+}
+
+// 関数本体は呼び出された時にデコードする
+pub fn module_decode_lazy<R: Read>(reader: R) -> Result<Module, Error> {
+    Decoder::new_lazy(reader).decode()
 }
@@ -12,44 +12,63 @@ fn main() {
         match s.as_ref() {
             "-l" => lex(&mut reader),
             "-p" => {
-                match parse(&mut reader) {
-                    Err(err) => {
-                        println!("PARSE ERROR: {:?}", err);
-                        return;
-                    },
-                    _ => {},
-                }
+                parse(file_name, &mut reader);
                 return;
             },
             "-b" => {                
                 use heliqs::Parser;
-                use heliqs::module_to_wasm;
+                use heliqs::{Error, EncodeOptions, module_write_wasm};
                 let mut parser = Parser::new(reader);
 
-                match parser.parse() {
-                    Err(err) => {
-                        println!("PARSE ERROR: {:?}", err);
-                        return;
-                    },
-                    _ => {},
+                if let Err(diagnostics) = parser.parse_with_diagnostics() {
+                    print_diagnostics(file_name, &diagnostics);
+                    return;
                 }
 
-                match module_to_wasm(&parser.module) {
-                    Ok(_) => {},
-                    Err(_e) => {},
+                // 出力先は3番目の引数で指定する
+                let out_path = args.get(3).map_or("wasm/_.wasm", |s| s.as_ref());
+                // 識別子はnameセクションとして書き出す
+                let options = EncodeOptions {
+                    context: parser.contexts[0].clone(),
+                    func_contexts: parser.func_contexts.clone(),
+                    ..EncodeOptions::default()
+                };
+                let res = File::create(out_path).map_err(Error::from).and_then(|mut file| module_write_wasm(&parser.module, &options, &mut file));
+                if let Err(e) = res {
+                    println!("WRITE ERROR: {:?}", e);
                 }
             },
             "-d" => {
-                use heliqs::module_decode;
-                match module_decode() {
-                    Ok(_) => {},
+                use heliqs::objdump;
+                let mut out = String::new();
+                let res = objdump(reader, &mut out);
+                print!("{}", out);
+                if let Err(e) = res {
+                    println!("DECODE ERROR: {:?}", e);
+                }
+            },
+            "-w" => {
+                use heliqs::{Decoder, WatOptions, module_to_wat};
+                let mut decoder = Decoder::new(reader);
+                match decoder.decode() {
+                    Ok(module) => {
+                        let options = WatOptions {
+                            folded: args.get(3).is_some_and(|s| s == "--fold"),
+                            context: decoder.context,
+                            func_contexts: decoder.func_contexts,
+                        };
+                        match module_to_wat(&module, &options) {
+                            Ok(wat) => print!("{}", wat),
+                            Err(e) => println!("DECODE ERROR: {:?}", e),
+                        }
+                    },
                     Err(e) => println!("DECODE ERROR: {:?}", e),
                 }
             },
             _ => panic!("invalid option"),
         }
     } else {
-        run(&mut reader);
+        run(file_name, &mut reader);
     }
 }
 
@@ -66,23 +85,33 @@ fn lex<R: Read + Seek>(reader: &mut R) {
     }
 }
 
-use heliqs::ParseError;
-fn parse<R: Read + Seek>(reader: &mut R) -> Result<(), ParseError> {
+use heliqs::Diagnostic;
+// ソースの該当行を付けて、標準エラーに出す
+// 端末に出すときだけ色を付ける(NO_COLORがあれば付けない)
+fn print_diagnostics(file_name: &str, diagnostics: &[Diagnostic]) {
+    use std::io::IsTerminal;
+    let source = std::fs::read(file_name).map(|bytes| String::from_utf8_lossy(&bytes).into_owned()).unwrap_or_default();
+    let color = env::var_os("NO_COLOR").is_none() && std::io::stderr().is_terminal();
+    for diagnostic in diagnostics {
+        eprintln!("{}", diagnostic.render(file_name, &source, color));
+    }
+}
+
+fn parse<R: Read + Seek>(file_name: &str, reader: &mut R) {
     use heliqs::Parser;
     let mut parser = Parser::new(reader);
-    parser.parse()
+    if let Err(diagnostics) = parser.parse_with_diagnostics() {
+        print_diagnostics(file_name, &diagnostics);
+    }
 }
 
-fn run<R: Read + Seek>(reader: &mut R) {
+fn run<R: Read + Seek>(file_name: &str, reader: &mut R) {
     use heliqs::Parser;
     let mut parser = Parser::new(reader);
 
-    match parser.parse() {
-        Err(err) => {
-            println!("PARSE ERROR: {:?}", err);
-            return;
-        },
-        _ => {},
+    if let Err(diagnostics) = parser.parse_with_diagnostics() {
+        print_diagnostics(file_name, &diagnostics);
+        return;
     }
     pp!(MODULE, parser.module);
 
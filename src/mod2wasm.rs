@@ -1,5 +1,4 @@
-use std::fs::File;
-use std::io::prelude::*;
+use std::io::Write;
 use std::convert::TryInto;
 
 use super::*;
@@ -8,78 +7,223 @@ use context::*;
 
 type Byte = u8;
 
-pub fn module_to_wasm(module: &Module) -> std::io::Result<()> {
-    let mut file = File::create("wasm/_.wasm")?;
-    file.write_all(&module2wasm(&module))?;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SectionId {
+    Custom = 0,
+    Type = 1,
+    Import = 2,
+    Func = 3,
+    Table = 4,
+    Memory = 5,
+    Global = 6,
+    Export = 7,
+    Start = 8,
+    Elem = 9,
+    Code = 10,
+    Data = 11,
+}
+
+// 実行時にだけ現れる管理命令は、バイナリに書き出せない
+// 関数の型が存在しない場合は、引数とローカル変数を区別できない
+#[derive(Debug, Clone, PartialEq)]
+pub enum EncodeError {
+    AdministrativeInstr(Instr),
+    UnknownType(TypeIndex),
+}
+
+impl SectionId {
+    pub fn from_byte(b: Byte) -> Option<SectionId> {
+        match b {
+            0 => Some(SectionId::Custom),
+            1 => Some(SectionId::Type),
+            2 => Some(SectionId::Import),
+            3 => Some(SectionId::Func),
+            4 => Some(SectionId::Table),
+            5 => Some(SectionId::Memory),
+            6 => Some(SectionId::Global),
+            7 => Some(SectionId::Export),
+            8 => Some(SectionId::Start),
+            9 => Some(SectionId::Elem),
+            10 => Some(SectionId::Code),
+            11 => Some(SectionId::Data),
+            _ => None,
+        }
+    }
+}
+
+// バイナリへの変換のオプション
+// minimal_leb128がfalseの場合、セクションと関数本体のサイズを5バイト固定で書く(後から書き換えやすいように)
+// contextとfunc_contextsはParserが集めた識別子で、nameセクションの生成に使う
+// customsはモジュールの独自セクションに加えて書き出す(位置はCustom::beforeやCustom::afterで指定する)
+pub struct EncodeOptions {
+    pub minimal_leb128: bool,
+    pub context: Context,
+    pub func_contexts: Vec<Context>,
+    pub customs: Vec<Custom>,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        EncodeOptions { minimal_leb128: true, context: Context::default(), func_contexts: vec![], customs: vec![] }
+    }
+}
+
+pub fn module_to_wasm(module: &Module, options: &EncodeOptions) -> Result<Vec<u8>, Error> {
+    module2wasm(module, options)
+}
+
+// 書き出す前にすべてエンコードするので、エンコードに失敗した場合writerには何も書かない
+pub fn module_write_wasm<W: Write>(module: &Module, options: &EncodeOptions, writer: &mut W) -> Result<(), Error> {
+    let bytes = module2wasm(module, options)?;
+    writer.write_all(&bytes)?;
     Ok(())
 }
 
-fn module2wasm(module: &Module) -> Vec<Byte> {
-    [
+fn module2wasm(module: &Module, options: &EncodeOptions) -> Result<Vec<Byte>, Error> {
+    // 中身が空のセクションは書き出さない
+    let sections = vec![
+        (SectionId::Type, module.types.is_empty(), typesection2wasm(&module.types)),
+        (SectionId::Import, module.imports.is_empty(), importsection2wasm(&module.imports)),
+        (SectionId::Func, module.funcs.is_empty(), funcsection2wasm(&module.funcs)),
+        (SectionId::Table, module.tables.is_empty(), tablesection2wasm(&module.tables)),
+        (SectionId::Memory, module.mems.is_empty(), memorysection2wasm(&module.mems)),
+        (SectionId::Global, module.globals.is_empty(), globalsection2wasm(&module.globals)?),
+        (SectionId::Export, module.exports.is_empty(), exportsection2wasm(&module.exports)),
+        (SectionId::Start, module.start.is_none(), startsection2wasm(&module.start)),
+        (SectionId::Elem, module.elems.is_empty(), elementsection2wasm(&module.elems)?),
+        (SectionId::Code, module.funcs.is_empty(), codesection2wasm(module, options)?),
+        (SectionId::Data, module.data.is_empty(), datasection2wasm(&module.data)?),
+    ];
+
+    // nameセクションを生成した場合は、元からあるものと置き換える
+    let names = namesection2wasm(module, options);
+    let customs = module.customs.iter()
+        .filter(|c| names.is_none() || c.name != "name")
+        .chain(options.customs.iter())
+        .chain(names.iter())
+        .collect::<Vec<&Custom>>();
+
+    let mut bytes = [
         b"\0asm".to_vec(),
         vec![0x01, 0x00, 0x00, 0x00],
-        typesection2wasm(&module.types),
-        importsection2wasm(&module.imports),
-        funcsection2wasm(&module.funcs),
-        tablesection2wasm(&module.tables),
-        memorysection2wasm(&module.mems),
-        globalsection2wasm(&module.globals),
-        exportsection2wasm(&module.exports),
-        startsection2wasm(&module.start),
-        elementsection2wasm(&module.elems),
-        codesection2wasm(&module.funcs),
-        datasection2wasm(&module.data),
+        customsections2wasm(&customs, SectionId::Custom, options),
     ]
-    .concat()
+    .concat();
+
+    // 独自セクションは、元の位置(直前の既知のセクションの後ろ)に置く
+    for (id, is_empty, cont) in sections {
+        if !is_empty {
+            bytes.extend(section2wasm(id, cont, options));
+        }
+        bytes.extend(customsections2wasm(&customs, id, options));
+    }
+
+    Ok(bytes)
 }
 
 fn typesection2wasm(types: &Vec<FuncType>) -> Vec<Byte> {
-    section2wasm(1, vector2wasm(types.iter().map(functype2wasm).collect()))
+    vector2wasm(types.iter().map(functype2wasm).collect())
 }
 
 fn importsection2wasm(imps: &Vec<Import>) -> Vec<Byte> {
-    section2wasm(2, vector2wasm(imps.iter().map(import2wasm).collect())) 
+    vector2wasm(imps.iter().map(import2wasm).collect())
 }
 
 fn funcsection2wasm(funcs: &Vec<Func>) -> Vec<Byte> {
-    let typeindices = funcs.iter().map(|f| &f.0).map(typeidx2wasm).collect::<Vec<Vec<Byte>>>().concat();
-    section2wasm(3, bytevector2wasm(typeindices))
+    vector2wasm(funcs.iter().map(|f| &f.0).map(typeidx2wasm).collect())
 }
 
 fn tablesection2wasm(tables: &Vec<Table>) -> Vec<Byte> {
-    section2wasm(4, vector2wasm(tables.iter().map(table2wasm).collect())) 
+    vector2wasm(tables.iter().map(table2wasm).collect())
 }
 
 fn memorysection2wasm(mems: &Vec<Memory>) -> Vec<Byte> {
-    section2wasm(5, vector2wasm(mems.iter().map(mem2wasm).collect()))
+    vector2wasm(mems.iter().map(mem2wasm).collect())
 }
 
-fn globalsection2wasm(globals: &Vec<Global>) -> Vec<Byte> {
-    section2wasm(6, vector2wasm(globals.iter().map(global2wasm).collect()))
+fn globalsection2wasm(globals: &[Global]) -> Result<Vec<Byte>, Error> {
+    Ok(vector2wasm(globals.iter().map(global2wasm).collect::<Result<_, _>>()?))
 }
 
 fn exportsection2wasm(exps: &Vec<Export>) -> Vec<Byte> {
-    section2wasm(7, vector2wasm(exps.iter().map(export2wasm).collect())) 
+    vector2wasm(exps.iter().map(export2wasm).collect())
 }
 
 fn startsection2wasm(stt: &Option<Start>) -> Vec<Byte> {
     if let Some(start) = stt {
-        section2wasm(8, funcidx2wasm(&start.0)) 
+        funcidx2wasm(&start.0)
     } else {
         vec![]
     }
 }
 
-fn elementsection2wasm(elems: &Vec<Elem>) -> Vec<Byte> {
-    section2wasm(9, vector2wasm(elems.iter().map(elem2wasm).collect())) 
+fn elementsection2wasm(elems: &[Elem]) -> Result<Vec<Byte>, Error> {
+    Ok(vector2wasm(elems.iter().map(elem2wasm).collect::<Result<_, _>>()?))
+}
+
+fn codesection2wasm(module: &Module, options: &EncodeOptions) -> Result<Vec<Byte>, Error> {
+    Ok(vector2wasm(module.funcs.iter().map(|f| code2wasm(f, &module.types, options)).collect::<Result<_, _>>()?))
 }
 
-fn codesection2wasm(funcs: &Vec<Func>) -> Vec<Byte> {
-    section2wasm(10, vector2wasm(funcs.iter().map(code2wasm).collect())) 
+fn datasection2wasm(data: &[Data]) -> Result<Vec<Byte>, Error> {
+    Ok(vector2wasm(data.iter().map(data2wasm).collect::<Result<_, _>>()?))
 }
 
-fn datasection2wasm(data: &Vec<Data>) -> Vec<Byte> {
-    section2wasm(11, vector2wasm(data.iter().map(data2wasm).collect())) 
+fn customsections2wasm(customs: &[&Custom], after: SectionId, options: &EncodeOptions) -> Vec<Byte> {
+    customs.iter().filter(|c| c.after == after).map(|c| customsection2wasm(c, options))
+        .collect::<Vec<Vec<Byte>>>().concat()
+}
+
+fn customsection2wasm(custom: &Custom, options: &EncodeOptions) -> Vec<Byte> {
+    section2wasm(SectionId::Custom, [name2wasm(&custom.name), custom.data.clone()].concat(), options)
+}
+
+// モジュール、関数、ローカル変数、ラベルの識別子からnameセクションを作る
+// 識別子が1つもなければ作らない
+fn namesection2wasm(module: &Module, options: &EncodeOptions) -> Option<Custom> {
+    let mut data = vec![];
+    if let Some(id) = &module.id {
+        data.extend(namesubsection2wasm(0, name2wasm(id)));
+    }
+    if options.context.funcs.iter().any(Option::is_some) {
+        data.extend(namesubsection2wasm(1, namemap2wasm(&options.context.funcs)));
+    }
+    let locals = options.func_contexts.iter().map(|c| &c.locals[..]).collect::<Vec<_>>();
+    if locals.iter().any(|ids| ids.iter().any(Option::is_some)) {
+        data.extend(namesubsection2wasm(2, indirectnamemap2wasm(&locals)));
+    }
+    let labels = options.func_contexts.iter().map(|c| &c.labels[..]).collect::<Vec<_>>();
+    if labels.iter().any(|ids| ids.iter().any(Option::is_some)) {
+        data.extend(namesubsection2wasm(3, indirectnamemap2wasm(&labels)));
+    }
+
+    if data.is_empty() {
+        None
+    } else {
+        Some(Custom { name: "name".into(), data, after: SectionId::Data })
+    }
+}
+
+fn namesubsection2wasm(id: Byte, cont: Vec<Byte>) -> Vec<Byte> {
+    [
+        vec![id],
+        unsigned32_to_wasm(cont.len().try_into().unwrap()),
+        cont,
+    ]
+    .concat()
+}
+
+fn namemap2wasm(ids: &[Option<Id>]) -> Vec<Byte> {
+    vector2wasm(ids.iter().enumerate()
+        .filter_map(|(idx, id)| id.as_ref().map(|id| [unsigned32_to_wasm(idx as u32), name2wasm(id)].concat()))
+        .collect())
+}
+
+fn indirectnamemap2wasm(ids: &[&[Option<Id>]]) -> Vec<Byte> {
+    vector2wasm(ids.iter().enumerate()
+        .filter(|(_, ids)| ids.iter().any(Option::is_some))
+        .map(|(idx, ids)| [unsigned32_to_wasm(idx as u32), namemap2wasm(ids)].concat())
+        .collect())
 }
 
 fn import2wasm(imp: &Import) -> Vec<Byte> {
@@ -104,12 +248,12 @@ fn table2wasm(t: &Table) -> Vec<Byte> { tabletype2wasm(&t.0) }
 
 fn mem2wasm(mem: &Memory) -> Vec<Byte> { memtype2wasm(&mem.0) }
 
-fn global2wasm(global: &Global) -> Vec<Byte> {
-    [
+fn global2wasm(global: &Global) -> Result<Vec<Byte>, Error> {
+    Ok([
         globaltype2wasm(&global.0),
-        expr2wasm(&global.1),
+        expr2wasm(&global.1)?,
     ]
-    .concat()
+    .concat())
 }
 
 fn export2wasm(exp: &Export) -> Vec<Byte> {
@@ -129,64 +273,87 @@ fn exportdesc2wasm(desc: &ExportDesc) -> Vec<Byte> {
     }
 }
 
-fn elem2wasm(elem: &Elem) -> Vec<Byte> {
-    [
+fn elem2wasm(elem: &Elem) -> Result<Vec<Byte>, Error> {
+    Ok([
         tableidx2wasm(&elem.table),
-        expr2wasm(&elem.offset),
-        bytevector2wasm(elem.init.iter().map(funcidx2wasm)
-            .collect::<Vec<Vec<Byte>>>().concat()),
+        expr2wasm(&elem.offset)?,
+        vector2wasm(elem.init.iter().map(funcidx2wasm).collect()),
     ]
-    .concat()
+    .concat())
 }
 
-fn code2wasm(func: &Func) -> Vec<Byte> {
-    let f = func2wasm(func);
-    [
-        unsigned32_to_wasm(f.len().try_into().unwrap()),
-        f, 
+fn code2wasm(func: &Func, types: &[FuncType], options: &EncodeOptions) -> Result<Vec<Byte>, Error> {
+    let f = func2wasm(func, types)?;
+    Ok([
+        size2wasm(f.len(), options),
+        f,
     ]
-    .concat()
-}
-
-fn func2wasm(func: &Func) -> Vec<Byte> {
-    [
-        func.1.iter().map(local2wasm)
-            .collect::<Vec<Vec<Byte>>>().concat(),
-        expr2wasm(&func.2),
+    .concat())
+}
+
+fn func2wasm(func: &Func, types: &[FuncType]) -> Result<Vec<Byte>, Error> {
+    // func.1は引数に続いてローカル変数を持つので、引数の分は飛ばす
+    let params = match types.get(func.0 as usize) {
+        Some(ft) => ft.0.len(),
+        None => return Err(EncodeError::UnknownType(func.0).into()),
+    };
+    Ok([
+        locals2wasm(&func.1[params.min(func.1.len())..]),
+        match &func.2 {
+            FuncBody::Expr(expr) => expr2wasm(expr)?,
+            FuncBody::Lazy(lazy) => lazy.bytes().to_vec(),
+        },
     ]
-    .concat()
+    .concat())
 }
 
-fn local2wasm(local: &ValType) -> Vec<Byte> {
-    [
-        0x01,  // TODO: to be compressed
-        valtype2wasm(local),
-    ]
-    .to_vec()
+// 同じ型が続くローカル変数は、(個数, 型)の1つの組にまとめる
+fn locals2wasm(locals: &[ValType]) -> Vec<Byte> {
+    let mut runs: Vec<(u32, &ValType)> = vec![];
+    for local in locals {
+        match runs.last_mut() {
+            Some((count, vt)) if *vt == local => *count += 1,
+            _ => runs.push((1, local)),
+        }
+    }
+    vector2wasm(runs.into_iter().map(|(count, vt)| [unsigned32_to_wasm(count), vec![valtype2wasm(vt)]].concat()).collect())
 }
 
-fn data2wasm(data: &Data) -> Vec<Byte> {
-    [
+fn data2wasm(data: &Data) -> Result<Vec<Byte>, Error> {
+    Ok([
         memidx2wasm(&data.data),
-        expr2wasm(&data.offset),
+        expr2wasm(&data.offset)?,
         datastring2wasm(&data.init),
     ]
-    .concat()
+    .concat())
 }
 
 fn datastring2wasm(ds: &DataString) -> Vec<Byte> {
-    string2wasm(ds)
+    [
+        unsigned32_to_wasm(ds.len().try_into().unwrap()),
+        ds.clone(),
+    ]
+    .concat()
 }
 
-fn section2wasm(id: Byte, cont: Vec<Byte>) -> Vec<Byte> {
+fn section2wasm(id: SectionId, cont: Vec<Byte>, options: &EncodeOptions) -> Vec<Byte> {
     [
-        vec![id],
-        unsigned32_to_wasm(cont.len().try_into().unwrap()),
+        vec![id as Byte],
+        size2wasm(cont.len(), options),
         cont,
     ]
     .concat()
 }
 
+fn size2wasm(size: usize, options: &EncodeOptions) -> Vec<Byte> {
+    let size = size.try_into().unwrap();
+    if options.minimal_leb128 {
+        unsigned32_to_wasm(size)
+    } else {
+        unsigned32_to_padded_leb128(size)
+    }
+}
+
 fn typeidx2wasm(idx: &TypeIndex) -> Vec<Byte> { unsigned32_to_wasm(*idx) }
 fn funcidx2wasm(idx: &FuncIndex) -> Vec<Byte> { unsigned32_to_wasm(*idx) }
 fn tableidx2wasm(idx: &TableIndex) -> Vec<Byte> { unsigned32_to_wasm(*idx) }
@@ -195,30 +362,35 @@ fn globalidx2wasm(idx: &GlobalIndex) -> Vec<Byte> { unsigned32_to_wasm(*idx) }
 fn localidx2wasm(idx: &LocalIndex) -> Vec<Byte> { unsigned32_to_wasm(*idx) }
 fn labelidx2wasm(idx: &LabelIndex) -> Vec<Byte> { unsigned32_to_wasm(*idx) }
 
-fn expr2wasm(expr: &Expr) -> Vec<Byte> {
-    [
-        expr.0.iter().map(instr2wasm).collect::<Vec<Vec<Byte>>>().concat(),
+fn expr2wasm(expr: &Expr) -> Result<Vec<Byte>, Error> {
+    Ok([
+        instrs2wasm(&expr.0)?,
         vec![0x0B],
     ]
-    .concat()
+    .concat())
 }
 
-fn instr2wasm(instr: &Instr) -> Vec<Byte> {
-    match instr {
+// 末尾のendを付けない命令列
+fn instrs2wasm(instrs: &[Instr]) -> Result<Vec<Byte>, Error> {
+    Ok(instrs.iter().map(instr2wasm).collect::<Result<Vec<Vec<Byte>>, Error>>()?.concat())
+}
+
+fn instr2wasm(instr: &Instr) -> Result<Vec<Byte>, Error> {
+    Ok(match instr {
         Instr::Unreachable => vec![0x00],
         Instr::Nop => vec![0x01],
         Instr::Block(rt, expr) => [
-            vec![0x02], blocktype2wasm(&rt), expr2wasm(expr), vec![0x0B]
+            vec![0x02], blocktype2wasm(rt), expr2wasm(expr)?
         ].concat(),
         Instr::Loop(rt, expr) => [
-            vec![0x03], blocktype2wasm(&rt), expr2wasm(expr), vec![0x0B]
+            vec![0x03], blocktype2wasm(rt), expr2wasm(expr)?
         ].concat(),
         Instr::If(rt, expr1, expr2) => {
-            let true_term = [vec![0x04], blocktype2wasm(&rt), expr2wasm(expr1)].concat();
+            let true_term = [vec![0x04], blocktype2wasm(rt), instrs2wasm(&expr1.0)?].concat();
             if expr2.0.is_empty() {
                 [true_term, vec![0x0B]].concat()
             } else {
-                [true_term, vec![0x05], expr2wasm(expr2), vec![0x0B]].concat()
+                [true_term, vec![0x05], expr2wasm(expr2)?].concat()
             }
         },
         Instr::Br(labelidx) => [vec![0x0C], labelidx2wasm(labelidx)].concat(),
@@ -270,8 +442,8 @@ fn instr2wasm(instr: &Instr) -> Vec<Byte> {
         Instr::MemorySize => vec![0x3F, 0x00],
         Instr::MemoryGrow => vec![0x40, 0x00],
 
-        Instr::I32Const(n) => [vec![0x41], unsigned32_to_wasm(*n)].concat(),
-        Instr::I64Const(n) => [vec![0x42], unsigned64_to_wasm(*n)].concat(),
+        Instr::I32Const(n) => [vec![0x41], signed32_to_wasm(*n as i32)].concat(),
+        Instr::I64Const(n) => [vec![0x42], signed64_to_wasm(*n as i64)].concat(),
         Instr::F32Const(n) => [vec![0x43], n.to_bits().to_le_bytes().to_vec()].concat(),
         Instr::F64Const(n) => [vec![0x44], n.to_bits().to_le_bytes().to_vec()].concat(),
 
@@ -485,8 +657,14 @@ fn instr2wasm(instr: &Instr) -> Vec<Byte> {
                 CvtOp::FReinterpretFromI(ValSize::V64) => vec![0xBF],
             }
         }
-        _ => unimplemented!(),
-    }
+
+        Instr::Trap |
+        Instr::Invoke(_) |
+        Instr::InitElem(..) |
+        Instr::InitData(..) |
+        Instr::Label(..) |
+        Instr::Frame(..) => return Err(EncodeError::AdministrativeInstr(instr.clone()).into()),
+    })
 }
 
 fn memarg2wasm(memarg: &MemArg) -> Vec<Byte> {
@@ -547,7 +725,7 @@ fn functype2wasm(func: &FuncType) -> Vec<Byte> {
 
 fn blocktype2wasm(results: &[ValType]) -> Vec<Byte> {
     if results.is_empty() {
-        vec![]
+        vec![0x40]
     } else {
         vec![valtype2wasm(&results[0])]
     }    
@@ -611,22 +789,34 @@ fn unsigned32_to_leb128(n: u32) -> Vec<Byte> {
     }
 }
 
-fn unsigned64_to_wasm(n: u64) -> Vec<Byte> {
-    unsigned64_to_leb128(n)
+// 常に5バイトで書く
+fn unsigned32_to_padded_leb128(n: u32) -> Vec<Byte> {
+    (0..5).map(|i| {
+        let b = ((n >> (i * 7)) & 0x7F) as Byte;
+        if i < 4 { b | 0x80 } else { b }
+    }).collect()
+}
+
+fn signed32_to_wasm(n: i32) -> Vec<Byte> {
+    signed64_to_leb128(n as i64)
+}
+
+fn signed64_to_wasm(n: i64) -> Vec<Byte> {
+    signed64_to_leb128(n)
 }
 
-fn unsigned64_to_leb128(n: u64) -> Vec<Byte> {
+// 残りのビットがすべて符号ビットと同じになったところで終わる
+fn signed64_to_leb128(n: i64) -> Vec<Byte> {
     let mut encoded = vec![];
-    let mut n_u64 = n;
+    let mut n_i64 = n;
     loop {
-        // println!("{:#32b}", n_u32);
-        let b = n_u64 & 0x000000000000007F;
-        if b == n_u64 {
-            encoded.push(b as Byte);
+        let b = (n_i64 & 0x7F) as Byte;
+        n_i64 >>= 7;
+        if (n_i64 == 0 && b & 0x40 == 0) || (n_i64 == -1 && b & 0x40 != 0) {
+            encoded.push(b);
             return encoded;
         } else {
-            encoded.push((b as Byte) + 0x80);
-            n_u64 >>= 7;
+            encoded.push(b | 0x80);
         }
     }
 }
@@ -662,10 +852,44 @@ fn test_functype2wasm() {
 
 #[test]
 fn test_blocktype2wasm() {
-    assert_eq!(blocktype2wasm(&vec![]), vec![]);
+    assert_eq!(blocktype2wasm(&vec![]), vec![0x40]);
     assert_eq!(blocktype2wasm(&vec![ValType::I32]), vec![0x7F]);
 }
 
+#[test]
+fn test_elem2wasm() {
+    // 長さはバイト数ではなく要素の数
+    let elem = Elem { table: 0, offset: Expr(vec![Instr::I32Const(0)]), init: vec![0, 200] };
+    assert_eq!(elem2wasm(&elem).unwrap(), vec![0x00, 0x41, 0x00, 0x0B, 2, 0x00, 0xC8, 0x01]);
+}
+
+#[test]
+fn test_customsections2wasm() {
+    let mut module = Module::default();
+    module.types.push((vec![], vec![]));
+    module.customs.push(Custom { name: "a".into(), data: vec![1, 2], after: SectionId::Custom });
+    module.customs.push(Custom { name: "b".into(), data: vec![3], after: SectionId::Type });
+    assert_eq!(module2wasm(&module, &EncodeOptions::default()).unwrap()[8..20].to_vec(), vec![
+        0x00, 0x04, 0x01, 0x61, 0x01, 0x02,
+        0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+    ]);
+    assert_eq!(module2wasm(&module, &EncodeOptions::default()).unwrap()[20..25].to_vec(), vec![0x00, 0x03, 0x01, 0x62, 0x03]);
+}
+
+#[test]
+fn test_module_to_wasm() {
+    let mut module = Module::default();
+    module.types.push((vec![ValType::I32], vec![]));
+
+    let bytes = module_to_wasm(&module, &EncodeOptions::default()).unwrap();
+    let mut written = vec![];
+    module_write_wasm(&module, &EncodeOptions::default(), &mut written).unwrap();
+    assert_eq!(bytes, written);
+
+    let decoded = ::decoder::Decoder::new(&bytes[..]).decode().unwrap();
+    assert_eq!(decoded.types, module.types);
+}
+
 #[test]
 fn test_name2wasm() {
     assert_eq!(name2wasm(&"a".into()), vec![1, 97]);
@@ -683,4 +907,110 @@ fn test_unsigned32_to_leb128() {
     assert_eq!(unsigned32_to_leb128(0x7F), vec![0x7F]);
     assert_eq!(unsigned32_to_leb128(0x80), vec![0x80, 0x01]);
     assert_eq!(unsigned32_to_leb128(624485), vec![0xE5, 0x8E, 0x26]);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_signed64_to_leb128() {
+    assert_eq!(signed64_to_leb128(0), vec![0x00]);
+    assert_eq!(signed64_to_leb128(63), vec![0x3F]);
+    assert_eq!(signed64_to_leb128(64), vec![0xC0, 0x00]);
+    assert_eq!(signed64_to_leb128(-1), vec![0x7F]);
+    assert_eq!(signed64_to_leb128(-64), vec![0x40]);
+    assert_eq!(signed64_to_leb128(-123456), vec![0xC0, 0xBB, 0x78]);
+    assert_eq!(signed32_to_wasm(i32::MIN), vec![0x80, 0x80, 0x80, 0x80, 0x78]);
+    assert_eq!(signed64_to_wasm(i64::MIN), vec![0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x7F]);
+}
+
+#[test]
+fn test_instr2wasm() {
+    assert_eq!(instr2wasm(&Instr::I32Const(-1i32 as u32)), Ok(vec![0x41, 0x7F]));
+    assert_eq!(instr2wasm(&Instr::I64Const(0x80)), Ok(vec![0x42, 0x80, 0x01]));
+    assert_eq!(instr2wasm(&Instr::Block(vec![], Expr(vec![Instr::Br(0)]))), Ok(vec![0x02, 0x40, 0x0C, 0x00, 0x0B]));
+    assert_eq!(instr2wasm(&Instr::If(vec![ValType::I32], Expr(vec![Instr::I32Const(1)]), Expr(vec![Instr::I32Const(2)]))),
+        Ok(vec![0x04, 0x7F, 0x41, 0x01, 0x05, 0x41, 0x02, 0x0B]));
+    assert_eq!(instr2wasm(&Instr::If(vec![], Expr(vec![Instr::Nop]), Expr(vec![]))), Ok(vec![0x04, 0x40, 0x01, 0x0B]));
+    assert_eq!(instr2wasm(&Instr::Load(ValType::I64, MemArg { align: 3, offset: 0x80 })), Ok(vec![0x29, 0x03, 0x80, 0x01]));
+
+    // 管理命令はエンコードできない
+    assert_eq!(instr2wasm(&Instr::Trap), Err(Error::Encode(EncodeError::AdministrativeInstr(Instr::Trap))));
+    let mut module = Module::default();
+    module.types.push((vec![], vec![]));
+    module.funcs.push(Func(0, vec![], FuncBody::Expr(Expr(vec![Instr::Invoke(0)]))));
+    let mut written = vec![];
+    assert!(module_write_wasm(&module, &EncodeOptions::default(), &mut written).is_err());
+    assert!(written.is_empty());
+}
+
+#[test]
+fn test_compact_encoding() {
+    let mut module = Module::default();
+    module.types.push((vec![ValType::I32], vec![]));
+    module.funcs.push(Func(0, vec![ValType::I32, ValType::I64, ValType::I64, ValType::I32], FuncBody::Expr(Expr(vec![]))));
+
+    // 引数を除いたローカル変数がまとめられ、空のセクションは書き出されない
+    let bytes = module_to_wasm(&module, &EncodeOptions::default()).unwrap();
+    assert_eq!(bytes[8..].to_vec(), vec![
+        0x01, 0x05, 0x01, 0x60, 0x01, 0x7F, 0x00,
+        0x03, 0x02, 0x01, 0x00,
+        0x0A, 0x08, 0x01, 0x06, 0x02, 0x02, 0x7E, 0x01, 0x7F, 0x0B,
+    ]);
+    let decoded = ::decoder::Decoder::new(&bytes[..]).decode().unwrap();
+    assert_eq!(decoded.funcs, module.funcs);
+
+    let options = EncodeOptions { minimal_leb128: false, ..EncodeOptions::default() };
+    let bytes = module_to_wasm(&module, &options).unwrap();
+    assert_eq!(bytes[8..14].to_vec(), vec![0x01, 0x85, 0x80, 0x80, 0x80, 0x00]);
+    let decoded = ::decoder::Decoder::new(&bytes[..]).decode().unwrap();
+    assert_eq!(decoded.funcs, module.funcs);
+
+    module.funcs[0].0 = 1;
+    assert_eq!(module_to_wasm(&module, &EncodeOptions::default()), Err(Error::Encode(EncodeError::UnknownType(1))));
+}
+
+#[test]
+fn test_namesection2wasm() {
+    let wat = "(module $m (type (func (param i32))) (func $f (type 0) (param $p i32) (local i64) (local $l i32) block $b loop br $b end end))";
+    let mut parser = ::parser::Parser::from_text(wat);
+    parser.parse().unwrap();
+
+    let options = EncodeOptions { context: parser.contexts[0].clone(), func_contexts: parser.func_contexts.clone(), ..EncodeOptions::default() };
+    let bytes = module_to_wasm(&parser.module, &options).unwrap();
+    let mut decoder = ::decoder::Decoder::new(&bytes[..]);
+    let module = decoder.decode().unwrap();
+
+    assert_eq!(module.id, Some("m".to_string()));
+    assert_eq!(module.customs.iter().filter(|c| c.name == "name").count(), 1);
+    assert_eq!(decoder.context.funcs, vec![Some("f".to_string())]);
+    assert_eq!(decoder.func_contexts[0].locals, vec![Some("p".to_string()), None, Some("l".to_string())]);
+    assert_eq!(decoder.func_contexts[0].labels, vec![Some("b".to_string())]);
+
+    // 再エンコードしても、nameセクションは1つだけ
+    let options = EncodeOptions { context: decoder.context.clone(), func_contexts: decoder.func_contexts.clone(), ..EncodeOptions::default() };
+    assert_eq!(module_to_wasm(&module, &options).unwrap(), bytes);
+
+    // 識別子がなければ作らない
+    let mut module = Module::default();
+    module.types.push((vec![], vec![]));
+    assert!(namesection2wasm(&module, &EncodeOptions::default()).is_none());
+}
+
+#[test]
+fn test_attach_customs() {
+    let wat = r#"(module (@custom "a" (before first) "1") (type (func)) (@custom "b" "2" "3") (@custom "c" (after type) "4"))"#;
+    let mut parser = ::parser::Parser::from_text(wat);
+    parser.parse().unwrap();
+    assert_eq!(parser.module.customs, vec![
+        Custom::after("a".into(), b"1".to_vec(), SectionId::Custom),
+        Custom::after("b".into(), b"23".to_vec(), SectionId::Data),
+        Custom::after("c".into(), b"4".to_vec(), SectionId::Type),
+    ]);
+
+    let options = EncodeOptions {
+        customs: vec![Custom::before("d".into(), vec![5], SectionId::Code)],
+        ..EncodeOptions::default()
+    };
+    let bytes = module_to_wasm(&parser.module, &options).unwrap();
+    let module = ::decoder::Decoder::new(&bytes[..]).decode().unwrap();
+    let placed = module.customs.iter().map(|c| (c.name.as_str(), c.after)).collect::<Vec<_>>();
+    assert_eq!(placed, vec![("a", SectionId::Custom), ("c", SectionId::Type), ("d", SectionId::Type), ("b", SectionId::Type)]);
+}
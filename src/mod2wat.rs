@@ -0,0 +1,854 @@
+use std::collections::HashSet;
+
+use instr::*;
+use context::*;
+use parser::*;
+use lexer::is_idchar;
+use error::Error;
+use mod2wasm::EncodeError;
+
+// テキストフォーマットへの変換のオプション
+// contextとfunc_contextsはDecoderがnameセクションから復元したもの(空なら番号で出力する)
+#[derive(Default)]
+pub struct WatOptions {
+    pub folded: bool,
+    pub context: Context,
+    pub func_contexts: Vec<Context>,
+}
+
+pub fn module_to_wat(module: &Module, options: &WatOptions) -> Result<String, Error> {
+    let mut printer = WatPrinter::new(module, options);
+    printer.module()?;
+    Ok(printer.out)
+}
+
+// 畳み込み形式の命令
+// 結果の数は、後続の命令のオペランドとして畳み込めるかの判定に使う
+enum Folded {
+    Plain(String, Vec<Folded>, usize),
+    Block(String, Vec<Folded>, usize),
+    If(String, Vec<Folded>, Vec<Folded>, Vec<Folded>, usize),
+}
+
+impl Folded {
+    fn results(&self) -> usize {
+        match self {
+            Folded::Plain(_, _, n) | Folded::Block(_, _, n) | Folded::If(_, _, _, _, n) => *n,
+        }
+    }
+
+    fn is_inline(&self) -> bool {
+        match self {
+            Folded::Plain(_, children, _) => children.iter().all(Folded::is_inline),
+            _ => false,
+        }
+    }
+}
+
+struct WatPrinter<'a> {
+    module: &'a Module,
+    folded: bool,
+    context: Context,
+    func_contexts: &'a [Context],
+    // 関数のインデックス空間(importを含む)ごとの型
+    func_types: Vec<FuncType>,
+
+    // 出力中の関数のlocalsとlabels
+    locals: Vec<Option<Id>>,
+    label_ids: Vec<Option<Id>>,
+    label_count: usize,
+    // 出力中のブロックのラベルと、その引数の数(内側が後ろ)
+    labels: Vec<(Option<Id>, usize)>,
+    results: usize,
+
+    out: String,
+}
+
+impl<'a> WatPrinter<'a> {
+    fn new(module: &'a Module, options: &'a WatOptions) -> Self {
+        let mut func_types = vec![];
+        for import in &module.imports {
+            if let ImportDesc::Func(typeidx) = import.2 {
+                func_types.push(module.types.get(typeidx as usize).cloned().unwrap_or_default());
+            }
+        }
+        for func in &module.funcs {
+            func_types.push(module.types.get(func.0 as usize).cloned().unwrap_or_default());
+        }
+
+        let names = &options.context;
+        let context = Context {
+            types: unique_ids(&names.types),
+            funcs: unique_ids(&names.funcs),
+            tables: unique_ids(&names.tables),
+            mems: unique_ids(&names.mems),
+            globals: unique_ids(&names.globals),
+            ..Context::default()
+        };
+
+        WatPrinter {
+            module,
+            folded: options.folded,
+            context,
+            func_contexts: &options.func_contexts,
+            func_types,
+            locals: vec![],
+            label_ids: vec![],
+            label_count: 0,
+            labels: vec![],
+            results: 0,
+            out: String::new(),
+        }
+    }
+
+    // フィールドはParserが受け付ける順に出力する
+    fn module(&mut self) -> Result<(), Error> {
+        let module = self.module;
+
+        self.out.push_str("(module");
+        if let Some(id) = &module.id {
+            if is_valid_id(id) { self.out.push_str(&format!(" ${}", id)); }
+        }
+        self.out.push('\n');
+
+        for (i, ft) in module.types.iter().enumerate() {
+            let line = format!("  (type{} {})\n", def_id(&self.context.types, i), functype(ft));
+            self.out.push_str(&line);
+        }
+
+        let mut counts = [0; 4];
+        for import in &module.imports {
+            let desc = match &import.2 {
+                ImportDesc::Func(typeidx) => {
+                    counts[0] += 1;
+                    let ft = module.types.get(*typeidx as usize).cloned().unwrap_or_default();
+                    format!("(func{} (type {}){})", def_id(&self.context.funcs, counts[0] - 1),
+                        idx(&self.context.types, *typeidx), signature(&ft, &[]))
+                },
+                ImportDesc::Table(tt) => {
+                    counts[1] += 1;
+                    format!("(table{} {})", def_id(&self.context.tables, counts[1] - 1), tabletype(tt))
+                },
+                ImportDesc::Mem(mt) => {
+                    counts[2] += 1;
+                    format!("(memory{} {})", def_id(&self.context.mems, counts[2] - 1), limits(&mt.0))
+                },
+                ImportDesc::Global(gt) => {
+                    counts[3] += 1;
+                    format!("(global{} {})", def_id(&self.context.globals, counts[3] - 1), globaltype(gt))
+                },
+            };
+            let line = format!("  (import {} {} {})\n", string(import.0.as_bytes()), string(import.1.as_bytes()), desc);
+            self.out.push_str(&line);
+        }
+
+        for (i, table) in module.tables.iter().enumerate() {
+            let line = format!("  (table{} {})\n", def_id(&self.context.tables, counts[1] + i), tabletype(&table.0));
+            self.out.push_str(&line);
+        }
+
+        for (i, mem) in module.mems.iter().enumerate() {
+            let line = format!("  (memory{} {})\n", def_id(&self.context.mems, counts[2] + i), limits(&(mem.0).0));
+            self.out.push_str(&line);
+        }
+
+        for (i, global) in module.globals.iter().enumerate() {
+            let line = format!("  (global{} {}", def_id(&self.context.globals, counts[3] + i), globaltype(&global.0));
+            self.out.push_str(&line);
+            self.begin_func(None, vec![]);
+            self.inline_expr(&global.1)?;
+            self.out.push_str(")\n");
+        }
+
+        for (i, func) in module.funcs.iter().enumerate() {
+            self.func(counts[0] + i, func)?;
+        }
+
+        for export in &module.exports {
+            let desc = match &export.1 {
+                ExportDesc::Func(x) => format!("(func {})", idx(&self.context.funcs, *x)),
+                ExportDesc::Table(x) => format!("(table {})", idx(&self.context.tables, *x)),
+                ExportDesc::Mem(x) => format!("(memory {})", idx(&self.context.mems, *x)),
+                ExportDesc::Global(x) => format!("(global {})", idx(&self.context.globals, *x)),
+            };
+            let line = format!("  (export {} {})\n", string(export.0.as_bytes()), desc);
+            self.out.push_str(&line);
+        }
+
+        if let Some(start) = &module.start {
+            let line = format!("  (start {})\n", idx(&self.context.funcs, start.0));
+            self.out.push_str(&line);
+        }
+
+        for elem in &module.elems {
+            let line = format!("  (elem {} (offset", idx(&self.context.tables, elem.table));
+            self.out.push_str(&line);
+            self.begin_func(None, vec![]);
+            self.inline_expr(&elem.offset)?;
+            self.out.push(')');
+            for funcidx in &elem.init {
+                let s = format!(" {}", idx(&self.context.funcs, *funcidx));
+                self.out.push_str(&s);
+            }
+            self.out.push_str(")\n");
+        }
+
+        for data in &module.data {
+            let line = format!("  (data {} (offset", idx(&self.context.mems, data.data));
+            self.out.push_str(&line);
+            self.begin_func(None, vec![]);
+            self.inline_expr(&data.offset)?;
+            let line = format!(") {})\n", string(&data.init));
+            self.out.push_str(&line);
+        }
+
+        self.out.push_str(")\n");
+        Ok(())
+    }
+
+    fn func(&mut self, funcidx: usize, func: &Func) -> Result<(), Error> {
+        let ft = self.func_types[funcidx].clone();
+        let func_contexts = self.func_contexts;
+        self.begin_func(func_contexts.get(funcidx), ft.1.clone());
+
+        let line = format!("  (func{} (type {}){}", def_id(&self.context.funcs, funcidx),
+            idx(&self.context.types, func.0), signature(&ft, &self.locals));
+        self.out.push_str(&line);
+        self.out.push('\n');
+
+        for (i, vt) in func.1.iter().enumerate().skip(ft.0.len()) {
+            let line = format!("    (local{} {})\n", def_id(&self.locals, i), valtype(vt));
+            self.out.push_str(&line);
+        }
+
+        let Expr(instrs) = func.2.expr()?;
+        if self.folded {
+            let nodes = self.fold(instrs)?;
+            self.folded_instrs(&nodes, 4);
+        } else {
+            self.instrs(instrs, 4)?;
+        }
+
+        self.out.push_str("  )\n");
+        Ok(())
+    }
+
+    // 関数ごとのlocalsとlabelsの識別子を準備する
+    fn begin_func(&mut self, func_context: Option<&Context>, results: ResultType) {
+        let empty = Context::default();
+        let func_context = func_context.unwrap_or(&empty);
+        self.locals = unique_ids(&func_context.locals);
+        self.label_ids = func_context.labels.iter()
+            .map(|id| id.clone().filter(|id| is_valid_id(id)))
+            .collect();
+        self.label_count = 0;
+        self.labels = vec![];
+        self.results = results.len();
+    }
+
+    // globalやoffsetの定数式は1行で出力する
+    // ブロックを含むこともあるので、関数本体と同じように出力してから行をつなげる
+    fn inline_expr(&mut self, expr: &Expr) -> Result<(), Error> {
+        let out = std::mem::take(&mut self.out);
+        if self.folded {
+            let nodes = self.fold(&expr.0)?;
+            self.folded_instrs(&nodes, 0);
+        } else {
+            self.instrs(&expr.0, 0)?;
+        }
+        let body = std::mem::replace(&mut self.out, out);
+        for line in body.lines() {
+            self.out.push(' ');
+            self.out.push_str(line.trim_start());
+        }
+        Ok(())
+    }
+
+    fn instrs(&mut self, instrs: &[Instr], indent: usize) -> Result<(), Error> {
+        let pad = " ".repeat(indent);
+        for instr in instrs {
+            match instr {
+                Instr::Block(rt, expr) | Instr::Loop(rt, expr) => {
+                    let keyword = if let Instr::Block(_, _) = instr { "block" } else { "loop" };
+                    let (label, arity) = (self.next_label(), if keyword == "block" { rt.len() } else { 0 });
+                    let line = format!("{}{}{}{}\n", pad, keyword, label_def(&label), blocktype(rt));
+                    self.out.push_str(&line);
+                    self.labels.push((label, arity));
+                    self.instrs(&expr.0, indent + 2)?;
+                    self.labels.pop();
+                    self.out.push_str(&format!("{}end\n", pad));
+                },
+                Instr::If(rt, expr1, expr2) => {
+                    let label = self.next_label();
+                    let line = format!("{}if{}{}\n", pad, label_def(&label), blocktype(rt));
+                    self.out.push_str(&line);
+                    self.labels.push((label, rt.len()));
+                    self.instrs(&expr1.0, indent + 2)?;
+                    if !expr2.0.is_empty() {
+                        self.out.push_str(&format!("{}else\n", pad));
+                        self.instrs(&expr2.0, indent + 2)?;
+                    }
+                    self.labels.pop();
+                    self.out.push_str(&format!("{}end\n", pad));
+                },
+                _ => {
+                    let line = format!("{}{}\n", pad, self.plain(instr)?);
+                    self.out.push_str(&line);
+                },
+            }
+        }
+        Ok(())
+    }
+
+    // 直前のいくつかの命令が、それぞれちょうど1つずつ値を積むならオペランドとして畳み込む
+    fn fold(&mut self, instrs: &[Instr]) -> Result<Vec<Folded>, Error> {
+        let mut nodes: Vec<Folded> = vec![];
+        for instr in instrs {
+            let node = match instr {
+                Instr::Block(rt, expr) | Instr::Loop(rt, expr) => {
+                    let keyword = if let Instr::Block(_, _) = instr { "block" } else { "loop" };
+                    let (label, arity) = (self.next_label(), if keyword == "block" { rt.len() } else { 0 });
+                    let head = format!("{}{}{}", keyword, label_def(&label), blocktype(rt));
+                    self.labels.push((label, arity));
+                    let body = self.fold(&expr.0)?;
+                    self.labels.pop();
+                    Folded::Block(head, body, rt.len())
+                },
+                Instr::If(rt, expr1, expr2) => {
+                    let label = self.next_label();
+                    let head = format!("if{}{}", label_def(&label), blocktype(rt));
+                    let cond = take_operands(&mut nodes, 1);
+                    self.labels.push((label, rt.len()));
+                    let then = self.fold(&expr1.0)?;
+                    let els = self.fold(&expr2.0)?;
+                    self.labels.pop();
+                    Folded::If(head, cond, then, els, rt.len())
+                },
+                _ => {
+                    let head = self.plain(instr)?;
+                    match self.arity(instr) {
+                        Some((params, results)) => Folded::Plain(head, take_operands(&mut nodes, params), results),
+                        None => Folded::Plain(head, vec![], 0),
+                    }
+                },
+            };
+            nodes.push(node);
+        }
+        Ok(nodes)
+    }
+
+    fn folded_instrs(&mut self, nodes: &[Folded], indent: usize) {
+        for node in nodes {
+            self.out.push_str(&" ".repeat(indent));
+            self.folded_instr(node, indent);
+            self.out.push('\n');
+        }
+    }
+
+    fn folded_instr(&mut self, node: &Folded, indent: usize) {
+        match node {
+            Folded::Plain(head, children, _) => {
+                self.out.push_str(&format!("({}", head));
+                if node.is_inline() {
+                    for child in children {
+                        self.out.push(' ');
+                        self.folded_instr(child, indent);
+                    }
+                    self.out.push(')');
+                } else {
+                    self.out.push('\n');
+                    self.folded_instrs(children, indent + 2);
+                    self.out.push_str(&format!("{})", " ".repeat(indent)));
+                }
+            },
+            Folded::Block(head, body, _) => {
+                self.out.push_str(&format!("({}\n", head));
+                self.folded_instrs(body, indent + 2);
+                self.out.push_str(&format!("{})", " ".repeat(indent)));
+            },
+            Folded::If(head, cond, then, els, _) => {
+                self.out.push_str(&format!("({}\n", head));
+                self.folded_instrs(cond, indent + 2);
+                let pad = " ".repeat(indent + 2);
+                self.out.push_str(&format!("{}(then\n", pad));
+                self.folded_instrs(then, indent + 4);
+                self.out.push_str(&format!("{})\n", pad));
+                if !els.is_empty() {
+                    self.out.push_str(&format!("{}(else\n", pad));
+                    self.folded_instrs(els, indent + 4);
+                    self.out.push_str(&format!("{})\n", pad));
+                }
+                self.out.push_str(&format!("{})", " ".repeat(indent)));
+            },
+        }
+    }
+
+    fn next_label(&mut self) -> Option<Id> {
+        let label = self.label_ids.get(self.label_count).cloned().unwrap_or(None);
+        self.label_count += 1;
+        label
+    }
+
+    // 内側に同じ名前のラベルがあると別のブロックを指してしまうので、その場合は番号にする
+    fn label(&self, depth: LabelIndex) -> String {
+        let depth = depth as usize;
+        if depth < self.labels.len() {
+            let pos = self.labels.len() - 1 - depth;
+            if let Some(id) = &self.labels[pos].0 {
+                if self.labels[pos + 1..].iter().all(|(inner, _)| inner.as_ref() != Some(id)) {
+                    return format!("${}", id);
+                }
+            }
+        }
+        depth.to_string()
+    }
+
+    fn label_arity(&self, depth: LabelIndex) -> Option<usize> {
+        let depth = depth as usize;
+        if depth < self.labels.len() {
+            Some(self.labels[self.labels.len() - 1 - depth].1)
+        } else if depth == self.labels.len() {
+            Some(self.results)
+        } else {
+            None
+        }
+    }
+
+    // 命令が取り出す値と積む値の数(畳み込めない命令はNone)
+    fn arity(&self, instr: &Instr) -> Option<(usize, usize)> {
+        let arity = match instr {
+            Instr::Unreachable | Instr::Nop => (0, 0),
+            Instr::Br(l) => (self.label_arity(*l)?, 0),
+            Instr::BrIf(l) => {
+                let n = self.label_arity(*l)?;
+                (n + 1, n)
+            },
+            Instr::BrTable(_, l) => (self.label_arity(*l)? + 1, 0),
+            Instr::Return => (self.results, 0),
+            Instr::Call(x) => {
+                let ft = self.func_types.get(*x as usize)?;
+                (ft.0.len(), ft.1.len())
+            },
+            Instr::CallIndirect(x) => {
+                let ft = self.module.types.get(*x as usize)?;
+                (ft.0.len() + 1, ft.1.len())
+            },
+            Instr::Drop => (1, 0),
+            Instr::Select => (3, 1),
+            Instr::LocalGet(_) | Instr::GlobalGet(_) => (0, 1),
+            Instr::LocalSet(_) | Instr::GlobalSet(_) => (1, 0),
+            Instr::LocalTee(_) => (1, 1),
+            Instr::Load(_, _) | Instr::ILoad8(_, _, _) | Instr::ILoad16(_, _, _) | Instr::I64Load32(_, _) => (1, 1),
+            Instr::Store(_, _) | Instr::IStore8(_, _) | Instr::IStore16(_, _) | Instr::I64Store32(_) => (2, 0),
+            Instr::MemorySize => (0, 1),
+            Instr::MemoryGrow => (1, 1),
+            Instr::I32Const(_) | Instr::I64Const(_) | Instr::F32Const(_) | Instr::F64Const(_) => (0, 1),
+            Instr::IUnOp(_, _) | Instr::FUnOp(_, _) | Instr::ITestOp(_, _) | Instr::CvtOp(_) => (1, 1),
+            Instr::IBinOp(_, _) | Instr::FBinOp(_, _) | Instr::IRelOp(_, _) | Instr::FRelOp(_, _) => (2, 1),
+            _ => return None,
+        };
+        Some(arity)
+    }
+
+    // ブロック以外の命令(オペランドを含む)
+    fn plain(&self, instr: &Instr) -> Result<String, Error> {
+        Ok(match instr {
+            Instr::Unreachable => "unreachable".to_string(),
+            Instr::Nop => "nop".to_string(),
+            Instr::Br(l) => format!("br {}", self.label(*l)),
+            Instr::BrIf(l) => format!("br_if {}", self.label(*l)),
+            Instr::BrTable(ls, l) => {
+                let mut s = "br_table".to_string();
+                for l in ls.iter().chain(Some(l)) {
+                    s.push_str(&format!(" {}", self.label(*l)));
+                }
+                s
+            },
+            Instr::Return => "return".to_string(),
+            Instr::Call(x) => format!("call {}", idx(&self.context.funcs, *x)),
+            Instr::CallIndirect(x) => format!("call_indirect (type {})", idx(&self.context.types, *x)),
+
+            Instr::Drop => "drop".to_string(),
+            Instr::Select => "select".to_string(),
+
+            Instr::LocalGet(x) => format!("local.get {}", idx(&self.locals, *x)),
+            Instr::LocalSet(x) => format!("local.set {}", idx(&self.locals, *x)),
+            Instr::LocalTee(x) => format!("local.tee {}", idx(&self.locals, *x)),
+            Instr::GlobalGet(x) => format!("global.get {}", idx(&self.context.globals, *x)),
+            Instr::GlobalSet(x) => format!("global.set {}", idx(&self.context.globals, *x)),
+
+            Instr::Load(vt, m) => format!("{}.load{}", valtype(vt), memarg(m, natural_align(vt))),
+            Instr::Store(vt, m) => format!("{}.store{}", valtype(vt), memarg(m, natural_align(vt))),
+            Instr::ILoad8(vs, sign, m) => format!("{}.load8_{}{}", ivalsize(vs), valsign(sign), memarg(m, 0)),
+            Instr::ILoad16(vs, sign, m) => format!("{}.load16_{}{}", ivalsize(vs), valsign(sign), memarg(m, 1)),
+            Instr::I64Load32(sign, m) => format!("i64.load32_{}{}", valsign(sign), memarg(m, 2)),
+            Instr::IStore8(vs, m) => format!("{}.store8{}", ivalsize(vs), memarg(m, 0)),
+            Instr::IStore16(vs, m) => format!("{}.store16{}", ivalsize(vs), memarg(m, 1)),
+            Instr::I64Store32(m) => format!("i64.store32{}", memarg(m, 2)),
+            Instr::MemorySize => "memory.size".to_string(),
+            Instr::MemoryGrow => "memory.grow".to_string(),
+
+            Instr::I32Const(n) => format!("i32.const {}", *n as i32),
+            Instr::I64Const(n) => format!("i64.const {}", *n as i64),
+            Instr::F32Const(z) => format!("f32.const {}", f32_literal(*z)),
+            Instr::F64Const(z) => format!("f64.const {}", f64_literal(*z)),
+
+            Instr::IUnOp(vs, op) => format!("{}.{}", ivalsize(vs), match op {
+                IUnOp::Clz => "clz", IUnOp::Ctz => "ctz", IUnOp::Popcnt => "popcnt",
+            }),
+            Instr::FUnOp(vs, op) => format!("{}.{}", fvalsize(vs), match op {
+                FUnOp::Abs => "abs", FUnOp::Neg => "neg", FUnOp::Sqrt => "sqrt", FUnOp::Ceil => "ceil",
+                FUnOp::Floor => "floor", FUnOp::Trunc => "trunc", FUnOp::Nearest => "nearest",
+            }),
+            Instr::IBinOp(vs, op) => format!("{}.{}", ivalsize(vs), match op {
+                IBinOp::Add => "add".to_string(),
+                IBinOp::Sub => "sub".to_string(),
+                IBinOp::Mul => "mul".to_string(),
+                IBinOp::Div(sign) => format!("div_{}", valsign(sign)),
+                IBinOp::Rem(sign) => format!("rem_{}", valsign(sign)),
+                IBinOp::And => "and".to_string(),
+                IBinOp::Or => "or".to_string(),
+                IBinOp::Xor => "xor".to_string(),
+                IBinOp::Shl => "shl".to_string(),
+                IBinOp::Shr(sign) => format!("shr_{}", valsign(sign)),
+                IBinOp::Rotl => "rotl".to_string(),
+                IBinOp::Rotr => "rotr".to_string(),
+            }),
+            Instr::FBinOp(vs, op) => format!("{}.{}", fvalsize(vs), match op {
+                FBinOp::Add => "add", FBinOp::Sub => "sub", FBinOp::Mul => "mul", FBinOp::Div => "div",
+                FBinOp::Min => "min", FBinOp::Max => "max", FBinOp::Copysign => "copysign",
+            }),
+            Instr::ITestOp(vs, ITestOp::Eqz) => format!("{}.eqz", ivalsize(vs)),
+            Instr::IRelOp(vs, op) => format!("{}.{}", ivalsize(vs), match op {
+                IRelOp::Eq => "eq".to_string(),
+                IRelOp::Ne => "ne".to_string(),
+                IRelOp::Lt(sign) => format!("lt_{}", valsign(sign)),
+                IRelOp::Gt(sign) => format!("gt_{}", valsign(sign)),
+                IRelOp::Le(sign) => format!("le_{}", valsign(sign)),
+                IRelOp::Ge(sign) => format!("ge_{}", valsign(sign)),
+            }),
+            Instr::FRelOp(vs, op) => format!("{}.{}", fvalsize(vs), match op {
+                FRelOp::Eq => "eq", FRelOp::Ne => "ne", FRelOp::Lt => "lt",
+                FRelOp::Gt => "gt", FRelOp::Le => "le", FRelOp::Ge => "ge",
+            }),
+
+            Instr::CvtOp(op) => match op {
+                CvtOp::I32WrapFromI64 => "i32.wrap_i64".to_string(),
+                CvtOp::I64ExtendFromI32(sign) => format!("i64.extend_i32_{}", valsign(sign)),
+                CvtOp::ITruncFromF(to, from, sign) => format!("{}.trunc_{}_{}", ivalsize(to), fvalsize(from), valsign(sign)),
+                CvtOp::F32DemoteFromF64 => "f32.demote_f64".to_string(),
+                CvtOp::F64PromoteFromF32 => "f64.promote_f32".to_string(),
+                CvtOp::FConvertFromI(to, from, sign) => format!("{}.convert_{}_{}", fvalsize(to), ivalsize(from), valsign(sign)),
+                CvtOp::IReinterpretFromF(vs) => format!("{}.reinterpret_{}", ivalsize(vs), fvalsize(vs)),
+                CvtOp::FReinterpretFromI(vs) => format!("{}.reinterpret_{}", fvalsize(vs), ivalsize(vs)),
+            },
+
+            // ブロック命令はinstrsとfoldで扱う
+            Instr::Block(..) | Instr::Loop(..) | Instr::If(..) => unreachable!("{:?}", instr),
+
+            // 管理命令は実行時にしか現れない
+            Instr::Trap |
+            Instr::Invoke(_) |
+            Instr::InitElem(..) |
+            Instr::InitData(..) |
+            Instr::Label(..) |
+            Instr::Frame(..) => return Err(EncodeError::AdministrativeInstr(instr.clone()).into()),
+        })
+    }
+}
+
+fn take_operands(nodes: &mut Vec<Folded>, n: usize) -> Vec<Folded> {
+    if n <= nodes.len() && nodes[nodes.len() - n..].iter().all(|node| node.results() == 1) {
+        nodes.split_off(nodes.len() - n)
+    } else {
+        vec![]
+    }
+}
+
+// テキストフォーマットで使えない名前や、重複した名前は捨てる(番号で出力する)
+fn unique_ids(ids: &[Option<Id>]) -> Vec<Option<Id>> {
+    let mut seen = HashSet::new();
+    ids.iter().map(|id| match id {
+        Some(id) if is_valid_id(id) && seen.insert(id.clone()) => Some(id.clone()),
+        _ => None,
+    })
+    .collect()
+}
+
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && id.bytes().all(is_idchar)
+}
+
+fn def_id(ids: &[Option<Id>], i: usize) -> String {
+    match ids.get(i) {
+        Some(Some(id)) => format!(" ${}", id),
+        _ => String::new(),
+    }
+}
+
+fn idx(ids: &[Option<Id>], i: u32) -> String {
+    match ids.get(i as usize) {
+        Some(Some(id)) => format!("${}", id),
+        _ => i.to_string(),
+    }
+}
+
+fn label_def(label: &Option<Id>) -> String {
+    match label {
+        Some(id) => format!(" ${}", id),
+        None => String::new(),
+    }
+}
+
+fn functype(ft: &FuncType) -> String {
+    format!("(func{})", signature(ft, &[]))
+}
+
+// paramsには、localsの識別子があれば付ける
+fn signature(ft: &FuncType, locals: &[Option<Id>]) -> String {
+    let mut s = String::new();
+    for (i, vt) in ft.0.iter().enumerate() {
+        s.push_str(&format!(" (param{} {})", def_id(locals, i), valtype(vt)));
+    }
+    for vt in &ft.1 {
+        s.push_str(&format!(" (result {})", valtype(vt)));
+    }
+    s
+}
+
+fn blocktype(rt: &ResultType) -> String {
+    rt.iter().map(|vt| format!(" (result {})", valtype(vt))).collect()
+}
+
+fn limits(limits: &Limits) -> String {
+    match limits.max {
+        Some(max) => format!("{} {}", limits.min, max),
+        None => format!("{}", limits.min),
+    }
+}
+
+fn tabletype(tt: &TableType) -> String {
+    format!("{} funcref", limits(&tt.limits))
+}
+
+fn globaltype(gt: &GlobalType) -> String {
+    match gt.0 {
+        Mutablity::Const => valtype(&gt.1).to_string(),
+        Mutablity::Var => format!("(mut {})", valtype(&gt.1)),
+    }
+}
+
+fn valtype(vt: &ValType) -> &'static str {
+    match vt {
+        ValType::I32 => "i32",
+        ValType::I64 => "i64",
+        ValType::F32 => "f32",
+        ValType::F64 => "f64",
+    }
+}
+
+fn ivalsize(vs: &ValSize) -> &'static str {
+    match vs { ValSize::V32 => "i32", ValSize::V64 => "i64" }
+}
+
+fn fvalsize(vs: &ValSize) -> &'static str {
+    match vs { ValSize::V32 => "f32", ValSize::V64 => "f64" }
+}
+
+fn valsign(sign: &ValSign) -> &'static str {
+    match sign { ValSign::S => "s", ValSign::U => "u" }
+}
+
+fn natural_align(vt: &ValType) -> u32 {
+    match vt {
+        ValType::I32 | ValType::F32 => 2,
+        ValType::I64 | ValType::F64 => 3,
+    }
+}
+
+// alignは2の指数で持っているので、テキストフォーマットではバイト数に直す
+fn memarg(m: &MemArg, natural: u32) -> String {
+    let mut s = String::new();
+    if m.offset != 0 { s.push_str(&format!(" offset={}", m.offset)); }
+    if m.align != natural { s.push_str(&format!(" align={}", 1u64 << m.align.min(63))); }
+    s
+}
+
+fn f32_literal(z: f32) -> String {
+    if z.is_nan() {
+        let payload = z.to_bits() & 0x007F_FFFF;
+        nan_literal(z.is_sign_negative(), payload as u64, payload == 0x0040_0000)
+    } else if z.is_infinite() {
+        if z < 0.0 { "-inf".to_string() } else { "inf".to_string() }
+    } else {
+        format!("{:?}", z)
+    }
+}
+
+fn f64_literal(z: f64) -> String {
+    if z.is_nan() {
+        let payload = z.to_bits() & 0x000F_FFFF_FFFF_FFFF;
+        nan_literal(z.is_sign_negative(), payload, payload == 0x0008_0000_0000_0000)
+    } else if z.is_infinite() {
+        if z < 0.0 { "-inf".to_string() } else { "inf".to_string() }
+    } else {
+        format!("{:?}", z)
+    }
+}
+
+fn nan_literal(negative: bool, payload: u64, canonical: bool) -> String {
+    let sign = if negative { "-" } else { "" };
+    if canonical {
+        format!("{}nan", sign)
+    } else {
+        format!("{}nan:{:#x}", sign, payload)
+    }
+}
+
+// 印字できないバイトと'"'、'\'、UTF-8として正しくないバイトはエスケープする
+fn string(bytes: &[u8]) -> String {
+    let mut s = "\"".to_string();
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '"' => s.push_str("\\\""),
+                '\\' => s.push_str("\\\\"),
+                '\t' => s.push_str("\\t"),
+                '\n' => s.push_str("\\n"),
+                '\r' => s.push_str("\\r"),
+                c if (c as u32) < 0x20 || c as u32 == 0x7F => s.push_str(&format!("\\{:02x}", c as u32)),
+                c => s.push(c),
+            }
+        }
+        for b in chunk.invalid() {
+            s.push_str(&format!("\\{:02x}", b));
+        }
+    }
+    s.push('"');
+    s
+}
+
+#[test]
+fn test_module_to_wat() {
+    let bytes = [
+        0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00,
+        // type section: (func (param i32) (result i32))
+        0x01, 0x06, 0x01, 0x60, 0x01, 0x7F, 0x01, 0x7F,
+        // func section
+        0x03, 0x02, 0x01, 0x00,
+        // export section: "f" func 0
+        0x07, 0x05, 0x01, 0x01, 0x66, 0x00, 0x00,
+        // code section: (local i32) block (result i32) local.get 0 i32.const -1 i32.add end local.set 1 local.get 1
+        0x0A, 0x12, 0x01, 0x10, 0x01, 0x01, 0x7F,
+            0x02, 0x7F, 0x20, 0x00, 0x41, 0x7F, 0x6A, 0x0B,
+            0x21, 0x01, 0x20, 0x01, 0x0B,
+        // name section: func 0 "inc", locals "x" "y"
+        0x00, 0x18, 0x04, 0x6E, 0x61, 0x6D, 0x65,
+            0x01, 0x06, 0x01, 0x00, 0x03, 0x69, 0x6E, 0x63,
+            0x02, 0x09, 0x01, 0x00, 0x02, 0x00, 0x01, 0x78, 0x01, 0x01, 0x79,
+    ];
+    let mut decoder = ::decoder::Decoder::new(&bytes[..]);
+    let module = decoder.decode().unwrap();
+    let mut options = WatOptions { folded: false, context: decoder.context, func_contexts: decoder.func_contexts };
+
+    let wat = module_to_wat(&module, &options).unwrap();
+    assert_eq!(wat, "\
+(module
+  (type (func (param i32) (result i32)))
+  (func $inc (type 0) (param $x i32) (result i32)
+    (local $y i32)
+    block (result i32)
+      local.get $x
+      i32.const -1
+      i32.add
+    end
+    local.set $y
+    local.get $y
+  )
+  (export \"f\" (func $inc))
+)
+");
+
+    // Parserで読み戻すと同じ関数になる
+    let mut parser = Parser::from_text(&wat);
+    parser.parse().unwrap();
+    assert_eq!(parser.module.types, module.types);
+    assert_eq!(parser.module.funcs, module.funcs);
+
+    options.folded = true;
+    let wat = module_to_wat(&module, &options).unwrap();
+    assert_eq!(wat, "\
+(module
+  (type (func (param i32) (result i32)))
+  (func $inc (type 0) (param $x i32) (result i32)
+    (local $y i32)
+    (local.set $y
+      (block (result i32)
+        (i32.add (local.get $x) (i32.const -1))
+      )
+    )
+    (local.get $y)
+  )
+  (export \"f\" (func $inc))
+)
+");
+}
+
+#[test]
+fn test_module_to_wat_block_in_const_expr() {
+    // 定数式にブロックがあっても、デコードできたモジュールは出力できる
+    let bytes = [
+        0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00,
+        // global section: (global i32 block end)
+        0x06, 0x07, 0x01, 0x7F, 0x00, 0x02, 0x40, 0x0B, 0x0B,
+    ];
+    let module = ::decoder::Decoder::new(&bytes[..]).decode().unwrap();
+    let mut options = WatOptions { folded: false, context: Context::default(), func_contexts: vec![] };
+    assert_eq!(module_to_wat(&module, &options).unwrap(), "(module\n  (global i32 block end)\n)\n");
+    options.folded = true;
+    assert_eq!(module_to_wat(&module, &options).unwrap(), "(module\n  (global i32 (block ))\n)\n");
+}
+
+#[test]
+fn test_module_to_wat_administrative_instr() {
+    // 管理命令はテキストにできないので、パニックせずにエラーを返す
+    let mut module = Module::default();
+    module.types.push((vec![], vec![]));
+    module.funcs.push(Func(0, vec![], FuncBody::Expr(Expr(vec![Instr::Nop, Instr::Trap]))));
+    module.globals.push(Global(GlobalType::default(), Expr(vec![Instr::Trap])));
+    for folded in &[false, true] {
+        let options = WatOptions { folded: *folded, ..WatOptions::default() };
+        assert_eq!(module_to_wat(&module, &options), Err(Error::Encode(EncodeError::AdministrativeInstr(Instr::Trap))));
+    }
+
+    module.globals.clear();
+    for folded in &[false, true] {
+        let options = WatOptions { folded: *folded, ..WatOptions::default() };
+        assert_eq!(module_to_wat(&module, &options), Err(Error::Encode(EncodeError::AdministrativeInstr(Instr::Trap))));
+    }
+}
+
+#[test]
+fn test_module_to_wat_cvt_names() {
+    // 変換命令は仕様の名前(i32.wrap_i64など)で出力する
+    let instrs = vec![
+        Instr::I64Const(1), Instr::CvtOp(CvtOp::I32WrapFromI64),
+        Instr::CvtOp(CvtOp::FConvertFromI(ValSize::V64, ValSize::V32, ValSign::S)),
+        Instr::CvtOp(CvtOp::ITruncFromF(ValSize::V64, ValSize::V64, ValSign::U)),
+        Instr::CvtOp(CvtOp::FReinterpretFromI(ValSize::V64)),
+        Instr::CvtOp(CvtOp::IReinterpretFromF(ValSize::V64)),
+        Instr::CvtOp(CvtOp::I64ExtendFromI32(ValSign::S)), Instr::Drop,
+    ];
+    let mut module = Module::default();
+    module.types.push((vec![], vec![]));
+    module.funcs.push(Func(0, vec![], FuncBody::Expr(Expr(instrs.clone()))));
+
+    let wat = module_to_wat(&module, &WatOptions::default()).unwrap();
+    for name in &["i32.wrap_i64", "f64.convert_i32_s", "i64.trunc_f64_u", "f64.reinterpret_i64", "i64.reinterpret_f64", "i64.extend_i32_s"] {
+        assert!(wat.contains(&format!("    {}\n", name)), "{}\n{}", name, wat);
+    }
+
+    let mut parser = Parser::from_text(&wat);
+    parser.parse().unwrap();
+    assert_eq!(parser.module.funcs[0].2, FuncBody::Expr(Expr(instrs)));
+}
@@ -0,0 +1,227 @@
+use std::io::Read;
+
+use instr::*;
+use parser::*;
+use decoder::*;
+use error::Error;
+
+// バイナリのセクション一覧と、各セクションの中身をoutに書き出す
+// 途中でデコードに失敗しても、それまでに読めたセクションは書き出してからエラーを返す
+// 関数本体はデコードしない
+pub fn objdump<R: Read>(reader: R, out: &mut String) -> Result<(), Error> {
+    let mut sections = vec![];
+    let res = Decoder::new_lazy(reader).decode_sections(&[], |section| {
+        sections.push(section);
+        Ok(())
+    });
+
+    out.push_str("Sections:\n");
+    for section in &sections {
+        out.push_str(&format!("{:>9} start={:#010x} end={:#010x} (size={:#010x})",
+            format!("{:?}", section.id), section.range.start, section.range.end, section.range.len()));
+        match &section.payload {
+            Payload::Custom(custom) => out.push_str(&format!(" {:?}", custom.name)),
+            Payload::Start(start) => out.push_str(&format!(" start: {}", start.0)),
+            payload => if let Some(count) = count(payload) {
+                out.push_str(&format!(" count: {}", count));
+            },
+        }
+        out.push('\n');
+    }
+
+    out.push_str("\nSection Details:\n");
+    let mut imported = [0; 4];
+    for section in &sections {
+        match &section.payload {
+            Payload::Custom(custom) => {
+                out.push_str(&format!("Custom:\n - name: {:?} size={}\n", custom.name, custom.data.len()));
+            },
+            Payload::Type(types) => {
+                out.push_str(&format!("Type[{}]:\n", types.len()));
+                for (i, ft) in types.iter().enumerate() {
+                    out.push_str(&format!(" - type[{}] {}\n", i, functype(ft)));
+                }
+            },
+            Payload::Import(imports) => {
+                out.push_str(&format!("Import[{}]:\n", imports.len()));
+                for import in imports {
+                    let desc = match &import.2 {
+                        ImportDesc::Func(typeidx) => {
+                            imported[0] += 1;
+                            format!("func[{}] sig={}", imported[0] - 1, typeidx)
+                        },
+                        ImportDesc::Table(tt) => {
+                            imported[1] += 1;
+                            format!("table[{}] funcref {}", imported[1] - 1, limits(&tt.limits))
+                        },
+                        ImportDesc::Mem(mt) => {
+                            imported[2] += 1;
+                            format!("memory[{}] pages: {}", imported[2] - 1, limits(&mt.0))
+                        },
+                        ImportDesc::Global(gt) => {
+                            imported[3] += 1;
+                            format!("global[{}] {}", imported[3] - 1, globaltype(gt))
+                        },
+                    };
+                    out.push_str(&format!(" - {} <- {}.{}\n", desc, import.0, import.1));
+                }
+            },
+            Payload::Func(typeindices) => {
+                out.push_str(&format!("Func[{}]:\n", typeindices.len()));
+                for (i, typeidx) in typeindices.iter().enumerate() {
+                    out.push_str(&format!(" - func[{}] sig={}\n", imported[0] + i, typeidx));
+                }
+            },
+            Payload::Table(tables) => {
+                out.push_str(&format!("Table[{}]:\n", tables.len()));
+                for (i, table) in tables.iter().enumerate() {
+                    out.push_str(&format!(" - table[{}] funcref {}\n", imported[1] + i, limits(&table.0.limits)));
+                }
+            },
+            Payload::Memory(mems) => {
+                out.push_str(&format!("Memory[{}]:\n", mems.len()));
+                for (i, mem) in mems.iter().enumerate() {
+                    out.push_str(&format!(" - memory[{}] pages: {}\n", imported[2] + i, limits(&(mem.0).0)));
+                }
+            },
+            Payload::Global(globals) => {
+                out.push_str(&format!("Global[{}]:\n", globals.len()));
+                for (i, global) in globals.iter().enumerate() {
+                    out.push_str(&format!(" - global[{}] {} - init {:?}\n", imported[3] + i, globaltype(&global.0), (global.1).0));
+                }
+            },
+            Payload::Export(exports) => {
+                out.push_str(&format!("Export[{}]:\n", exports.len()));
+                for export in exports {
+                    let desc = match &export.1 {
+                        ExportDesc::Func(x) => format!("func[{}]", x),
+                        ExportDesc::Table(x) => format!("table[{}]", x),
+                        ExportDesc::Mem(x) => format!("memory[{}]", x),
+                        ExportDesc::Global(x) => format!("global[{}]", x),
+                    };
+                    out.push_str(&format!(" - {} -> {:?}\n", desc, export.0));
+                }
+            },
+            Payload::Start(start) => {
+                out.push_str(&format!("Start:\n - start function: {}\n", start.0));
+            },
+            Payload::Elem(elems) => {
+                out.push_str(&format!("Elem[{}]:\n", elems.len()));
+                for (i, elem) in elems.iter().enumerate() {
+                    out.push_str(&format!(" - segment[{}] table={} count={} - init {:?}\n", i, elem.table, elem.init.len(), elem.init));
+                }
+            },
+            Payload::Code(codes) => {
+                out.push_str(&format!("Code[{}]:\n", codes.len()));
+                for (i, code) in codes.iter().enumerate() {
+                    out.push_str(&format!(" - func[{}] size={} locals={} start={:#010x}\n",
+                        imported[0] + i, code.range.len(), code.locals.len(), code.range.start));
+                }
+            },
+            Payload::Data(data) => {
+                out.push_str(&format!("Data[{}]:\n", data.len()));
+                for (i, d) in data.iter().enumerate() {
+                    out.push_str(&format!(" - segment[{}] memory={} size={}\n", i, d.data, d.init.len()));
+                }
+            },
+            Payload::Skipped => {},
+        }
+    }
+
+    res
+}
+
+fn count(payload: &Payload) -> Option<usize> {
+    match payload {
+        Payload::Type(v) => Some(v.len()),
+        Payload::Import(v) => Some(v.len()),
+        Payload::Func(v) => Some(v.len()),
+        Payload::Table(v) => Some(v.len()),
+        Payload::Memory(v) => Some(v.len()),
+        Payload::Global(v) => Some(v.len()),
+        Payload::Export(v) => Some(v.len()),
+        Payload::Elem(v) => Some(v.len()),
+        Payload::Code(v) => Some(v.len()),
+        Payload::Data(v) => Some(v.len()),
+        _ => None,
+    }
+}
+
+fn functype(ft: &FuncType) -> String {
+    format!("({}) -> ({})", valtypes(&ft.0), valtypes(&ft.1))
+}
+
+fn valtypes(vts: &[ValType]) -> String {
+    vts.iter().map(|vt| format!("{:?}", vt).to_lowercase()).collect::<Vec<_>>().join(", ")
+}
+
+fn limits(limits: &Limits) -> String {
+    match limits.max {
+        Some(max) => format!("initial={} max={}", limits.min, max),
+        None => format!("initial={}", limits.min),
+    }
+}
+
+fn globaltype(gt: &GlobalType) -> String {
+    let mutable = match gt.0 { Mutablity::Const => 0, Mutablity::Var => 1 };
+    format!("{} mutable={}", format!("{:?}", gt.1).to_lowercase(), mutable)
+}
+
+#[test]
+fn test_objdump() {
+    let bytes = [
+        0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00,
+        // type section: (func (param i32) (result i32))
+        0x01, 0x06, 0x01, 0x60, 0x01, 0x7F, 0x01, 0x7F,
+        // import section: "m" "g" (global i32)
+        0x02, 0x08, 0x01, 0x01, 0x6D, 0x01, 0x67, 0x03, 0x7F, 0x00,
+        // func section
+        0x03, 0x02, 0x01, 0x00,
+        // memory section: (memory 1 2)
+        0x05, 0x04, 0x01, 0x01, 0x01, 0x02,
+        // export section: "f" func 0
+        0x07, 0x05, 0x01, 0x01, 0x66, 0x00, 0x00,
+        // start section
+        0x08, 0x01, 0x00,
+        // code section: (local i64) local.get 0
+        0x0A, 0x08, 0x01, 0x06, 0x01, 0x01, 0x7E, 0x20, 0x00, 0x0B,
+        // data section: memory 0 (i32.const 8) "hi"
+        0x0B, 0x08, 0x01, 0x00, 0x41, 0x08, 0x0B, 0x02, 0x68, 0x69,
+    ];
+    let mut out = String::new();
+    objdump(&bytes[..], &mut out).unwrap();
+    assert_eq!(out, "\
+Sections:
+     Type start=0x0000000a end=0x00000010 (size=0x00000006) count: 1
+   Import start=0x00000012 end=0x0000001a (size=0x00000008) count: 1
+     Func start=0x0000001c end=0x0000001e (size=0x00000002) count: 1
+   Memory start=0x00000020 end=0x00000024 (size=0x00000004) count: 1
+   Export start=0x00000026 end=0x0000002b (size=0x00000005) count: 1
+    Start start=0x0000002d end=0x0000002e (size=0x00000001) start: 0
+     Code start=0x00000030 end=0x00000038 (size=0x00000008) count: 1
+     Data start=0x0000003a end=0x00000042 (size=0x00000008) count: 1
+
+Section Details:
+Type[1]:
+ - type[0] (i32) -> (i32)
+Import[1]:
+ - global[0] i32 mutable=0 <- m.g
+Func[1]:
+ - func[0] sig=0
+Memory[1]:
+ - memory[0] pages: initial=1 max=2
+Export[1]:
+ - func[0] -> \"f\"
+Start:
+ - start function: 0
+Code[1]:
+ - func[0] size=6 locals=1 start=0x00000032
+Data[1]:
+ - segment[0] memory=0 size=2
+");
+
+    // 壊れたセクションの手前までは書き出す
+    let mut out = String::new();
+    assert!(objdump(&bytes[..bytes.len() - 1], &mut out).is_err());
+    assert!(out.contains(" - func[0] size=6 locals=1"));
+}
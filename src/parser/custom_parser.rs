@@ -0,0 +1,129 @@
+use super::*;
+use mod2wasm::SectionId;
+
+impl<R> Parser<R> where R: Read + Seek {
+    // (@custom "name" place? datastring*)
+    // placeは(before first)、(after last)、(before sec)、(after sec)のいずれかで、省略時は(after last)
+    pub(super) fn parse_customs(&mut self) -> Result<(), ParseError> {
+        for annotation in &self.annotations {
+            if let tk!(TokenKind::Annotation(name, tokens)) = annotation {
+                if name == "custom" {
+                    let custom = parse_custom(annotation, tokens)?;
+                    self.module.customs.push(custom);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // markより後に読んだ(@name "name")の名前
+    pub(super) fn parse_name_annotation(&self, mark: usize) -> Result<Option<Name>, ParseError> {
+        let mut res = None;
+        for annotation in &self.annotations[mark..] {
+            if let tk!(TokenKind::Annotation(name, tokens)) = annotation {
+                if name != "name" { continue; }
+                match &tokens[..] {
+                    [token @ tk!(TokenKind::String(s))] => res = Some(annotation_name(token, s)?),
+                    _ => return Err(ParseError::InvalidMessage(annotation.clone(), "@name must have one string".to_string())),
+                }
+            }
+        }
+        Ok(res)
+    }
+}
+
+fn annotation_name(token: &Token, s: &[u8]) -> Result<Name, ParseError> {
+    String::from_utf8(s.to_vec())
+        .map_err(|_| ParseError::InvalidMessage(token.clone(), "malformed UTF-8 encoding".to_string()))
+}
+
+fn parse_custom(annotation: &Token, tokens: &[Token]) -> Result<Custom, ParseError> {
+    let mut tokens = tokens.iter().peekable();
+
+    let name = match tokens.next() {
+        Some(token @ tk!(TokenKind::String(s))) => annotation_name(token, s)?,
+        Some(token) => return Err(ParseError::NotMatch(token.clone(), TokenKind::String(vec![]))),
+        None => return Err(ParseError::InvalidMessage(annotation.clone(), "custom section must have a name".to_string())),
+    };
+
+    let custom = if let Some(tk!(TokenKind::LeftParen)) = tokens.peek() {
+        tokens.next();
+        let before = match tokens.next() {
+            Some(tk!(TokenKind::Reserved(s))) if s == "before" => true,
+            Some(tk!(TokenKind::Reserved(s))) if s == "after" => false,
+            _ => return Err(ParseError::InvalidMessage(annotation.clone(), "custom section place must be before or after".to_string())),
+        };
+        let id = match tokens.next() {
+            Some(token) => custom_place(token, before)?,
+            None => return Err(ParseError::InvalidMessage(annotation.clone(), "invalid custom section place".to_string())),
+        };
+        match tokens.next() {
+            Some(tk!(TokenKind::RightParen)) => {},
+            Some(token) => return Err(ParseError::NotMatch(token.clone(), TokenKind::RightParen)),
+            None => return Err(ParseError::InvalidMessage(annotation.clone(), "invalid custom section place".to_string())),
+        }
+        if before { Custom::before(name, vec![], id) } else { Custom::after(name, vec![], id) }
+    } else {
+        Custom::after(name, vec![], SectionId::Data)
+    };
+
+    let mut data = vec![];
+    for token in tokens {
+        match token {
+            tk!(TokenKind::String(s)) => data.extend(s),
+            _ => return Err(ParseError::NotMatch(token.clone(), TokenKind::String(vec![]))),
+        }
+    }
+
+    Ok(Custom { data, ..custom })
+}
+
+// 基準になるセクション
+fn custom_place(token: &Token, before: bool) -> Result<SectionId, ParseError> {
+    let id = match token {
+        tk!(TokenKind::Reserved(s)) if s == "first" && before => SectionId::Type,
+        tk!(TokenKind::Reserved(s)) if s == "last" && !before => SectionId::Data,
+        tk!(TokenKind::Reserved(s)) if s == "code" => SectionId::Code,
+        kw!(Keyword::Type) => SectionId::Type,
+        kw!(Keyword::Import) => SectionId::Import,
+        kw!(Keyword::Func) => SectionId::Func,
+        kw!(Keyword::Table) => SectionId::Table,
+        kw!(Keyword::Memory) => SectionId::Memory,
+        kw!(Keyword::Global) => SectionId::Global,
+        kw!(Keyword::Export) => SectionId::Export,
+        kw!(Keyword::Start) => SectionId::Start,
+        kw!(Keyword::Elem) => SectionId::Elem,
+        kw!(Keyword::Data) => SectionId::Data,
+        _ => return Err(ParseError::InvalidMessage(token.clone(), "invalid custom section place".to_string())),
+    };
+    Ok(id)
+}
+
+#[test]
+fn test_parse_annotations() {
+    use instr::*;
+
+    let mut parser = Parser::from_text(r#"(module (@unknown (nested "(" x) y)
+      (func $f (@name "first function") (@metadata.code.branch_hint "\01")
+        (param $x i32) (@foo) (result i32)
+        local.get $x (@bar) if (result i32) (@baz 1 2) i32.const 1 else i32.const 2 end)
+      (@custom "c" (after func) "data")
+      (func (@name "second")))"#);
+    parser.parse().unwrap();
+
+    assert_eq!(parser.contexts[0].funcs, vec![Some("first function".to_string()), Some("second".to_string())]);
+    assert_eq!(parser.module.customs, vec![Custom::after("c".into(), b"data".to_vec(), SectionId::Func)]);
+
+    // 注釈は命令や型の解析に影響しない
+    assert_eq!(parser.module.types, vec![
+        (vec![ValType::I32], vec![ValType::I32]),
+        (vec![], vec![]),
+    ]);
+    assert_eq!(parser.module.funcs, vec![
+        Func(0, vec![ValType::I32], FuncBody::Expr(Expr(vec![
+            Instr::LocalGet(0),
+            Instr::If(vec![ValType::I32], Expr(vec![Instr::I32Const(1)]), Expr(vec![Instr::I32Const(2)])),
+        ]))),
+        Func(1, vec![], FuncBody::Expr(Expr(vec![]))),
+    ]);
+}
@@ -12,7 +12,7 @@ impl<R> Parser<R> where R: Read + Seek {
         let offset = self.parse_offset()?;
 
         // data string
-        let datastring = self.parse_data_string()?;
+        let datastring = self.parse_data_strings()?;
 
         let data = Data {
             data: memidx, 
@@ -22,7 +22,16 @@ impl<R> Parser<R> where R: Read + Seek {
 
         self.module.data.push(data);
 
-        Ok(())
+        self.match_rparen()
+    }
+
+    // 続く文字列をつなげて1つのデータにする
+    pub(super) fn parse_data_strings(&mut self) -> Result<DataString, ParseError> {
+        let mut datastring = vec![];
+        while let tk!(TokenKind::String(_)) = &self.lookahead {
+            datastring.extend(self.parse_data_string()?);
+        }
+        Ok(datastring)
     }
 
 }
@@ -10,10 +10,134 @@ pub enum ParseError {
     NumCast(Token),
     CantResolveId(Token),
     InvalidTypeuseDef(Token, FuncType, FuncType),
+    UnknownType(Token, TypeIndex),
     InvalidMessage(Token, String),
     LastItem,    
 }
 
+impl ParseError {
+    // エラーの起きた位置
+    pub fn loc(&self) -> Loc {
+        match self {
+            ParseError::Lex(e) => e.loc,
+            ParseError::NotMatch(token, _) | ParseError::Invalid(token) | ParseError::NumCast(token)
+            | ParseError::CantResolveId(token) | ParseError::InvalidTypeuseDef(token, _, _)
+            | ParseError::UnknownType(token, _) | ParseError::InvalidMessage(token, _) => token.loc,
+            ParseError::LastItem => Loc::default(),
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.loc(), self.message())
+    }
+}
+
+impl ParseError {
+    // 位置を除いたメッセージ
+    pub fn message(&self) -> String {
+        match self {
+            ParseError::Lex(e) => e.value.to_string(),
+            ParseError::NotMatch(token, expected) => format!("expected {}, found {}", expected, token.value),
+            ParseError::Invalid(token) => format!("unexpected {}", token.value),
+            ParseError::NumCast(token) => format!("{} is out of range", token.value),
+            ParseError::CantResolveId(token) => format!("unknown identifier or index {}", token.value),
+            ParseError::InvalidTypeuseDef(_, def, found) =>
+                format!("type use does not match its definition: expected {}, found {}", functype(def), functype(found)),
+            ParseError::UnknownType(_, typeidx) => format!("unknown type {}", typeidx),
+            ParseError::InvalidMessage(_, mes) => mes.clone(),
+            ParseError::LastItem => "unexpected end of input".to_string(),
+        }
+    }
+}
+
+fn functype(ft: &FuncType) -> String {
+    let valtypes = |vts: &[ValType]| vts.iter().map(|vt| format!("{:?}", vt).to_lowercase()).collect::<Vec<_>>().join(" ");
+    format!("[{}] -> [{}]", valtypes(&ft.0), valtypes(&ft.1))
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError::Lex(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// エラーから回復して読み進めたときに集める、1つ1つのエラー
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub loc: Loc,
+    pub error: ParseError,
+}
+
+impl From<ParseError> for Diagnostic {
+    fn from(error: ParseError) -> Self {
+        Diagnostic { loc: error.loc(), error }
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.loc, self.error.message())
+    }
+}
+
+impl std::error::Error for Diagnostic {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+impl Diagnostic {
+    // ファイル名と位置、ソースの該当行、トークンの下に'^'を付けて表示する
+    //
+    // error: expected `)`, found `func`
+    //  --> a.wat:3:5
+    //   |
+    // 3 |   (func
+    //   |    ^^^^
+    pub fn render(&self, file_name: &str, source: &str, color: bool) -> String {
+        let (red, blue, bold, reset) = if color { (RED, BLUE, BOLD, RESET) } else { ("", "", "", "") };
+        let Loc(line, col) = self.loc;
+        let gutter = " ".repeat(line.to_string().len());
+
+        let mut out = format!("{}error{}{}: {}{}\n", red, reset, bold, self.error.message(), reset);
+        out += &format!("{}{}-->{} {}:{}:{}\n", gutter, blue, reset, file_name, line, col);
+
+        if let Some(text) = source.lines().nth(line.wrapping_sub(1)) {
+            let chars = text.chars().collect::<Vec<_>>();
+            let start = col.saturating_sub(1).min(chars.len());
+            // タブはそのまま残して、'^'の位置をそろえる
+            let indent = chars[..start].iter().map(|c| if *c == '\t' { '\t' } else { ' ' }).collect::<String>();
+            let width = token_width(&chars[start..]);
+
+            out += &format!("{} {}|{}\n", gutter, blue, reset);
+            out += &format!("{}{} |{} {}\n", blue, line, reset, text);
+            out += &format!("{} {}|{} {}{}{}{}\n", gutter, blue, reset, indent, red, "^".repeat(width), reset);
+        }
+        out
+    }
+}
+
+// 位置から始まるトークンの桁数(少なくとも1)
+fn token_width(chars: &[char]) -> usize {
+    let is_delimiter = |c: &char| c.is_whitespace() || matches!(c, '(' | ')' | '"' | ';');
+    let width = match chars.first() {
+        Some('(') | Some(')') | None => 1,
+        Some('"') => chars.iter().skip(1).position(|c| *c == '"').map_or(chars.len(), |i| i + 2),
+        Some(_) => chars.iter().position(is_delimiter).unwrap_or(chars.len()),
+    };
+    width.max(1)
+}
+
 use lexer::LexError;
 impl From<LexError> for ParseError {
     fn from(e: LexError) -> Self { ParseError::Lex(e) }
@@ -25,3 +149,36 @@ impl From<TryFromIntError> for ParseError {
         ParseError::NumCast(Token::empty(Loc::default()))
     }
 }
+
+#[test]
+fn test_diagnostic_display() {
+    let source = "(module\n  (func $f\n\t(param i32) (result i32) local.get 0\n    (start $f)))";
+    let mut parser = Parser::from_text(source);
+    let diagnostics = parser.parse_with_diagnostics().unwrap_err();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].to_string(), "4:5: expected `)`, found `(`");
+    assert_eq!(diagnostics[0].render("a.wat", source, false), "\
+error: expected `)`, found `(`
+ --> a.wat:4:5
+  |
+4 |     (start $f)))
+  |     ^
+");
+    assert!(diagnostics[0].render("a.wat", source, true).contains("\x1b[1;31merror\x1b[0m"));
+
+    let source = "(module\n\t(memory 1)\n\t(type (func (param \"i32\"))))";
+    let mut parser = Parser::from_text(source);
+    let e = parser.parse().unwrap_err();
+    assert_eq!(e.to_string(), "3:21: expected `)`, found string \"i32\"");
+    let rendered = Diagnostic::from(e).render("b.wat", source, false);
+    assert!(rendered.ends_with("3 | \t(type (func (param \"i32\"))))\n  | \t                   ^^^^^\n"), "{}", rendered);
+
+    let mut parser = Parser::from_text("(module (func $f (export \"f\")) (export \"f\" (func $f) ;; x\n)");
+    let e = parser.parse().unwrap_err();
+    assert_eq!(e.message(), "expected `)`, found end of file");
+
+    let mut parser = Parser::from_text("(module (memory 1 ,))");
+    let e = parser.parse().unwrap_err();
+    assert_eq!(e.to_string(), "1:19: invalid character `,`");
+    assert!(std::error::Error::source(&e).is_some());
+}
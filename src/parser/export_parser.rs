@@ -20,6 +20,22 @@ impl<R> Parser<R> where R: Read + Seek {
         Ok(())
     }
 
+    // 省略形の(export "name")*を、idxのエクスポートとして加える
+    pub(super) fn parse_inline_exports(&mut self, desc: ExportDesc) -> Result<(), ParseError> {
+        while self.is_lparen()? {
+            if let kw!(Keyword::Export) = self.peek()? {
+                self.consume()?;
+                self.consume()?;
+                let export_name = self.parse_name()?;
+                self.module.exports.push(Export(export_name, desc.clone()));
+                self.match_rparen()?;
+            } else {
+                break;
+            }
+        }
+        Ok(())
+    }
+
     fn parse_export_desc(&mut self) -> Result<ExportDesc, ParseError> {
         match self.lookahead {
             kw!(Keyword::Func) => self.parse_export_desc_func(),
@@ -74,3 +90,28 @@ impl<R> Parser<R> where R: Read + Seek {
         Ok(ExportDesc::Global(globalidx))
     }
 }
+
+#[test]
+fn test_parse_inline_exports() {
+    let mut parser = Parser::from_text(r#"(module
+      (import "m" "g" (func))
+      (table (export "tab") 1 funcref)
+      (global $c (export "c") (mut i32) (i32.const 0))
+      (memory (export "m1") (export "m2") 1)
+      (func $f (export "f") (export "f2")))"#);
+    parser.parse().unwrap();
+
+    let export = |name: &str, desc| Export(name.to_string(), desc);
+    assert_eq!(parser.module.exports, vec![
+        export("tab", ExportDesc::Table(0)),
+        export("c", ExportDesc::Global(0)),
+        export("m1", ExportDesc::Mem(0)),
+        export("m2", ExportDesc::Mem(0)),
+        export("f", ExportDesc::Func(1)),
+        export("f2", ExportDesc::Func(1)),
+    ]);
+    // 定義そのものは残る
+    assert_eq!(parser.module.globals, vec![Global(GlobalType(Mutablity::Var, ValType::I32), Expr(vec![Instr::I32Const(0)]))]);
+    assert_eq!(parser.module.mems, vec![Memory(MemType(Limits { min: 1, max: None }))]);
+    assert_eq!(parser.module.funcs.len(), 1);
+}
@@ -33,24 +33,40 @@ macro_rules! instr_label {
     }};
 }
 
+// 値の範囲と丸めは$toで型に合わせる
 macro_rules! instr_const {
-    ($this:ident, $pat:pat, $n:ident, $v:ident, $instr:ident, $tp:ident, $err:expr) => {{
+    ($this:ident, $v:ident, $instr:ident, $to:ident, $err:expr) => {{
         $this.consume()?;
-        match $this.lookahead {
-            nm!($pat) => {
-                $v.push(Instr::$instr($n as $tp));
-                $this.consume()?;
+        match &$this.lookahead {
+            nm!(n) => match n.$to() {
+                Some(n) => {
+                    $v.push(Instr::$instr(n));
+                    $this.consume()?;
+                },
+                None => return Err(ParseError::NumCast($this.lookahead.clone())),
             },
             _ => return Err($this.err2($err)),
         }
     }};
 }
 
+// $naturalは自然なアライメント(2の指数)
 macro_rules! instr_memarg {
-    ($this: ident, $v:ident, $align:expr) => {{
+    ($this: ident, $v:ident, $natural:expr) => {{
+        let mut instr = match &$this.lookahead {
+            instr!(instr) => instr.clone(),
+            _ => return Err($this.err()),
+        };
         $this.consume()?;
-        let memarg = MemArg { align: $align, offset: 0 };
-        $v.push(Instr::Load(ValType::I32, memarg));
+
+        let memarg = $this.parse_memarg($natural)?;
+        match &mut instr {
+            Instr::Load(_, m) | Instr::Store(_, m) |
+            Instr::ILoad8(_, _, m) | Instr::ILoad16(_, _, m) | Instr::I64Load32(_, m) |
+            Instr::IStore8(_, m) | Instr::IStore16(_, m) | Instr::I64Store32(m) => *m = memarg,
+            _ => {},
+        }
+        $v.push(instr);
     }};
 }
 
@@ -63,10 +79,8 @@ macro_rules! instr_one_block {
         parse_optional_label_id!($this, new_label_context.labels);
         $this.contexts.push(new_label_context);
 
-        $this.match_lparen()?;
-
         // resulttype
-        let vt = $this.parse_result()?;
+        let rt = $this.parse_blocktype()?;
 
         // expr
         let expr = $this.parse_expr()?;
@@ -79,7 +93,7 @@ macro_rules! instr_one_block {
         p!($this.contexts.last());
         $this.contexts.pop();
 
-        $v.push(Instr::$instr(vec![vt], expr));
+        $v.push(Instr::$instr(rt, expr));
     }};
 }
 
@@ -90,63 +104,200 @@ impl<R> Parser<R> where R: Read + Seek {
 
         loop {
             match &self.lookahead {
-                // Control Instructions
-                instr!(Instr::Block(_, _)) => instr_one_block!(self, instrs, Block),
-                instr!(Instr::Loop(_, _)) => instr_one_block!(self, instrs, Loop),
-                instr!(Instr::If(_, _, _)) => self.parse_if(&mut instrs)?,
-                instr!(Instr::Br(_)) => instr_label!(self, instrs, BrIf),
-                instr!(Instr::BrIf(_)) => instr_label!(self, instrs, BrIf),
-                instr!(Instr::BrTable(_, _)) => self.parse_br_table(&mut instrs)?,
-                instr!(Instr::Call(_)) => instr_func!(self, instrs, Call),
-                instr!(Instr::CallIndirect(_)) => self.parse_call_indirect(&mut instrs)?,
-
-                // Variable Instructions
-                instr!(Instr::LocalGet(_)) => instr_local!(self, instrs, LocalGet),
-                instr!(Instr::LocalSet(_)) => instr_local!(self, instrs, LocalSet),
-                instr!(Instr::LocalTee(_)) => instr_local!(self, instrs, LocalTee),
-                instr!(Instr::GlobalGet(_)) => instr_global!(self, instrs, GlobalGet),
-                instr!(Instr::GlobalSet(_)) => instr_global!(self, instrs, GlobalSet),
-
-                // Memory Instructions
-                instr!(Instr::ILoad8(_, _, _)) => instr_memarg!(self, instrs, 0),
-                instr!(Instr::IStore8(_, _)) => instr_memarg!(self, instrs, 0),
-
-                instr!(Instr::ILoad16(_, _, _)) => instr_memarg!(self, instrs, 1),
-                instr!(Instr::IStore16(_, _)) => instr_memarg!(self, instrs, 1),
-
-                instr!(Instr::Load(ValType::I32, _)) => instr_memarg!(self, instrs, 2),
-                instr!(Instr::Load(ValType::F32, _)) => instr_memarg!(self, instrs, 2),
-                instr!(Instr::I64Load32(_, _)) => instr_memarg!(self, instrs, 2),
-                instr!(Instr::Store(ValType::I32, _)) => instr_memarg!(self, instrs, 2),
-                instr!(Instr::Store(ValType::F32, _)) => instr_memarg!(self, instrs, 2),
-                instr!(Instr::I64Store32(_)) => instr_memarg!(self, instrs, 2),
-
-                instr!(Instr::Load(ValType::I64, _)) => instr_memarg!(self, instrs, 3),
-                instr!(Instr::Load(ValType::F64, _)) => instr_memarg!(self, instrs, 3),
-                instr!(Instr::Store(ValType::I64, _)) => instr_memarg!(self, instrs, 3),
-                instr!(Instr::Store(ValType::F64, _)) => instr_memarg!(self, instrs, 3),
-
-                // Numeric Instructions
-                instr!(Instr::I32Const(_)) => instr_const!(self, Number::Integer(n), n, instrs, I32Const, u32, "i32.const"),
-                instr!(Instr::I64Const(_)) => instr_const!(self, Number::Integer(n), n, instrs, I64Const, u64, "i64.const"),
-                instr!(Instr::F32Const(_)) => instr_const!(self, Number::FloatingPoint(n), n, instrs, F32Const, f32, "f32.const"),
-                instr!(Instr::F64Const(_)) => instr_const!(self, Number::FloatingPoint(n), n, instrs, F64Const, f64, "f64.const"),
-
-                instr!(instr) => {
-                    instrs.push(instr.clone());
-                    self.consume()?;
+                tk!(TokenKind::LeftParen) => {
+                    if let kw!(Keyword::Instr(_)) = self.peek()? {
+                        self.parse_folded_instr(&mut instrs)?;
+                    } else {
+                        break;
+                    }
                 },
-
-                tk!(TokenKind::RightParen) => { break; },
-                kw!(Keyword::Else) => { break; },
-                kw!(Keyword::End) => { break; },
-                _ => {},
+                kw!(Keyword::Instr(_)) => self.parse_instr(&mut instrs)?,
+                _ => break,
             }
         }
 
         Ok(Expr(instrs))
     }
 
+    // 平坦な形式の命令を1つ読む
+    fn parse_instr(&mut self, instrs: &mut Vec<Instr>) -> Result<(), ParseError> {
+        match &self.lookahead {
+            // Control Instructions
+            instr!(Instr::Block(_, _)) => instr_one_block!(self, instrs, Block),
+            instr!(Instr::Loop(_, _)) => instr_one_block!(self, instrs, Loop),
+            instr!(Instr::If(_, _, _)) => self.parse_if(instrs)?,
+            instr!(Instr::Br(_)) => instr_label!(self, instrs, Br),
+            instr!(Instr::BrIf(_)) => instr_label!(self, instrs, BrIf),
+            instr!(Instr::BrTable(_, _)) => self.parse_br_table(instrs)?,
+            instr!(Instr::Call(_)) => instr_func!(self, instrs, Call),
+            instr!(Instr::CallIndirect(_)) => self.parse_call_indirect(instrs)?,
+
+            // Variable Instructions
+            instr!(Instr::LocalGet(_)) => instr_local!(self, instrs, LocalGet),
+            instr!(Instr::LocalSet(_)) => instr_local!(self, instrs, LocalSet),
+            instr!(Instr::LocalTee(_)) => instr_local!(self, instrs, LocalTee),
+            instr!(Instr::GlobalGet(_)) => instr_global!(self, instrs, GlobalGet),
+            instr!(Instr::GlobalSet(_)) => instr_global!(self, instrs, GlobalSet),
+
+            // Memory Instructions
+            instr!(Instr::ILoad8(_, _, _)) => instr_memarg!(self, instrs, 0),
+            instr!(Instr::IStore8(_, _)) => instr_memarg!(self, instrs, 0),
+
+            instr!(Instr::ILoad16(_, _, _)) => instr_memarg!(self, instrs, 1),
+            instr!(Instr::IStore16(_, _)) => instr_memarg!(self, instrs, 1),
+
+            instr!(Instr::Load(ValType::I32, _)) => instr_memarg!(self, instrs, 2),
+            instr!(Instr::Load(ValType::F32, _)) => instr_memarg!(self, instrs, 2),
+            instr!(Instr::I64Load32(_, _)) => instr_memarg!(self, instrs, 2),
+            instr!(Instr::Store(ValType::I32, _)) => instr_memarg!(self, instrs, 2),
+            instr!(Instr::Store(ValType::F32, _)) => instr_memarg!(self, instrs, 2),
+            instr!(Instr::I64Store32(_)) => instr_memarg!(self, instrs, 2),
+
+            instr!(Instr::Load(ValType::I64, _)) => instr_memarg!(self, instrs, 3),
+            instr!(Instr::Load(ValType::F64, _)) => instr_memarg!(self, instrs, 3),
+            instr!(Instr::Store(ValType::I64, _)) => instr_memarg!(self, instrs, 3),
+            instr!(Instr::Store(ValType::F64, _)) => instr_memarg!(self, instrs, 3),
+
+            // Numeric Instructions
+            instr!(Instr::I32Const(_)) => instr_const!(self, instrs, I32Const, to_i32, "i32.const"),
+            instr!(Instr::I64Const(_)) => instr_const!(self, instrs, I64Const, to_i64, "i64.const"),
+            instr!(Instr::F32Const(_)) => instr_const!(self, instrs, F32Const, to_f32, "f32.const"),
+            instr!(Instr::F64Const(_)) => instr_const!(self, instrs, F64Const, to_f64, "f64.const"),
+
+            instr!(instr) => {
+                instrs.push(instr.clone());
+                self.consume()?;
+            },
+
+            _ => return Err(self.err()),
+        }
+        Ok(())
+    }
+
+    // 畳み込み形式の命令を読み、平坦な命令列に展開してinstrsに加える
+    // (plaininstr foldedinstr*)はオペランドを先に、(if ...)は条件を先に置く
+    fn parse_folded_instr(&mut self, instrs: &mut Vec<Instr>) -> Result<(), ParseError> {
+        self.match_lparen()?;
+
+        match &self.lookahead {
+            instr!(Instr::Block(_, _)) | instr!(Instr::Loop(_, _)) => {
+                let is_block = matches!(self.lookahead, instr!(Instr::Block(_, _)));
+                self.consume()?;
+
+                // label id
+                let mut new_label_context = self.contexts.last().unwrap().clone();
+                parse_optional_label_id!(self, new_label_context.labels);
+                self.contexts.push(new_label_context);
+
+                // resulttype
+                let rt = self.parse_blocktype()?;
+
+                // instr*
+                let expr = self.parse_expr()?;
+
+                self.contexts.pop();
+
+                instrs.push(if is_block { Instr::Block(rt, expr) } else { Instr::Loop(rt, expr) });
+            },
+            instr!(Instr::If(_, _, _)) => {
+                self.consume()?;
+
+                // label id
+                // 条件の中のブロックが先に現れるので、ラベルの記録は条件の後に回す
+                let mut new_label_context = self.contexts.last().unwrap().clone();
+                parse_optional_label_id!(self, new_label_context.labels);
+                let label = self.func_labels.pop().unwrap();
+
+                // resulttype
+                let rt = self.parse_blocktype()?;
+
+                // 条件(ifのラベルの外側)
+                while self.is_lparen()? {
+                    if let kw!(Keyword::Then) = self.peek()? { break; }
+                    self.parse_folded_instr(instrs)?;
+                }
+
+                self.func_labels.push(label);
+                self.contexts.push(new_label_context);
+
+                // (then instr*)
+                self.match_lparen()?;
+                self.match_keyword(Keyword::Then)?;
+                let expr1 = self.parse_expr()?;
+                self.match_rparen()?;
+
+                // (else instr*)は省略できる
+                let mut expr2 = Expr::default();
+                if self.is_lparen()? {
+                    if let kw!(Keyword::Else) = self.peek()? {
+                        self.consume()?;
+                        self.consume()?;
+                        expr2 = self.parse_expr()?;
+                        self.match_rparen()?;
+                    }
+                }
+
+                self.contexts.pop();
+
+                instrs.push(Instr::If(rt, expr1, expr2));
+            },
+            _ => {
+                let mut plain = vec![];
+                self.parse_instr(&mut plain)?;
+
+                // オペランド
+                while self.is_lparen()? {
+                    self.parse_folded_instr(instrs)?;
+                }
+
+                instrs.extend(plain);
+            },
+        }
+
+        self.match_rparen()
+    }
+
+    // memarg: offset=n? align=n?
+    // alignは2の指数で持ち、省略したときは自然なアライメントになる
+    fn parse_memarg(&mut self, natural: u32) -> Result<MemArg, ParseError> {
+        let mut memarg = MemArg { align: natural, offset: 0 };
+
+        if let Some(offset) = self.parse_memarg_field("offset=")? {
+            memarg.offset = offset;
+        }
+
+        let start = self.lookahead.clone();
+        if let Some(align) = self.parse_memarg_field("align=")? {
+            if !align.is_power_of_two() {
+                return Err(ParseError::InvalidMessage(start, "alignment must be a power of two".to_string()));
+            }
+            memarg.align = align.trailing_zeros();
+            if memarg.align > natural {
+                return Err(ParseError::InvalidMessage(start, "alignment must not be larger than natural".to_string()));
+            }
+        }
+
+        Ok(memarg)
+    }
+
+    fn parse_memarg_field(&mut self, prefix: &str) -> Result<Option<u32>, ParseError> {
+        let value = match &self.lookahead {
+            tk!(TokenKind::Reserved(s)) if s.starts_with(prefix) => s[prefix.len()..].to_string(),
+            _ => return Ok(None),
+        };
+
+        let n = word_to_number(value.as_bytes())
+            .and_then(|n| n.to_unsigned())
+            .and_then(|n| u32::try_from(n).ok());
+        match n {
+            Some(n) => {
+                self.consume()?;
+                Ok(Some(n))
+            },
+            None => Err(ParseError::NumCast(self.lookahead.clone())),
+        }
+    }
+
     fn parse_call_indirect(&mut self, instrs: &mut Vec<Instr>) -> Result<(), ParseError> {
         self.consume()?;
 
@@ -156,7 +307,6 @@ impl<R> Parser<R> where R: Read + Seek {
         self.contexts.push(Context::default());
 
         let typeidx = self.parse_typeuse(&mut _ft.0, &mut _ft.1)?;
-        self.check_typeuse(typeidx, _ft)?;    
 
         // check params context (must not include string id)
         if self.contexts[2].locals.iter().any(|x| x.is_some()) {
@@ -180,21 +330,24 @@ impl<R> Parser<R> where R: Read + Seek {
         parse_optional_label_id!(self, new_label_context.labels);
         self.contexts.push(new_label_context);
 
-        self.match_lparen()?;
-
         // resulttype
-        let vt = self.parse_result()?;
+        let rt = self.parse_blocktype()?;
 
         // expr1
         let expr1 = self.parse_expr()?;
 
-        self.match_keyword(Keyword::Else)?;
+        // elseは省略できる
+        let expr2 = if let kw!(Keyword::Else) = self.lookahead {
+            self.consume()?;
 
-        // check label id(after else)
-        self.check_label_id()?;
+            // check label id(after else)
+            self.check_label_id()?;
 
-        // expr2
-        let expr2 = self.parse_expr()?;
+            // expr2
+            self.parse_expr()?
+        } else {
+            Expr::default()
+        };
 
         self.match_keyword(Keyword::End)?;
 
@@ -204,7 +357,7 @@ impl<R> Parser<R> where R: Read + Seek {
         p!(self.contexts.last());
         self.contexts.pop();
 
-        instrs.push(Instr::If(vec![vt], expr1, expr2));
+        instrs.push(Instr::If(rt, expr1, expr2));
 
         Ok(())
     }
@@ -214,18 +367,9 @@ impl<R> Parser<R> where R: Read + Seek {
 
         let mut labelindices = vec![];
 
-        loop {
-            match &self.lookahead {
-                tk!(TokenKind::Id(_)) => {
-                    let local_id = self.resolve_id(&self.contexts.last().unwrap().clone().labels)?;
-                    labelindices.push(local_id);
-                },
-                nm!(Number::Integer(n)) => {
-                    labelindices.push(*n as u32);
-                    self.consume()?;
-                },
-                _ => break,
-            }
+        while let tk!(TokenKind::Id(_)) | nm!(Number::Integer(_)) = &self.lookahead {
+            let local_id = self.resolve_id(&self.contexts.last().unwrap().clone().labels)?;
+            labelindices.push(local_id);
         }
 
         if let Some(labelidx) = labelindices.pop() {
@@ -237,10 +381,21 @@ impl<R> Parser<R> where R: Read + Seek {
         Ok(())
     }
 
+    // resultがなければ空のresulttype
+    fn parse_blocktype(&mut self) -> Result<ResultType, ParseError> {
+        if self.is_lparen()? {
+            if let kw!(Keyword::Result) = self.peek()? {
+                self.match_lparen()?;
+                return Ok(vec![self.parse_result()?]);
+            }
+        }
+        Ok(vec![])
+    }
+
     fn check_label_id(&mut self) -> Result<(), ParseError> {
         if let tk!(TokenKind::Id(s)) = &self.lookahead {
 
-            if let Some(label_s) = &self.contexts.last().unwrap().labels.last().unwrap() {
+            if let Some(label_s) = &self.contexts.last().unwrap().labels[0] {
                 if s != label_s {
                     return Err(self.err2("invalid label of block end"));
                 }
@@ -253,3 +408,111 @@ impl<R> Parser<R> where R: Read + Seek {
         Ok(())
     }
 }
+
+#[cfg(test)]
+fn parse_body(wat: &str) -> Vec<Instr> {
+    let mut parser = Parser::from_text(wat);
+    if let Err(e) = parser.parse() {
+        panic!("{}: {:?}", wat, e);
+    }
+    match &parser.module.funcs[0].2 {
+        FuncBody::Expr(Expr(instrs)) => instrs.clone(),
+        body => panic!("{:?}", body),
+    }
+}
+
+#[test]
+fn test_parse_br() {
+    let instrs = parse_body("(func block br 0 end block i32.const 0 br_if 0 end)");
+    assert_eq!(instrs[0], Instr::Block(vec![], Expr(vec![Instr::Br(0)])));
+    assert_eq!(instrs[1], Instr::Block(vec![], Expr(vec![Instr::I32Const(0), Instr::BrIf(0)])));
+}
+
+#[test]
+fn test_parse_blocktype() {
+    let instrs = parse_body("(func (result i32) block (result i32) i32.const 1 end drop loop nop end i32.const 0)");
+    assert_eq!(instrs[0], Instr::Block(vec![ValType::I32], Expr(vec![Instr::I32Const(1)])));
+    assert_eq!(instrs[2], Instr::Loop(vec![], Expr(vec![Instr::Nop])));
+}
+
+#[test]
+fn test_parse_if_without_else() {
+    let instrs = parse_body("(func i32.const 1 if nop end i32.const 1 if (result i32) i32.const 2 else i32.const 3 end drop)");
+    assert_eq!(instrs[1], Instr::If(vec![], Expr(vec![Instr::Nop]), Expr(vec![])));
+    assert_eq!(instrs[3], Instr::If(vec![ValType::I32], Expr(vec![Instr::I32Const(2)]), Expr(vec![Instr::I32Const(3)])));
+}
+
+#[test]
+fn test_parse_and() {
+    let instrs = parse_body("(func i32.const 1 i32.const 3 i32.and drop i64.const 1 i64.const 3 i64.and drop)");
+    assert_eq!(instrs[2], Instr::IBinOp(ValSize::V32, IBinOp::And));
+    assert_eq!(instrs[6], Instr::IBinOp(ValSize::V64, IBinOp::And));
+}
+
+#[test]
+fn test_parse_folded() {
+    let instrs = parse_body("(func $f (param $x i32) (result i32)
+      (if $l (result i32) (block (result i32) (local.get $x)) (i32.eqz)
+        (then (i32.add (local.get $x) (i32.const 1)))
+        (else (loop $k (br_if $k (local.get $x))) (br $l (i32.const 2))))
+      (if (local.get 0) (then nop))
+      (drop (call $f (i32.const 3))))");
+
+    // オペランドと条件が先に、then・elseがそれぞれのブロックになる
+    assert_eq!(instrs, vec![
+        Instr::Block(vec![ValType::I32], Expr(vec![Instr::LocalGet(0)])),
+        Instr::ITestOp(ValSize::V32, ITestOp::Eqz),
+        Instr::If(vec![ValType::I32],
+            Expr(vec![Instr::LocalGet(0), Instr::I32Const(1), Instr::IBinOp(ValSize::V32, IBinOp::Add)]),
+            Expr(vec![Instr::Loop(vec![], Expr(vec![Instr::LocalGet(0), Instr::BrIf(0)])), Instr::I32Const(2), Instr::Br(0)])),
+        Instr::LocalGet(0),
+        Instr::If(vec![], Expr(vec![Instr::Nop]), Expr(vec![])),
+        Instr::I32Const(3),
+        Instr::Call(0),
+        Instr::Drop,
+    ]);
+}
+
+#[test]
+fn test_parse_memarg() {
+    let instrs = parse_body("(module (memory 1)
+      (func
+        i32.const 0 i64.load offset=8 drop
+        i32.const 0 i64.const 1 i64.store align=4
+        i32.const 0 i32.load8_u offset=0x10 align=1 drop
+        i32.const 0 i32.const 1 i64.store32 offset=1_000))");
+
+    // alignを省略すると自然なアライメント
+    let memarg = |align, offset| MemArg { align, offset };
+    assert_eq!(instrs[1], Instr::Load(ValType::I64, memarg(3, 8)));
+    assert_eq!(instrs[5], Instr::Store(ValType::I64, memarg(2, 0)));
+    assert_eq!(instrs[7], Instr::ILoad8(ValSize::V32, ValSign::U, memarg(0, 16)));
+    assert_eq!(instrs[11], Instr::I64Store32(memarg(2, 1000)));
+
+    for wat in &["(module (func i32.const 0 i32.load align=8 drop))",
+                 "(module (func i32.const 0 i32.load16_s align=3 drop))"] {
+        let mut parser = Parser::from_text(wat);
+        match parser.parse() {
+            Err(ParseError::InvalidMessage(..)) => {},
+            res => panic!("{}: {:?}", wat, res),
+        }
+    }
+}
+
+#[test]
+fn test_parse_label_index() {
+    // 識別子のあるラベルの内側に、識別子のないラベルがあってもずれない
+    let instrs = parse_body("(func block $a block br $a end end)");
+    assert_eq!(instrs, vec![Instr::Block(vec![], Expr(vec![Instr::Block(vec![], Expr(vec![Instr::Br(1)]))]))]);
+
+    let instrs = parse_body("(func block block $b loop br $b br 2 end end $b end)");
+    assert_eq!(instrs, vec![Instr::Block(vec![], Expr(vec![
+        Instr::Block(vec![], Expr(vec![Instr::Loop(vec![], Expr(vec![Instr::Br(1), Instr::Br(2)]))])),
+    ]))]);
+
+    // endの後の識別子は、いちばん内側のラベルと一致しなければならない
+    let instrs = parse_body("(func block $a block $b end $b end $a)");
+    assert_eq!(instrs, vec![Instr::Block(vec![], Expr(vec![Instr::Block(vec![], Expr(vec![]))]))]);
+    let mut parser = Parser::from_text("(module (func block $a block $b end $a end))");
+    assert!(parser.parse().is_err());
+}
@@ -4,11 +4,20 @@ use super::*;
 impl<R> Parser<R> where R: Read + Seek {
     pub(super) fn parse_func(&mut self) -> Result<(), ParseError> {
         let mut func = Func::default();
+        let mark = self.annotations.len();
 
         self.match_keyword(Keyword::Func)?;
 
         // func id
-        parse_optional_id!(self, self.contexts[0].funcs);
+        skip_optional_id!(self);
+        let funcidx = next_index!(self, Func, funcs);
+        if let Some(name) = self.parse_name_annotation(mark)? {
+            self.func_names.push((funcidx, name));
+        }
+
+        // 省略形のexportとimport
+        self.parse_inline_exports(ExportDesc::Func(funcidx))?;
+        let import = self.parse_inline_import()?;
 
         // add local context
         self.contexts.push(Context::default());
@@ -17,10 +26,14 @@ impl<R> Parser<R> where R: Read + Seek {
         let mut _ft = FuncType::default();
         func.0 = self.parse_typeuse(&mut _ft.0, &mut _ft.1)?;
 
-        self.check_typeuse(func.0, _ft)?;
+        if let Some((module, name)) = import {
+            self.contexts.pop();
+            self.push_import(Import(module, name, ImportDesc::Func(func.0)))?;
+            self.match_rparen()?;
+            return Ok(());
+        }
 
-        let typedef = &self.contexts[0].typedefs[func.0 as usize];
-        func.1.extend(typedef.0.clone());
+        func.1.extend(_ft.0);
 
         // locals
         parse_field!(self, Local, 
@@ -29,10 +42,19 @@ impl<R> Parser<R> where R: Read + Seek {
         });
 
         // Expr
-        func.2 = self.parse_expr()?;
+        self.func_labels.clear();
+        func.2 = FuncBody::Expr(self.parse_expr()?);
 
         self.module.funcs.push(func);
 
+        let funcidx = funcidx as usize;
+        if self.func_contexts.len() <= funcidx { self.func_contexts.resize(funcidx + 1, Context::default()); }
+        self.func_contexts[funcidx] = Context {
+            locals: self.contexts[1].locals.clone(),
+            labels: std::mem::take(&mut self.func_labels),
+            ..Context::default()
+        };
+
         // la!(self);p!(self.contexts[1]);
         self.contexts.pop();
         self.match_rparen()?;
@@ -3,11 +3,25 @@ use super::*;
 impl<R> Parser<R> where R: Read + Seek {
 
     pub(super) fn parse_global(&mut self) -> Result<(), ParseError> {
+        self.match_keyword(Keyword::Global)?;
+
+        // global id
+        skip_optional_id!(self);
+        let globalidx = next_index!(self, Global, globals);
+
+        // 省略形のexportとimport
+        self.parse_inline_exports(ExportDesc::Global(globalidx))?;
+        let import = self.parse_inline_import()?;
+
         let global_type = self.parse_global_type()?;
 
-        let expr = self.parse_expr()?;
-        
-        self.module.globals.push(Global(global_type, expr));
+        match import {
+            Some((module, name)) => self.push_import(Import(module, name, ImportDesc::Global(global_type)))?,
+            None => {
+                let expr = self.parse_expr()?;
+                self.module.globals.push(Global(global_type, expr));
+            },
+        }
 
         self.match_rparen()?;
 
@@ -15,27 +29,32 @@ impl<R> Parser<R> where R: Read + Seek {
     }
 
     pub(super) fn parse_global_type(&mut self) -> Result<GlobalType, ParseError> {        
-        self.match_keyword(Keyword::Global)?;
-
-        // global id
-        parse_optional_id!(self, self.contexts[0].globals);
-
-        // mutablity
-        let mutablity = Mutablity::Const;
-
-        // valtype
-        let vt = if self.is_lparen()? {
+        // mutablity, valtype
+        let (mutablity, vt) = if self.is_lparen()? {
             self.match_lparen()?;
             self.match_keyword(Keyword::Mutable)?;
             let vt = self.parse_valtype()?;
             self.match_rparen()?;
-            vt 
+            (Mutablity::Var, vt)
         } else {
-            self.parse_valtype()?
+            (Mutablity::Const, self.parse_valtype()?)
         };
 
         let global_type = GlobalType(mutablity, vt);
 
         Ok(global_type)
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_parse_global_mutability() {
+    let mut parser = Parser::from_text("(module
+      (global i32 (i32.const 1))
+      (global (mut i64) (i64.const 2)))");
+    parser.parse().unwrap();
+
+    assert_eq!(parser.module.globals, vec![
+        Global(GlobalType(Mutablity::Const, ValType::I32), Expr(vec![Instr::I32Const(1)])),
+        Global(GlobalType(Mutablity::Var, ValType::I64), Expr(vec![Instr::I64Const(2)])),
+    ]);
+}
@@ -17,13 +17,43 @@ impl<R> Parser<R> where R: Read + Seek {
         // import desc
         let import_desc = self.parse_import_desc()?;
 
-        self.module.imports.push(Import(import_module, import_name, import_desc));
+        self.push_import(Import(import_module, import_name, import_desc))?;
 
         self.match_rparen()?;
         
         Ok(())
     }
 
+    // インポートは、同じインデックス空間の定義より前になければならない
+    pub(super) fn push_import(&mut self, import: Import) -> Result<(), ParseError> {
+        let defined = match import.2 {
+            ImportDesc::Func(_) => !self.module.funcs.is_empty(),
+            ImportDesc::Table(_) => !self.module.tables.is_empty(),
+            ImportDesc::Mem(_) => !self.module.mems.is_empty(),
+            ImportDesc::Global(_) => !self.module.globals.is_empty(),
+        };
+        if defined {
+            return Err(self.err2("import after definition"));
+        }
+        self.module.imports.push(import);
+        Ok(())
+    }
+
+    // 省略形の(import "module" "name")
+    pub(super) fn parse_inline_import(&mut self) -> Result<Option<(Name, Name)>, ParseError> {
+        if self.is_lparen()? {
+            if let kw!(Keyword::Import) = self.peek()? {
+                self.consume()?;
+                self.consume()?;
+                let import_module = self.parse_name()?;
+                let import_name = self.parse_name()?;
+                self.match_rparen()?;
+                return Ok(Some((import_module, import_name)));
+            }
+        }
+        Ok(None)
+    }
+
     fn parse_import_desc(&mut self) -> Result<ImportDesc, ParseError> {
         match self.lookahead {
             kw!(Keyword::Func) => self.parse_import_desc_func(),
@@ -38,31 +68,103 @@ impl<R> Parser<R> where R: Read + Seek {
         self.match_keyword(Keyword::Func)?;
 
         // func id
-        parse_optional_id!(self, self.contexts[0].funcs);
+        skip_optional_id!(self);
 
         // typeuse
         let mut _ft = FuncType::default();
         let typeidx = self.parse_typeuse(&mut _ft.0, &mut _ft.1)?;
 
-        self.check_typeuse(typeidx, _ft)?;
-
         self.match_rparen()?;
 
         Ok(ImportDesc::Func(typeidx))
     }
 
     fn parse_import_desc_table(&mut self) -> Result<ImportDesc, ParseError> {
+        self.match_keyword(Keyword::Table)?;
+
+        // table id
+        skip_optional_id!(self);
+
         let table_type = self.parse_table_type()?;
+
+        self.match_rparen()?;
+
         Ok(ImportDesc::Table(table_type))
     }
 
     fn parse_import_desc_memory(&mut self) -> Result<ImportDesc, ParseError> {        
+        self.match_keyword(Keyword::Memory)?;
+
+        // mem id
+        skip_optional_id!(self);
+
         let mem_type = self.parse_memory_type()?;
+
+        self.match_rparen()?;
+
         Ok(ImportDesc::Mem(mem_type))
     }
 
     fn parse_import_desc_global(&mut self) -> Result<ImportDesc, ParseError> {        
+        self.match_keyword(Keyword::Global)?;
+
+        // global id
+        skip_optional_id!(self);
+
         let global_type = self.parse_global_type()?;
+
+        self.match_rparen()?;
+
         Ok(ImportDesc::Global(global_type))
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_parse_inline_imports() {
+    let mut parser = Parser::from_text(r#"(module
+      (type (func (param i32)))
+      (memory (import "env" "mem") 1)
+      (global $h (import "env" "h") i32)
+      (func $g (import "m" "g") (type 0))
+      (table $t (import "env" "t") 2 3 funcref)
+      (func $f (param i32) local.get 0 call $g))"#);
+    parser.parse().unwrap();
+
+    let limits = |min, max| Limits { min, max };
+    assert_eq!(parser.module.imports, vec![
+        Import("env".into(), "mem".into(), ImportDesc::Mem(MemType(limits(1, None)))),
+        Import("env".into(), "h".into(), ImportDesc::Global(GlobalType(Mutablity::Const, ValType::I32))),
+        Import("m".into(), "g".into(), ImportDesc::Func(0)),
+        Import("env".into(), "t".into(), ImportDesc::Table(TableType { limits: limits(2, Some(3)), elem_type: ElemType::FuncRef })),
+    ]);
+    assert!(parser.module.mems.is_empty() && parser.module.globals.is_empty() && parser.module.tables.is_empty());
+    // インポートした関数が先にインデックスを持つ
+    assert_eq!(parser.module.funcs.len(), 1);
+    assert_eq!(parser.module.funcs[0].2.expr().unwrap().0, vec![Instr::LocalGet(0), Instr::Call(0)]);
+    assert_eq!(parser.contexts[0].funcs, vec![Some("g".to_string()), Some("f".to_string())]);
+    assert_eq!(parser.contexts[0].tables, vec![Some("t".to_string())]);
+
+    // インポートは同じインデックス空間の定義より前に置く
+    let mut parser = Parser::from_text(r#"(module
+      (type (func))
+      (func (type 0))
+      (func (import "m" "f") (type 0)))"#);
+    match parser.parse() {
+        Err(ParseError::InvalidMessage(_, mes)) => assert_eq!(mes, "import after definition"),
+        res => panic!("{:?}", res),
+    }
+}
+
+#[test]
+fn test_parse_import_global() {
+    // (global ...)を閉じたあとも、続くフィールドを読める
+    let mut parser = Parser::from_text(r#"(module
+      (import "env" "g" (global (mut i64)))
+      (import "env" "f" (func)))"#);
+    parser.parse().unwrap();
+
+    assert_eq!(parser.module.imports, vec![
+        Import("env".into(), "g".into(), ImportDesc::Global(GlobalType(Mutablity::Var, ValType::I64))),
+        Import("env".into(), "f".into(), ImportDesc::Func(0)),
+    ]);
+}
@@ -1,27 +1,86 @@
+use instr::*;
 use super::*;
 
+// メモリのページの大きさ(64KiB)
+const PAGE_SIZE: usize = 65536;
+
 impl<R> Parser<R> where R: Read + Seek {
 
     pub(super) fn parse_memory(&mut self) -> Result<(), ParseError> {
+        self.match_keyword(Keyword::Memory)?;
+
+        // mem id
+        skip_optional_id!(self);
+        let memidx = next_index!(self, Mem, mems);
+
+        // 省略形のexportとimport
+        self.parse_inline_exports(ExportDesc::Mem(memidx))?;
+        let import = self.parse_inline_import()?;
+
+        // 省略形の (data datastring*)
+        if import.is_none() && self.is_lparen()? {
+            if let kw!(Keyword::Data) = self.peek()? {
+                return self.parse_memory_inline_data(memidx);
+            }
+        }
+
         // memtype
         let mem_type = self.parse_memory_type()?;
 
-        self.module.mems.push(Memory(mem_type));        
+        match import {
+            Some((module, name)) => self.push_import(Import(module, name, ImportDesc::Mem(mem_type)))?,
+            None => self.module.mems.push(Memory(mem_type)),
+        }
+
+        self.match_rparen()?;
 
         Ok(())
     }
 
-    pub(super) fn parse_memory_type(&mut self) -> Result<MemType, ParseError> {
+    // データが収まるページ数のメモリと、オフセット0のデータセグメントになる
+    fn parse_memory_inline_data(&mut self, memidx: MemIndex) -> Result<(), ParseError> {
+        self.match_lparen()?;
+        let data = self.lookahead.clone();
+        self.match_keyword(Keyword::Data)?;
+        let init = self.parse_data_strings()?;
+        self.match_rparen()?;
 
-        self.match_keyword(Keyword::Memory)?;
+        // 大きすぎるときは`data`の位置でエラーにする
+        let pages = u32::try_from(init.len().div_ceil(PAGE_SIZE)).map_err(|_| ParseError::NumCast(data))?;
+        let limits = Limits { min: pages, max: Some(pages) };
+        self.module.mems.push(Memory(MemType(limits)));
+        self.module.data.push(Data {
+            data: memidx,
+            offset: Expr(vec![Instr::I32Const(0)]),
+            init,
+        });
 
-        // mem id
-        parse_optional_id!(self, self.contexts[0].mems);
+        self.match_rparen()
+    }
 
+    pub(super) fn parse_memory_type(&mut self) -> Result<MemType, ParseError> {
         let limits = self.parse_limits()?;
 
-        self.match_rparen()?;
-
         Ok(MemType(limits))
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_parse_memory_inline_data() {
+    let mut parser = Parser::from_text(r#"(module
+      (memory (data "hello" " " "world"))
+      (data 0 (offset i32.const 16) "a" "" "bc"))"#);
+    parser.parse().unwrap();
+
+    // データの大きさをページ単位に切り上げたものが最小・最大の大きさになる
+    assert_eq!(parser.module.mems, vec![Memory(MemType(Limits { min: 1, max: Some(1) }))]);
+    assert_eq!(parser.module.data, vec![
+        Data { data: 0, offset: Expr(vec![Instr::I32Const(0)]), init: b"hello world".to_vec() },
+        Data { data: 0, offset: Expr(vec![Instr::I32Const(16)]), init: b"abc".to_vec() },
+    ]);
+
+    let mut parser = Parser::from_text("(module (memory (data)))");
+    parser.parse().unwrap();
+    assert_eq!(parser.module.mems[0].0, MemType(Limits { min: 0, max: Some(0) }));
+    assert_eq!(parser.module.data[0].init, vec![]);
+}
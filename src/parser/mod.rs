@@ -13,8 +13,9 @@ mod export_parser;
 mod elem_parser;
 mod data_parser;
 mod expr_parser;
+mod custom_parser;
 
-use std::io::{Read, Seek};
+use std::io::{Read, Seek, Cursor};
 use std::convert::TryFrom;
 
 use annot::*;
@@ -34,23 +35,102 @@ where R: Read + Seek {
     lexer: Lexer<R>,
     lookahead: Token,
     pub contexts: Vec<Context>,
+    // 関数ごとのlocalsとlabelsの識別子(インデックスは関数のインデックス)
+    pub func_contexts: Vec<Context>,
+    func_labels: Vec<Option<Id>>,
+    // 文法の外で読んだ注釈トークン(出現順)
+    annotations: Vec<Token>,
+    // @nameで付けた関数の名前
+    func_names: Vec<(FuncIndex, Name)>,
+    // trueなら、フィールドのエラーを記録して次のフィールドから読み直す
+    recovering: bool,
+    diagnostics: Vec<Diagnostic>,
+    // 1回目の読み込みで、フィールドごとに識別子を加えたインデックス空間
+    declared: Vec<Option<Keyword>>,
     pub module: Module,
 }
 
+// 1つのモジュールと、nameセクションのための識別子
+#[derive(Debug)]
+pub struct ParsedModule {
+    pub module: Module,
+    pub context: Context,
+    pub func_contexts: Vec<Context>,
+}
+
+impl<'a> Parser<Cursor<&'a [u8]>> {
+    pub fn from_text(text: &'a str) -> Self {
+        Self::from_bytes(text.as_bytes())
+    }
+
+    pub fn from_bytes(bytes: &'a [u8]) -> Self {
+        Self::new(Cursor::new(bytes))
+    }
+}
+
 impl<R> Parser<R> where R: Read + Seek {
     pub fn new(reader: R) -> Self {
         Self {
             lexer: Lexer::new(reader),
             lookahead: Token::empty(Loc::default()),
             contexts: vec![Context::default()],
+            func_contexts: vec![],
+            func_labels: vec![],
+            annotations: vec![],
+            func_names: vec![],
+            recovering: false,
+            diagnostics: vec![],
+            declared: vec![],
             module: Module::default(),
         }
     }
 
+    // ソース全体を1つのモジュールとして読む
+    // (module ...)で囲まず、フィールドだけを並べてもよい
     pub fn parse(&mut self) -> Result<(), ParseError> {
-        self.lookahead = self.lexer.next_token()?;
-        self.match_lparen()?;
-        self.parse_module()
+        self.lookahead = self.next_token()?;
+        if self.is_lparen()? && matches!(self.peek()?, kw!(Keyword::Module)) {
+            self.match_lparen()?;
+            self.parse_module()?;
+        } else {
+            self.parse_module_fields()?;
+        }
+        self.match_token(TokenKind::Empty)
+    }
+
+    // parseと同じだが、最初のエラーで止まらずにすべてのエラーを集める
+    // エラーのあったフィールドを除いたModuleは、エラーがあってもself.moduleに残る
+    pub fn parse_with_diagnostics(&mut self) -> Result<(), Vec<Diagnostic>> {
+        self.recovering = true;
+        let res = self.parse();
+        self.recovering = false;
+
+        let mut diagnostics = std::mem::take(&mut self.diagnostics);
+        if let Err(e) = res {
+            diagnostics.push(Diagnostic::from(e));
+        }
+        // 型のエラーは1回目の読み込みで記録しているので、位置の順に並べ直す
+        diagnostics.sort_by_key(|d| (d.loc.0, d.loc.1));
+
+        if diagnostics.is_empty() { Ok(()) } else { Err(diagnostics) }
+    }
+
+    // ソースに並んだ(module ...)をすべて読む
+    pub fn parse_modules(&mut self) -> Result<Vec<ParsedModule>, ParseError> {
+        let mut modules = vec![];
+        self.lookahead = self.next_token()?;
+        while !matches!(self.lookahead, tk!(TokenKind::Empty)) {
+            self.match_lparen()?;
+            self.parse_module()?;
+
+            modules.push(ParsedModule {
+                module: std::mem::take(&mut self.module),
+                context: std::mem::take(&mut self.contexts[0]),
+                func_contexts: std::mem::take(&mut self.func_contexts),
+            });
+            self.annotations.clear();
+        }
+        Ok(modules)
     }
 
     fn parse_module(&mut self) -> Result<(), ParseError> {
@@ -62,34 +142,257 @@ impl<R> Parser<R> where R: Read + Seek {
             self.consume()?;
         }
 
-        parse_field!(self, Type, self.parse_type()?);
-        parse_field!(self, Import, self.parse_import()?);
-        parse_field!(self, Table, self.parse_table()?);
-        parse_field!(self, Memory, self.parse_memory()?);
-        parse_field!(self, Global, self.parse_global()?);
-        parse_field!(self, Func, self.parse_func()?);
-        parse_field!(self, Export, self.parse_export()?);
+        self.parse_module_fields()?;
 
-        if !self.is_rparen()? {
-            if let tk!(TokenKind::LeftParen) = self.lookahead {
-                self.consume()?;
+        self.match_rparen()
+    }
+
+    fn parse_module_fields(&mut self) -> Result<(), ParseError> {
+
+        // 後で定義される型や関数も参照できるように、先に型の定義と識別子を集めてから読み直す
+        let checkpoint = self.lexer.checkpoint()?;
+        let lookahead = self.lookahead.clone();
+        let annotations = self.annotations.len();
+        self.declared.clear();
+        self.declare_fields()?;
+        self.lexer.restore(checkpoint)?;
+        self.lookahead = lookahead;
+        self.annotations.truncate(annotations);
+
+        self.parse_fields(false)?;
+
+        // 注釈は、識別子が要らなくなってから反映する
+        self.parse_customs()?;
+        for (funcidx, name) in std::mem::take(&mut self.func_names) {
+            if let Some(id) = self.contexts[0].funcs.get_mut(funcidx as usize) {
+                *id = Some(name);
             }
-            if let kw!(Keyword::Start) = self.lookahead {
-                self.parse_start()?;
+        }
+
+        Ok(())
+    }
+
+    fn parse_field(&mut self) -> Result<(), ParseError> {
+        match self.peek()? {
+            // 型は1回目で定義済み
+            kw!(Keyword::Type) => { self.consume()?; self.consume()?; self.skip_field() },
+            kw!(Keyword::Import) => { self.consume()?; self.parse_import() },
+            kw!(Keyword::Func) => { self.consume()?; self.parse_func() },
+            kw!(Keyword::Table) => { self.consume()?; self.parse_table() },
+            kw!(Keyword::Memory) => { self.consume()?; self.parse_memory() },
+            kw!(Keyword::Global) => { self.consume()?; self.parse_global() },
+            kw!(Keyword::Export) => { self.consume()?; self.parse_export() },
+            kw!(Keyword::Start) => { self.consume()?; self.parse_start() },
+            kw!(Keyword::Elem) => { self.consume()?; self.parse_elem() },
+            kw!(Keyword::Data) => { self.consume()?; self.parse_data() },
+            _ => { self.consume()?; Err(self.err()) },
+        }
+    }
+
+    // ')'かソースの終わりまで、フィールドを読む
+    // declaringなら1回目の読み込み
+    fn parse_fields(&mut self, declaring: bool) -> Result<(), ParseError> {
+        let mut field = 0;
+        loop {
+            match self.lookahead {
+                tk!(TokenKind::LeftParen) if self.recovering => {
+                    self.recover_field(declaring, field)?;
+                    field += 1;
+                },
+                tk!(TokenKind::LeftParen) if declaring => self.declare_field()?,
+                tk!(TokenKind::LeftParen) => self.parse_field()?,
+                tk!(TokenKind::RightParen) | tk!(TokenKind::Empty) => return Ok(()),
+                // フィールドの外にある余計なトークン
+                _ if self.recovering => {
+                    if !declaring {
+                        self.diagnostics.push(Diagnostic::from(self.err()));
+                    }
+                    self.skip_broken_field()?;
+                },
+                _ => return Ok(()),
             }
         }
-        parse_field!(self, Elem, self.parse_elem()?);
-        parse_field!(self, Data, self.parse_data()?);        
+    }
+
+    // '('から始まるフィールドを1つ読む
+    // エラーを記録し、フィールドの先頭に戻って対応する')'まで読み飛ばす
+    // 型は1回目、それ以外は2回目にだけ読むので、エラーもそのときに1度だけ記録する
+    fn recover_field(&mut self, declaring: bool, field: usize) -> Result<(), ParseError> {
+        let checkpoint = self.lexer.checkpoint()?;
+        let lookahead = self.lookahead.clone();
+        let annotations = self.annotations.len();
+        let keyword = match self.peek() {
+            Ok(kw!(kw)) => Some(kw),
+            Ok(_) => None,
+            // エラーはフィールドを読むときにもう一度出る
+            Err(_) => { self.lexer.restore(checkpoint.clone())?; None },
+        };
+        let is_type = keyword == Some(Keyword::Type);
+
+        let declared = self.declared.len();
+        let types = self.module.types.len();
+        // 2回目は、このフィールドが定義するはずのインデックス空間
+        let space = if declaring { None } else { self.declared.get(field).cloned().flatten() };
+        let count = space.as_ref().map(|space| self.index_count(space));
+
+        let res = if declaring { self.declare_field() } else { self.parse_field() };
+        if declaring && self.declared.len() == declared {
+            self.declared.push(None);
+        }
 
-        self.match_rparen()?;
+        if let Err(e) = res {
+            if declaring == is_type {
+                self.diagnostics.push(Diagnostic::from(e));
+            }
+            self.contexts.truncate(1);
+            self.func_labels.clear();
+            self.lexer.restore(checkpoint)?;
+            self.lookahead = lookahead;
+            self.annotations.truncate(annotations);
+            self.skip_broken_field()?;
+
+            // インデックスがずれないように、読めなかった定義の代わりを置く
+            if declaring && is_type {
+                self.push_type_placeholder(types);
+            }
+            if let (Some(space), Some(count)) = (space, count) {
+                if self.index_count(&space) == count {
+                    self.push_placeholder(&space, keyword == Some(Keyword::Import));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn index_count(&self, space: &Keyword) -> u32 {
+        match space {
+            Keyword::Func => next_index!(self, Func, funcs),
+            Keyword::Table => next_index!(self, Table, tables),
+            Keyword::Memory => next_index!(self, Mem, mems),
+            Keyword::Global => next_index!(self, Global, globals),
+            _ => 0,
+        }
+    }
+
+    // 識別子は1回目で加えてあるので、定義だけを空のもので補う
+    fn push_placeholder(&mut self, space: &Keyword, import: bool) {
+        let table_type = || TableType { limits: Limits::default(), elem_type: ElemType::FuncRef };
+        if import {
+            let desc = match space {
+                Keyword::Func => ImportDesc::Func(self.implicit_type(FuncType::default())),
+                Keyword::Table => ImportDesc::Table(table_type()),
+                Keyword::Memory => ImportDesc::Mem(MemType::default()),
+                _ => ImportDesc::Global(GlobalType::default()),
+            };
+            self.module.imports.push(Import(Name::new(), Name::new(), desc));
+            return;
+        }
+        match space {
+            Keyword::Func => {
+                let typeidx = self.implicit_type(FuncType::default());
+                self.module.funcs.push(Func(typeidx, vec![], FuncBody::default()));
+            },
+            Keyword::Table => self.module.tables.push(Table(table_type())),
+            Keyword::Memory => self.module.mems.push(Memory::default()),
+            _ => self.module.globals.push(Global(GlobalType::default(), Expr(vec![Instr::I32Const(0)]))),
+        }
+    }
 
+    // 読めなかった型は、識別子のない空の関数型にする
+    fn push_type_placeholder(&mut self, types: usize) {
+        let context = &mut self.contexts[0];
+        if context.types.len() == types {
+            context.types.push(None);
+        }
+        if self.module.types.len() == types {
+            self.module.types.push(FuncType::default());
+            context.typedefs.push(FuncType::default());
+        }
+    }
+
+    // skip_fieldと同じだが、字句のエラーも読み飛ばす
+    fn skip_broken_field(&mut self) -> Result<(), ParseError> {
+        let mut depth = 0;
+        loop {
+            match self.lookahead {
+                tk!(TokenKind::LeftParen) => depth += 1,
+                tk!(TokenKind::RightParen) => depth -= 1,
+                tk!(TokenKind::Empty) => return Ok(()),
+                _ => {},
+            }
+            self.lookahead = loop {
+                match self.next_token() {
+                    Err(ParseError::Lex(_)) => self.lexer.recover()?,
+                    res => break res?,
+                }
+            };
+            if depth == 0 { return Ok(()) }
+        }
+    }
+
+    // 1回目の読み込み
+    // 型を定義し、関数・テーブル・メモリ・グローバルの識別子をインデックスの順に集める
+    fn declare_fields(&mut self) -> Result<(), ParseError> {
+        self.parse_fields(true)
+    }
+
+    fn declare_field(&mut self) -> Result<(), ParseError> {
+        self.consume()?;
+
+        match self.lookahead {
+            kw!(Keyword::Type) => return self.parse_type(),
+            kw!(Keyword::Import) => {
+                self.consume()?;
+                self.parse_name()?;
+                self.parse_name()?;
+                self.match_lparen()?;
+                self.declare_id()?;
+                self.skip_field()?;
+            },
+            _ => self.declare_id()?,
+        }
+        self.skip_field()
+    }
+
+    // キーワードに応じたインデックス空間に、続く識別子(なければNone)を加える
+    fn declare_id(&mut self) -> Result<(), ParseError> {
+        let id = if let tk!(TokenKind::Id(s)) = self.peek()? { Some(s) } else { None };
+        let context = &mut self.contexts[0];
+        match self.lookahead {
+            kw!(Keyword::Func) => context.funcs.push(id),
+            kw!(Keyword::Table) => context.tables.push(id),
+            kw!(Keyword::Memory) => context.mems.push(id),
+            kw!(Keyword::Global) => context.globals.push(id),
+            _ => return Ok(()),
+        }
+        if let kw!(kw) = &self.lookahead {
+            self.declared.push(Some(kw.clone()));
+        }
         Ok(())
     }
 
+    // 対応する')'の次まで読み飛ばす
+    fn skip_field(&mut self) -> Result<(), ParseError> {
+        let mut depth = 1;
+        loop {
+            match self.lookahead {
+                tk!(TokenKind::LeftParen) => depth += 1,
+                tk!(TokenKind::RightParen) => depth -= 1,
+                tk!(TokenKind::Empty) => return Err(self.err()),
+                _ => {},
+            }
+            self.consume()?;
+            if depth == 0 { return Ok(()) }
+        }
+    }
+
     fn parse_start(&mut self) -> Result<(), ParseError> {
         self.match_keyword(Keyword::Start)?;
 
         // func id
+        if self.module.start.is_some() {
+            return Err(self.err2("multiple start sections"));
+        }
         let funcidx = self.resolve_id(&self.contexts[0].funcs.clone())?;
         self.module.start = Some(Start(funcidx));
 
@@ -136,21 +439,24 @@ impl<R> Parser<R> where R: Read + Seek {
         }
     }
 
+    // 名前はUTF-8でなければならない
     fn parse_name(&mut self) -> Result<Name, ParseError> {
-        self.parse_string()
+        let start = self.lookahead.clone();
+        let bytes = self.parse_string()?;
+        String::from_utf8(bytes).map_err(|_| ParseError::InvalidMessage(start, "malformed UTF-8 encoding".to_string()))
     }
 
     fn parse_data_string(&mut self) -> Result<DataString, ParseError> {
         self.parse_string()
     }
 
-    fn parse_string(&mut self) -> Result<String, ParseError> {
+    fn parse_string(&mut self) -> Result<Vec<u8>, ParseError> {
         if let tk!(TokenKind::String(s)) = &self.lookahead {
             let res = Ok(s.clone());
             self.consume()?;
             res
         } else {
-            Err(ParseError::NotMatch(self.lookahead.clone(), TokenKind::String("".into())))
+            Err(ParseError::NotMatch(self.lookahead.clone(), TokenKind::String(vec![])))
         }
     }
 
@@ -164,9 +470,9 @@ impl<R> Parser<R> where R: Read + Seek {
         }
     }
 
-    fn parse_num<T: TryFrom<usize>>(&mut self) -> Result<T, ParseError> {
-        if let nm!(Number::Integer(n)) = &self.lookahead {
-            if let Ok(num) = T::try_from(n.clone()) {
+    fn parse_num<T: TryFrom<u64>>(&mut self) -> Result<T, ParseError> {
+        if let nm!(n) = &self.lookahead {
+            if let Some(num) = n.to_unsigned().and_then(|n| T::try_from(n).ok()) {
                 self.consume()?;
                 Ok(num)
             } else {
@@ -204,11 +510,7 @@ impl<R> Parser<R> where R: Read + Seek {
 
     fn resolve_id(&mut self, from: &Vec<Option<Id>>) -> Result<u32, ParseError> {
         match &self.lookahead {
-            nm!(Number::Integer(n)) => {
-                let res = u32::try_from(n.clone())?;
-                self.consume()?;
-                Ok(res)
-            },
+            nm!(_) => self.parse_num::<u32>(),
             tk!(TokenKind::Id(id)) => {
 
                 if let Some(idx) = from.iter()
@@ -231,16 +533,35 @@ impl<R> Parser<R> where R: Read + Seek {
     }
 
     fn peek(&mut self) -> Result<Token, ParseError> {
-        let peeked = self.lexer.peek_token()?;
-        Ok(peeked)
+        loop {
+            let peeked = self.lexer.peek_token()?;
+            if let tk!(TokenKind::Annotation(..)) = peeked {
+                self.lexer.next_token()?;
+                self.annotations.push(peeked);
+            } else {
+                return Ok(peeked);
+            }
+        }
     }
 
     fn consume(&mut self) -> Result<(), ParseError> {
-        self.lookahead = self.lexer.next_token()?;
+        self.lookahead = self.next_token()?;
         // p!(self.lookahead);
         Ok(())
     }
 
+    // 注釈はどこにでも書けるので、文法のトークンとは分けて取っておく
+    fn next_token(&mut self) -> Result<Token, ParseError> {
+        loop {
+            let token = self.lexer.next_token()?;
+            if let tk!(TokenKind::Annotation(..)) = token {
+                self.annotations.push(token);
+            } else {
+                return Ok(token);
+            }
+        }
+    }
+
     fn err(&self) -> ParseError {
         ParseError::Invalid(self.lookahead.clone())
     }
@@ -248,4 +569,139 @@ impl<R> Parser<R> where R: Read + Seek {
     fn err2(&self, mes: &'static str) -> ParseError {
         ParseError::InvalidMessage(self.lookahead.clone(), mes.to_string())
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_parse_any_field_order() {
+    let mut parser = Parser::from_text(r#"(module
+      (export "main" (func $main))
+      (func $main (type $t) (param i32) (result i32)
+        local.get 0 call $inc)
+      (start $init)
+      (elem 0 (offset i32.const 0) $inc $main)
+      (func $inc (param $x i32) (result i32)
+        local.get $x i32.const 1 i32.add)
+      (type $t (func (param i32) (result i32)))
+      (table 2 funcref)
+      (func $init (type $v) (drop (call $main (global.get $g))))
+      (global $g (import "env" "g") i32)
+      (type $v (func)))"#);
+    parser.parse().unwrap();
+    let module = &parser.module;
+
+    // 後で定義される型や関数も参照できる
+    assert_eq!(module.types, vec![(vec![ValType::I32], vec![ValType::I32]), (vec![], vec![])]);
+    assert_eq!(module.imports, vec![Import("env".into(), "g".into(), ImportDesc::Global(GlobalType(Mutablity::Const, ValType::I32)))]);
+    let body = |i: usize| module.funcs[i].2.expr().unwrap().0.clone();
+    assert_eq!(module.funcs.iter().map(|func| func.0).collect::<Vec<_>>(), vec![0, 0, 1]);
+    assert_eq!(body(0), vec![Instr::LocalGet(0), Instr::Call(1)]);
+    assert_eq!(body(2), vec![Instr::GlobalGet(0), Instr::Call(0), Instr::Drop]);
+    assert_eq!(module.exports, vec![Export("main".into(), ExportDesc::Func(0))]);
+    assert_eq!(module.start, Some(Start(2)));
+    assert_eq!(module.elems, vec![Elem { table: 0, offset: Expr(vec![Instr::I32Const(0)]), init: vec![1, 0] }]);
+    assert_eq!(module.tables.len(), 1);
+}
+
+#[test]
+fn test_parse_modules() {
+    let mut parser = Parser::from_text(r#"(module $a (func $f (export "f")))
+      ;; 2つ目のモジュール
+      (module (memory 1) (func $g) (func $h))"#);
+    let modules = parser.parse_modules().unwrap();
+    assert_eq!(modules.len(), 2);
+    assert_eq!(modules[0].module.id, Some("a".to_string()));
+    assert_eq!(modules[0].context.funcs, vec![Some("f".to_string())]);
+    assert_eq!(modules[1].module.funcs.len(), 2);
+    assert_eq!(modules[1].module.mems.len(), 1);
+    assert_eq!(modules[1].context.funcs, vec![Some("g".to_string()), Some("h".to_string())]);
+    assert_eq!(modules[1].func_contexts.len(), 2);
+
+    // (module ...)で囲まないフィールドの並び
+    let parse = |wat: &str| {
+        let mut parser = Parser::from_text(wat);
+        parser.parse().unwrap();
+        parser.module
+    };
+    let bare = parse(r#"(type (func)) (func $f (type 0)) (export "f" (func $f))"#);
+    assert_eq!(bare.types, vec![(vec![], vec![])]);
+    assert_eq!(bare.funcs, vec![Func(0, vec![], FuncBody::Expr(Expr(vec![])))]);
+    assert_eq!(bare.exports, vec![Export("f".to_string(), ExportDesc::Func(0))]);
+    assert!(parse("") == Module::default());
+
+    let mut parser = Parser::from_bytes(b"(module) (module)");
+    assert!(parser.parse().is_err());
+    let mut parser = Parser::from_bytes(b"(module) (module)");
+    assert_eq!(parser.parse_modules().unwrap().len(), 2);
+}
+
+#[test]
+fn test_parse_stray_idchar() {
+    // 1回目の読み込みで読み飛ばすフィールドに予約語があっても、止まらずにエラーになる
+    for wat in &["(module (func !))", "(module (func #))", "(module (func nop ^))"] {
+        let mut parser = Parser::from_text(wat);
+        match parser.parse() {
+            Err(ParseError::NotMatch(tk!(TokenKind::Reserved(_)), _)) | Err(ParseError::Invalid(tk!(TokenKind::Reserved(_)))) => {},
+            res => panic!("{}: {:?}", wat, res),
+        }
+    }
+}
+
+#[test]
+fn test_parse_with_diagnostics() {
+    let mut parser = Parser::from_text(r#"(module
+      (func $a (result i32) i32.const 1)
+      (func $b (result i32) i32.const 0x1_0000_0000)
+      (memory 1)
+      (global i32 (i32.const "\zz"))
+      (foo)
+      stray
+      (func $c call $a drop)
+      (export "c" (func $c)))"#);
+    let diagnostics = parser.parse_with_diagnostics().unwrap_err();
+
+    let lines = diagnostics.iter().map(|d| d.loc.0).collect::<Vec<_>>();
+    assert_eq!(lines, vec![3, 5, 6, 7], "{:?}", diagnostics);
+    assert!(matches!(diagnostics[0].error, ParseError::NumCast(_)));
+    assert!(matches!(diagnostics[1].error, ParseError::Lex(_)));
+    assert!(matches!(diagnostics[2].error, ParseError::Invalid(_)));
+    assert!(matches!(diagnostics[3].error, ParseError::Invalid(_)));
+
+    // エラーのなかったフィールドは残り、エラーのあった定義は空のものに置き換わる
+    assert_eq!(parser.module.funcs.len(), 3);
+    assert_eq!(parser.module.funcs[1], Func(1, vec![], FuncBody::default()));
+    assert_eq!(parser.module.funcs[2].2, FuncBody::Expr(Expr(vec![Instr::Call(0), Instr::Drop])));
+    assert_eq!(parser.module.mems.len(), 1);
+    assert_eq!(parser.module.globals.len(), 1);
+    assert_eq!(parser.module.exports, vec![Export("c".to_string(), ExportDesc::Func(2))]);
+
+    // インデックスは、エラーのあったフィールドの後もずれない
+    let mut parser = Parser::from_text(r#"(type (func (param i33))) (type $t (func (result i32)))
+      (import "m" "f" (func (param i32 i33))) (import "m" "g" (func $g))
+      (func $a) (func $b i32.const 0x1_0000_0000) (func $c (type $t) i32.const 0) (export "c" (func $c))"#);
+    let diagnostics = parser.parse_with_diagnostics().unwrap_err();
+    assert_eq!(diagnostics.len(), 3, "{:?}", diagnostics);
+    // 読めなかった型は空の関数型になり、空の定義もそれを使う
+    assert_eq!(parser.module.types, vec![(vec![], vec![]), (vec![], vec![ValType::I32])]);
+    assert_eq!(parser.module.imports[0].2, ImportDesc::Func(0));
+    assert_eq!(parser.module.funcs[1].0, 0);
+    assert_eq!(parser.module.funcs.len(), 3);
+    assert_eq!(parser.module.funcs[2].0, 1);
+    assert_eq!(parser.module.exports, vec![Export("c".to_string(), ExportDesc::Func(4))]);
+
+    // 知らない命令名や記号だけの語も、パニックや無限ループにならずにエラーとして集める
+    let mut parser = Parser::from_text(r#"(module (func $f) (func nop !)
+      (func i32.foo) (func i32.div_s_x)
+      (func $g) (export "g" (func $g)))"#);
+    let diagnostics = parser.parse_with_diagnostics().unwrap_err();
+    let lines = diagnostics.iter().map(|d| d.loc.0).collect::<Vec<_>>();
+    assert_eq!(lines, vec![1, 2, 2], "{:?}", diagnostics);
+    for d in &diagnostics {
+        assert!(d.to_string().contains("found `"), "{}", d);
+    }
+    assert_eq!(parser.module.funcs.len(), 5);
+    assert_eq!(parser.module.exports, vec![Export("g".to_string(), ExportDesc::Func(4))]);
+
+    let mut parser = Parser::from_text("(module (func $f) (export \"f\" (func $f)))");
+    assert!(parser.parse_with_diagnostics().is_ok());
+    assert_eq!(parser.module.exports.len(), 1);
+}
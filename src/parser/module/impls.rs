@@ -1,5 +1,5 @@
 use std::fmt::Debug;
-use super::Module;
+use super::{Module, FuncBody};
 
 impl Debug for Module {
 fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -35,6 +35,21 @@ fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
    if self.start.is_some() { writeln!(f, "  start: {:?}", self.start)?; }
    if self.elems.len() > 0 { writeln!(f, "  elems: {:?}", self.elems)?; }
    if self.data.len() > 0 { writeln!(f, "  data: {:?}", self.data)?; }
+   if !self.customs.is_empty() {
+      writeln!(f, "  customs:")?;
+      for custom in &self.customs {
+         writeln!(f, "    {:?} after {:?} ({} bytes)", custom.name, custom.after, custom.data.len())?;
+      }
+   }
    writeln!(f, "}}")        
 }
 }
+
+impl Debug for FuncBody {
+fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+   match self {
+      FuncBody::Expr(expr) => write!(f, "{:?}", expr),
+      FuncBody::Lazy(lazy) => write!(f, "{:?}", lazy),
+   }
+}
+}
@@ -2,10 +2,13 @@ mod impls;
 
 use context::*;
 use instr::{FuncType, Expr, ValType};
+use mod2wasm::SectionId;
+use decoder::LazyExpr;
+use error::Error;
 
 pub use self::impls::*;
 
-#[derive(Default)]
+#[derive(Default, PartialEq)]
 pub struct Module {
     pub id: Option<String>,
     pub types: Vec<FuncType>,
@@ -18,48 +21,86 @@ pub struct Module {
     pub start: Option<Start>,
     pub elems: Vec<Elem>,
     pub data: Vec<Data>,
+    pub customs: Vec<Custom>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Import (pub Name, pub Name, pub ImportDesc);
 
 #[derive(Debug, Clone, PartialEq, Default)]
 pub struct Func (
     pub TypeIndex,  // type: typeuse
     pub Vec<ValType>,  // locals: vec(valtype)
-    pub Expr,  // body: expr
+    pub FuncBody,  // body: expr
 );
 
-#[derive(Debug)]
+// バイナリから遅延デコードした場合は、最初に使われる時までLazyのまま
+#[derive(Clone, PartialEq)]
+pub enum FuncBody {
+    Expr(Expr),
+    Lazy(LazyExpr),
+}
+
+impl FuncBody {
+    pub fn expr(&self) -> Result<&Expr, Error> {
+        match self {
+            FuncBody::Expr(expr) => Ok(expr),
+            FuncBody::Lazy(lazy) => lazy.expr(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub struct Table(pub TableType);
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, PartialEq)]
 pub struct Memory(pub MemType);
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Global(pub GlobalType, pub Expr);
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Export (pub String, pub ExportDesc);
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct Start (pub FuncIndex);
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, PartialEq)]
 pub struct Elem {
     pub table: TableIndex,
     pub offset: Expr,
     pub init: Vec<FuncIndex>,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, PartialEq)]
 pub struct Data {
     pub data: MemIndex,
     pub offset: Expr,
     pub init: DataString,
 }
 
-#[derive(Debug)]
+// 独自セクション
+// afterは直前にある既知のセクションのid(先頭にある場合はSectionId::Custom)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Custom {
+    pub name: Name,
+    pub data: Vec<u8>,
+    pub after: SectionId,
+}
+
+impl Custom {
+    pub fn after(name: Name, data: Vec<u8>, id: SectionId) -> Self {
+        Custom { name, data, after: id }
+    }
+
+    // idのセクションの直前は、1つ前の既知のセクションの直後と同じ位置
+    pub fn before(name: Name, data: Vec<u8>, id: SectionId) -> Self {
+        let after = SectionId::from_byte((id as u8).saturating_sub(1)).unwrap();
+        Custom { name, data, after }
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub enum ImportDesc {
     Func(TypeIndex),
     Table(TableType),
@@ -67,19 +108,19 @@ pub enum ImportDesc {
     Global(GlobalType),
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct TableType {
     pub limits: Limits,
     pub elem_type: ElemType,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, PartialEq)]
 pub struct MemType(pub Limits);
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, PartialEq)]
 pub struct GlobalType(pub Mutablity, pub ValType);
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ExportDesc {
     Func(FuncIndex),
     Table(TableIndex),
@@ -87,22 +128,23 @@ pub enum ExportDesc {
     Global(GlobalIndex),
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, PartialEq)]
 pub struct Limits {
     pub min: u32,
     pub max: Option<u32>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum ElemType { FuncRef, }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Mutablity { Const, Var, }
 
 pub type Name = String;
-pub type DataString = String;
+pub type DataString = Vec<u8>;
 
 
+impl Default for FuncBody { fn default() -> Self { FuncBody::Expr(Expr::default()) } }
 impl Default for Mutablity { fn default() -> Self { Mutablity::Const } }
 impl Default for ValType { fn default() -> Self { ValType::I32 } }
 
@@ -1,30 +1,117 @@
+use instr::*;
 use super::*;
 
 impl<R> Parser<R> where R: Read + Seek {
 
-    pub(super) fn parse_table(&mut self) -> Result<(), ParseError> {        
+    pub(super) fn parse_table(&mut self) -> Result<(), ParseError> {
+        self.match_keyword(Keyword::Table)?;
+
+        // table id
+        skip_optional_id!(self);
+        let tableidx = next_index!(self, Table, tables);
+
+        // 省略形のexportとimport
+        self.parse_inline_exports(ExportDesc::Table(tableidx))?;
+        let import = self.parse_inline_import()?;
+
+        // 省略形の funcref (elem funcidx*)
+        if import.is_none() {
+            if let kw!(Keyword::FuncRef) | kw!(Keyword::AnyFunc) = self.lookahead {
+                return self.parse_table_inline_elem(tableidx);
+            }
+        }
+
         // tabletype
         let table_type = self.parse_table_type()?;
 
-        self.module.tables.push(Table(table_type));
+        match import {
+            Some((module, name)) => self.push_import(Import(module, name, ImportDesc::Table(table_type)))?,
+            None => self.module.tables.push(Table(table_type)),
+        }
+
+        self.match_rparen()?;
 
         Ok(())
     }
 
+    // 要素の数に合わせた大きさのテーブルと、オフセット0の要素セグメントになる
+    fn parse_table_inline_elem(&mut self, tableidx: TableIndex) -> Result<(), ParseError> {
+        self.consume()?;
+        self.match_lparen()?;
+        let elem = self.lookahead.clone();
+        self.match_keyword(Keyword::Elem)?;
+
+        let mut init = vec![];
+        while !self.is_rparen()? {
+            init.push(self.resolve_id(&self.contexts[0].funcs.clone())?);
+        }
+        self.match_rparen()?;
+
+        // 大きすぎるときは`elem`の位置でエラーにする
+        let n = u32::try_from(init.len()).map_err(|_| ParseError::NumCast(elem))?;
+        let limits = Limits { min: n, max: Some(n) };
+        self.module.tables.push(Table(TableType { limits, elem_type: ElemType::FuncRef }));
+        self.module.elems.push(Elem {
+            table: tableidx,
+            offset: Expr(vec![Instr::I32Const(0)]),
+            init,
+        });
+
+        self.match_rparen()
+    }
+
     pub(super) fn parse_table_type(&mut self) -> Result<TableType, ParseError> {
         let mut table_type = TableType{ limits: Limits::default(), elem_type: ElemType::FuncRef };
-        self.match_keyword(Keyword::Table)?;
-
-        // table id
-        parse_optional_id!(self, self.contexts[0].tables);
 
         // limits
         table_type.limits = self.parse_limits()?;
 
-        // 'funcref'
-        self.match_keyword(Keyword::FuncRef)?;
+        // 'funcref'(旧名のanyfuncも受け付ける)
+        if let kw!(Keyword::AnyFunc) = self.lookahead {
+            self.consume()?;
+        } else {
+            self.match_keyword(Keyword::FuncRef)?;
+        }
 
-        self.match_rparen()?;
         Ok(table_type)
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_parse_table_inline_elem() {
+    let mut parser = Parser::from_text(r#"(module
+      (table $t (export "t") funcref (elem $f1 $f2 $f1))
+      (func $f1)
+      (func $f2))"#);
+    parser.parse().unwrap();
+
+    // 要素数がそのままテーブルの最小・最大の大きさになる
+    let limits = Limits { min: 3, max: Some(3) };
+    assert_eq!(parser.module.tables, vec![Table(TableType { limits, elem_type: ElemType::FuncRef })]);
+    assert_eq!(parser.module.elems, vec![Elem {
+        table: 0,
+        offset: Expr(vec![Instr::I32Const(0)]),
+        init: vec![0, 1, 0],
+    }]);
+    assert_eq!(parser.module.exports, vec![Export("t".to_string(), ExportDesc::Table(0))]);
+
+    let mut parser = Parser::from_text("(module (table funcref (elem)))");
+    parser.parse().unwrap();
+    assert_eq!(parser.module.tables[0].0.limits, Limits { min: 0, max: Some(0) });
+    assert_eq!(parser.module.elems[0].init, vec![]);
+}
+
+#[test]
+fn test_parse_table_anyfunc() {
+    // 旧名のanyfuncはfuncrefと同じ
+    let mut parser = Parser::from_text("(module (import \"m\" \"t\" (table 3 anyfunc)) (table 1 2 anyfunc))");
+    parser.parse().unwrap();
+    assert_eq!(parser.module.imports[0].2, ImportDesc::Table(TableType {
+        limits: Limits { min: 3, max: None },
+        elem_type: ElemType::FuncRef,
+    }));
+    assert_eq!(parser.module.tables, vec![Table(TableType {
+        limits: Limits { min: 1, max: Some(2) },
+        elem_type: ElemType::FuncRef,
+    })]);
+}
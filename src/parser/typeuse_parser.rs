@@ -2,14 +2,38 @@ use instr::*;
 use super::*;
 
 impl<R> Parser<R> where R: Read + Seek {
+    // typeuse: (type x)? param* result*
+    // (type x)を省略したときは、同じ関数型を探し、なければ型を追加する
     pub(super) fn parse_typeuse(&mut self, params: &mut Vec<ValType>, results: &mut Vec<ValType>) -> Result<TypeIndex, ParseError> {
-        self.match_lparen()?;
-        let typeidx = self.parse_typeuse_typeidx()?;
-        
-        if !self.is_rparen()? {
-            self.parse_signature(params, results)?;
+        let start = self.lookahead.clone();
+        let typeidx = if self.is_lparen()? && matches!(self.peek()?, kw!(Keyword::Type)) {
+            self.match_lparen()?;
+            Some(self.parse_typeuse_typeidx()?)
+        } else {
+            None
+        };
+
+        self.parse_signature(params, results)?;
+
+        let ft = (params.clone(), results.clone());
+        match typeidx {
+            Some(typeidx) => {
+                self.check_typeuse(start, typeidx, ft)?;
+
+                // 型だけを書いたときは、型定義のparamとresultを使う
+                let typedef = self.contexts[0].typedefs[typeidx as usize].clone();
+                if params.is_empty() && results.is_empty() {
+                    if self.contexts.len() > 1 {
+                        let locals = &mut self.contexts.last_mut().unwrap().locals;
+                        locals.extend(typedef.0.iter().map(|_| None));
+                    }
+                    *params = typedef.0;
+                    *results = typedef.1;
+                }
+                Ok(typeidx)
+            },
+            None => Ok(self.implicit_type(ft)),
         }
-        Ok(typeidx)
     }
 
     pub(super) fn parse_signature(&mut self, params: &mut Vec<ValType>, results: &mut Vec<ValType>) -> Result<(), ParseError> {
@@ -29,16 +53,31 @@ impl<R> Parser<R> where R: Read + Seek {
         Ok(())
     }
 
-    pub(super) fn check_typeuse(&mut self, typeidx: TypeIndex, tp: FuncType) -> Result<(), ParseError> {
-        let typedef = &self.contexts[0].typedefs[typeidx as usize];
-        if tp.0.len() == 0 && tp.1.len() == 0 { return Ok(()) }
+    fn check_typeuse(&mut self, start: Token, typeidx: TypeIndex, tp: FuncType) -> Result<(), ParseError> {
+        let typedef = match self.contexts[0].typedefs.get(typeidx as usize) {
+            Some(typedef) => typedef,
+            None => return Err(ParseError::UnknownType(start, typeidx)),
+        };
+        if tp.0.is_empty() && tp.1.is_empty() { return Ok(()) }
         if typedef != &tp {
-            Err(ParseError::InvalidTypeuseDef(self.lookahead.clone(), typedef.clone(), tp))
+            Err(ParseError::InvalidTypeuseDef(start, typedef.clone(), tp))
         } else {
             Ok(())
         }
     }
 
+    pub(super) fn implicit_type(&mut self, ft: FuncType) -> TypeIndex {
+        let context = &mut self.contexts[0];
+        if let Some(typeidx) = context.typedefs.iter().position(|typedef| typedef == &ft) {
+            return typeidx as TypeIndex;
+        }
+
+        self.module.types.push(ft.clone());
+        context.types.push(None);
+        context.typedefs.push(ft);
+        context.typedefs.len() as TypeIndex - 1
+    }
+
     fn parse_typeuse_typeidx(&mut self) -> Result<TypeIndex, ParseError> {
         self.match_keyword(Keyword::Type)?;
 
@@ -87,4 +126,52 @@ impl<R> Parser<R> where R: Read + Seek {
 
         Ok(vt)
     }
-}
\ No newline at end of file
+}
+#[test]
+fn test_parse_typeuse() {
+    let mut parser = Parser::from_text(r#"(module
+      (type (func (param i32) (result i32)))
+      (import "m" "g" (func $g (param i64)))
+      (func $f (param $x i32) (result i32)
+        local.get $x)
+      (func (type 0) (local $y i32)
+        local.get $y)
+      (func (result i32)
+        i32.const 0 call_indirect (param i32) (result i32)))"#);
+    parser.parse().unwrap();
+    let module = &parser.module;
+
+    // 同じ関数型はあるものを使い、なければ末尾に加える
+    assert_eq!(module.types, vec![
+        (vec![ValType::I32], vec![ValType::I32]),
+        (vec![ValType::I64], vec![]),
+        (vec![], vec![ValType::I32]),
+    ]);
+    assert_eq!(module.imports[0].2, ImportDesc::Func(1));
+    assert_eq!(module.funcs[0].0, 0);
+    assert_eq!(module.funcs[0].1, vec![ValType::I32]);
+
+    // (type x)だけなら、引数は型定義から補う
+    assert_eq!(module.funcs[1].0, 0);
+    assert_eq!(module.funcs[1].1, vec![ValType::I32, ValType::I32]);
+    assert_eq!(module.funcs[1].2.expr().unwrap().0, vec![Instr::LocalGet(1)]);
+    assert_eq!(parser.func_contexts[2].locals, vec![None, Some("y".to_string())]);
+
+    assert_eq!(module.funcs[2].0, 2);
+    assert_eq!(module.funcs[2].2.expr().unwrap().0, vec![Instr::I32Const(0), Instr::CallIndirect(0)]);
+
+    let mut parser = Parser::from_text("(module (func (type 3)))");
+    match parser.parse() {
+        Err(ParseError::UnknownType(_, 3)) => {},
+        res => panic!("{:?}", res),
+    }
+
+    let mut parser = Parser::from_text("(module (type (func)) (func (type 0) (param i32)))");
+    match parser.parse() {
+        Err(ParseError::InvalidTypeuseDef(_, def, found)) => {
+            assert_eq!(def, (vec![], vec![]));
+            assert_eq!(found, (vec![ValType::I32], vec![]));
+        },
+        res => panic!("{:?}", res),
+    }
+}
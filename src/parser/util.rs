@@ -11,43 +11,50 @@ macro_rules! parse_optional_id {
     }
 }
 
+// モジュールのインデックス空間の識別子は1回目の読み込みで集めてあるので、読み飛ばすだけ
+macro_rules! skip_optional_id {
+    ($this:ident) => {
+        if let tk!(TokenKind::Id(_)) = &$this.lookahead {
+            $this.consume()?;
+        }
+    }
+}
+
+// インポートを含めた、次に定義されるもののインデックス
+macro_rules! next_index {
+    ($this:ident, $desc:ident, $defs:ident) => {
+        ($this.module.imports.iter().filter(|import| matches!(import.2, ImportDesc::$desc(_))).count()
+            + $this.module.$defs.len()) as u32
+    }
+}
+
+// ラベルの識別子は、nameセクション用に出現順にも記録する
+// ラベルのインデックスは内側から数えるので、識別子の有無によらず先頭に加える
 macro_rules! parse_optional_label_id {
     ($this:ident, $v:expr) => {
         if let tk!(TokenKind::Id(s)) = &$this.lookahead {
             let new_s = s.clone();
+            $this.func_labels.push(Some(new_s.clone()));
             $v.insert(0, Some(new_s));
             $this.consume()?;
         } else {
-            $v.push(None);
+            $this.func_labels.push(None);
+            $v.insert(0, None);
         }
     }
 }
 
+// 次が'(' $field_typeで始まる間、$fで読む
+// 別のフィールドや畳み込み形式の命令のために、一致しなかった'('は読まずに残す
 macro_rules! parse_field {
     ($this:ident, $field_type:ident, $f:expr) => {
-        if !$this.is_rparen()? {            
-            if let tk!(TokenKind::LeftParen) = $this.lookahead {
+        while let tk!(TokenKind::LeftParen) = $this.lookahead {
+            if let kw!(Keyword::$field_type) = $this.peek()? {
                 $this.consume()?;
-            }
-            loop {
-                if let kw!(Keyword::$field_type) = &$this.lookahead {
-                    { $f }
-                    if let tk!(TokenKind::LeftParen) = $this.lookahead {
-                        let peeked = $this.peek()?;
-                        if let kw!(Keyword::$field_type) = peeked {
-                            $this.consume()?;
-
-                            continue;
-                        } else {
-                            break;
-                        }
-                    } else {
-                        break;
-                    }
-                } else {
-                    break;
-                }
+                { $f }
+            } else {
+                break;
             }
         }
     };
-}
\ No newline at end of file
+}
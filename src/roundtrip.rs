@@ -0,0 +1,411 @@
+// テキスト → Module → バイナリ → Module の往復で、構造が変わらないことを確かめる
+// 対象はwast/以下の.watと、乱数で生成したモジュール
+
+use instr::*;
+use context::*;
+use parser::*;
+use mod2wasm::*;
+use mod2wat::*;
+use decoder::Decoder;
+
+// 比較のための正規形
+// 同じ関数型は最初のものにまとめて型インデックスを振り直し、nameセクションは取り除く
+fn normalize(module: &mut Module) {
+    let mut types: Vec<FuncType> = vec![];
+    let mut remap = vec![];
+    for ft in &module.types {
+        match types.iter().position(|t| t == ft) {
+            Some(i) => remap.push(i as TypeIndex),
+            None => {
+                remap.push(types.len() as TypeIndex);
+                types.push(ft.clone());
+            },
+        }
+    }
+    let remap = |idx: &mut TypeIndex| if let Some(new) = remap.get(*idx as usize) { *idx = *new; };
+
+    module.types = types;
+    for import in &mut module.imports {
+        if let ImportDesc::Func(typeidx) = &mut import.2 { remap(typeidx); }
+    }
+    for func in &mut module.funcs {
+        remap(&mut func.0);
+        let mut expr = func.2.expr().unwrap().clone();
+        normalize_expr(&mut expr, &remap);
+        func.2 = FuncBody::Expr(expr);
+    }
+    for global in &mut module.globals {
+        normalize_expr(&mut global.1, &remap);
+    }
+    module.customs.retain(|c| c.name != "name");
+}
+
+fn normalize_expr(expr: &mut Expr, remap: &dyn Fn(&mut TypeIndex)) {
+    for instr in &mut expr.0 {
+        match instr {
+            Instr::CallIndirect(typeidx) => remap(typeidx),
+            Instr::Block(_, expr) | Instr::Loop(_, expr) => normalize_expr(expr, remap),
+            Instr::If(_, expr1, expr2) => {
+                normalize_expr(expr1, remap);
+                normalize_expr(expr2, remap);
+            },
+            _ => {},
+        }
+    }
+}
+
+// 正規形どうしの比較
+// NaNや-0.0も区別できるように、浮動小数点の定数はビット列で比べる
+fn same(a: &mut Module, b: &mut Module) -> bool {
+    let (exprs_a, exprs_b) = (take_exprs(a), take_exprs(b));
+    let same = a == b && exprs_a.len() == exprs_b.len()
+        && exprs_a.iter().zip(&exprs_b).all(|(a, b)| same_expr(a, b));
+    put_exprs(a, exprs_a);
+    put_exprs(b, exprs_b);
+    same
+}
+
+// 式以外を==で比べられるように、式を取り出して空にする
+fn take_exprs(module: &mut Module) -> Vec<Expr> {
+    let mut exprs = vec![];
+    for func in &mut module.funcs {
+        exprs.push(func.2.expr().unwrap().clone());
+        func.2 = FuncBody::default();
+    }
+    for global in &mut module.globals {
+        exprs.push(std::mem::take(&mut global.1));
+    }
+    for elem in &mut module.elems {
+        exprs.push(std::mem::take(&mut elem.offset));
+    }
+    for data in &mut module.data {
+        exprs.push(std::mem::take(&mut data.offset));
+    }
+    exprs
+}
+
+fn put_exprs(module: &mut Module, exprs: Vec<Expr>) {
+    let mut exprs = exprs.into_iter();
+    for func in &mut module.funcs {
+        func.2 = FuncBody::Expr(exprs.next().unwrap());
+    }
+    for global in &mut module.globals {
+        global.1 = exprs.next().unwrap();
+    }
+    for elem in &mut module.elems {
+        elem.offset = exprs.next().unwrap();
+    }
+    for data in &mut module.data {
+        data.offset = exprs.next().unwrap();
+    }
+}
+
+fn same_expr(a: &Expr, b: &Expr) -> bool {
+    a.0.len() == b.0.len() && a.0.iter().zip(&b.0).all(|(a, b)| match (a, b) {
+        (Instr::F32Const(x), Instr::F32Const(y)) => x.to_bits() == y.to_bits(),
+        (Instr::F64Const(x), Instr::F64Const(y)) => x.to_bits() == y.to_bits(),
+        (Instr::Block(rt_a, a), Instr::Block(rt_b, b)) |
+        (Instr::Loop(rt_a, a), Instr::Loop(rt_b, b)) => rt_a == rt_b && same_expr(a, b),
+        (Instr::If(rt_a, a1, a2), Instr::If(rt_b, b1, b2)) => rt_a == rt_b && same_expr(a1, b1) && same_expr(a2, b2),
+        _ => a == b,
+    })
+}
+
+// テキストをパースし、識別子をnameセクションにしてエンコードし、デコードし直す
+fn roundtrip(wat: &str) -> (Module, Module) {
+    let mut parser = Parser::from_text(wat);
+    if let Err(e) = parser.parse() {
+        panic!("parse error: {:?}\n{}", e, wat);
+    }
+
+    let options = EncodeOptions {
+        context: parser.contexts[0].clone(),
+        func_contexts: parser.func_contexts.clone(),
+        ..EncodeOptions::default()
+    };
+    let bytes = module_to_wasm(&parser.module, &options).unwrap();
+    let decoded = match Decoder::new(&bytes[..]).decode() {
+        Ok(module) => module,
+        Err(e) => panic!("decode error: {:?}\n{}", e, wat),
+    };
+
+    let mut parsed = parser.module;
+    let mut decoded = decoded;
+    normalize(&mut parsed);
+    normalize(&mut decoded);
+    (parsed, decoded)
+}
+
+// 決まった種から作る擬似乱数(xorshift)
+struct Gen(u64);
+
+impl Gen {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+
+    fn valtype(&mut self) -> ValType {
+        [ValType::I32, ValType::I64, ValType::F32, ValType::F64][self.below(4)].clone()
+    }
+
+    fn ivalsize(&mut self) -> ValSize {
+        if self.chance(50) { ValSize::V32 } else { ValSize::V64 }
+    }
+
+    fn valsign(&mut self) -> ValSign {
+        if self.chance(50) { ValSign::S } else { ValSign::U }
+    }
+
+    // 符号付きLEB128の境界をまたぐ値が出やすいように、ビット幅も乱数で決める
+    fn i64(&mut self) -> i64 {
+        let bits = self.below(64);
+        (self.next() as i64) >> bits
+    }
+
+    fn module(&mut self) -> Module {
+        let mut module = Module::default();
+
+        for _ in 0..1 + self.below(4) {
+            let params = (0..self.below(3)).map(|_| self.valtype()).collect();
+            let results = (0..self.below(2)).map(|_| self.valtype()).collect();
+            module.types.push((params, results));
+        }
+
+        if self.chance(50) {
+            let typeidx = self.below(module.types.len()) as TypeIndex;
+            module.imports.push(Import("env".into(), "f".into(), ImportDesc::Func(typeidx)));
+        }
+        if self.chance(50) {
+            let gt = GlobalType(Mutablity::Const, ValType::I32);
+            module.imports.push(Import("env".into(), "g".into(), ImportDesc::Global(gt)));
+        }
+
+        let limits = |g: &mut Gen| {
+            let min = g.below(4) as u32;
+            Limits { min, max: if g.chance(50) { Some(min + g.below(4) as u32) } else { None } }
+        };
+        module.tables.push(Table(TableType { limits: limits(self), elem_type: ElemType::FuncRef }));
+        module.mems.push(Memory(MemType(limits(self))));
+
+        for _ in 0..self.below(3) {
+            let mutablity = if self.chance(50) { Mutablity::Var } else { Mutablity::Const };
+            let (vt, init) = if self.chance(50) {
+                (ValType::I32, Instr::I32Const(self.i64() as u32))
+            } else {
+                (ValType::I64, Instr::I64Const(self.i64() as u64))
+            };
+            module.globals.push(Global(GlobalType(mutablity, vt), Expr(vec![init])));
+        }
+
+        let imported_funcs = module.imports.iter().filter(|i| matches!(i.2, ImportDesc::Func(_))).count();
+        let imported_globals = module.imports.len() - imported_funcs;
+        let funcs = 1 + self.below(3);
+        for _ in 0..funcs {
+            let typeidx = self.below(module.types.len()) as TypeIndex;
+            let mut locals = module.types[typeidx as usize].0.clone();
+            for _ in 0..self.below(4) {
+                locals.push(self.valtype());
+            }
+            let mut body = Body {
+                locals: locals.len() as u32,
+                globals: (imported_globals + module.globals.len()) as u32,
+                funcs: (imported_funcs + funcs) as u32,
+                types: module.types.len() as u32,
+                depth: 0,
+            };
+            let expr = self.expr(&mut body, 16);
+            module.funcs.push(Func(typeidx, locals, FuncBody::Expr(expr)));
+        }
+
+        for i in 0..self.below(3) {
+            let funcidx = self.below(imported_funcs + funcs) as FuncIndex;
+            module.exports.push(Export(format!("e{}", i), ExportDesc::Func(funcidx)));
+        }
+        if self.chance(30) {
+            module.start = Some(Start(self.below(imported_funcs + funcs) as FuncIndex));
+        }
+        if self.chance(50) {
+            let init = (0..self.below(200)).map(|_| self.below(imported_funcs + funcs) as FuncIndex).collect();
+            module.elems.push(Elem { table: 0, offset: Expr(vec![Instr::I32Const(self.below(8) as u32)]), init });
+        }
+        if self.chance(50) {
+            let init = (0..self.below(16)).map(|_| self.below(256) as u8).collect();
+            module.data.push(Data { data: 0, offset: Expr(vec![Instr::I32Const(self.below(64) as u32)]), init });
+        }
+
+        module
+    }
+
+    // ラベルの識別子(出現順)
+    // 識別子のないラベルや、外側と同じ名前のラベルも混ぜる
+    fn labels(&mut self, expr: &Expr) -> Context {
+        fn count(expr: &Expr) -> usize {
+            expr.0.iter().map(|instr| match instr {
+                Instr::Block(_, expr) | Instr::Loop(_, expr) => 1 + count(expr),
+                Instr::If(_, expr1, expr2) => 1 + count(expr1) + count(expr2),
+                _ => 0,
+            }).sum()
+        }
+        let labels = (0..count(expr))
+            .map(|_| if self.chance(50) { Some(format!("l{}", self.below(4))) } else { None })
+            .collect();
+        Context { labels, ..Context::default() }
+    }
+
+    fn expr(&mut self, body: &mut Body, fuel: usize) -> Expr {
+        Expr((0..self.below(fuel)).map(|_| self.instr(body, fuel / 2)).collect())
+    }
+
+    fn instr(&mut self, body: &mut Body, fuel: usize) -> Instr {
+        loop {
+            let instr = match self.below(20) {
+                0 if fuel > 0 => {
+                    let rt = (0..self.below(2)).map(|_| self.valtype()).collect();
+                    body.depth += 1;
+                    let expr = self.expr(body, fuel);
+                    body.depth -= 1;
+                    if self.chance(50) { Instr::Block(rt, expr) } else { Instr::Loop(rt, expr) }
+                },
+                1 if fuel > 0 => {
+                    let rt = (0..self.below(2)).map(|_| self.valtype()).collect();
+                    body.depth += 1;
+                    let expr1 = self.expr(body, fuel);
+                    let expr2 = self.expr(body, fuel);
+                    body.depth -= 1;
+                    Instr::If(rt, expr1, expr2)
+                },
+                2 => Instr::Br(self.below(body.depth as usize + 1) as LabelIndex),
+                3 => Instr::BrIf(self.below(body.depth as usize + 1) as LabelIndex),
+                4 => Instr::Call(self.below(body.funcs as usize) as FuncIndex),
+                5 => Instr::CallIndirect(self.below(body.types as usize) as TypeIndex),
+                6 if body.locals > 0 => {
+                    let x = self.below(body.locals as usize) as LocalIndex;
+                    [Instr::LocalGet(x), Instr::LocalSet(x), Instr::LocalTee(x)][self.below(3)].clone()
+                },
+                7 if body.globals > 0 => Instr::GlobalGet(self.below(body.globals as usize) as GlobalIndex),
+                8 => Instr::I32Const(self.i64() as u32),
+                9 => Instr::I64Const(self.i64() as u64),
+                10 => Instr::IBinOp(self.ivalsize(), [IBinOp::Add, IBinOp::Sub, IBinOp::Mul, IBinOp::Div(self.valsign()),
+                    IBinOp::Rem(self.valsign()), IBinOp::And, IBinOp::Or, IBinOp::Xor, IBinOp::Shl,
+                    IBinOp::Shr(self.valsign()), IBinOp::Rotl, IBinOp::Rotr][self.below(12)].clone()),
+                11 => Instr::IRelOp(self.ivalsize(), [IRelOp::Eq, IRelOp::Ne, IRelOp::Lt(self.valsign()),
+                    IRelOp::Gt(self.valsign()), IRelOp::Le(self.valsign()), IRelOp::Ge(self.valsign())][self.below(6)].clone()),
+                12 => Instr::ITestOp(self.ivalsize(), ITestOp::Eqz),
+                13 => Instr::IUnOp(self.ivalsize(), [IUnOp::Clz, IUnOp::Ctz, IUnOp::Popcnt][self.below(3)].clone()),
+                14 => Instr::FBinOp(self.ivalsize(), [FBinOp::Add, FBinOp::Sub, FBinOp::Mul, FBinOp::Div,
+                    FBinOp::Min, FBinOp::Max, FBinOp::Copysign][self.below(7)].clone()),
+                15 => Instr::CvtOp([CvtOp::I32WrapFromI64, CvtOp::I64ExtendFromI32(self.valsign()),
+                    CvtOp::ITruncFromF(self.ivalsize(), self.ivalsize(), self.valsign()),
+                    CvtOp::FConvertFromI(self.ivalsize(), self.ivalsize(), self.valsign()),
+                    CvtOp::F32DemoteFromF64, CvtOp::F64PromoteFromF32][self.below(6)].clone()),
+                16 => [Instr::Drop, Instr::Select, Instr::Nop][self.below(3)].clone(),
+                17 => {
+                    let kind = self.below(8);
+                    let vt = self.valtype();
+                    let natural = match kind {
+                        0 | 1 => if matches!(vt, ValType::I32 | ValType::F32) { 2 } else { 3 },
+                        2 | 5 => 0,
+                        3 | 6 => 1,
+                        _ => 2,
+                    };
+                    let memarg = MemArg {
+                        align: self.below(natural + 1) as u32,
+                        offset: if self.chance(50) { 0 } else { self.next() as u32 },
+                    };
+                    match kind {
+                        0 => Instr::Load(vt, memarg),
+                        1 => Instr::Store(vt, memarg),
+                        2 => Instr::ILoad8(self.ivalsize(), self.valsign(), memarg),
+                        3 => Instr::ILoad16(self.ivalsize(), self.valsign(), memarg),
+                        4 => Instr::I64Load32(self.valsign(), memarg),
+                        5 => Instr::IStore8(self.ivalsize(), memarg),
+                        6 => Instr::IStore16(self.ivalsize(), memarg),
+                        _ => Instr::I64Store32(memarg),
+                    }
+                },
+                // NaNのペイロードや非正規化数も出るように、ビット列から作る
+                18 => Instr::F32Const(f32::from_bits(self.next() as u32 >> self.below(32))),
+                19 => Instr::F64Const(f64::from_bits(self.next() >> self.below(64))),
+                _ => continue,
+            };
+            return instr;
+        }
+    }
+}
+
+// 生成中の関数から参照できるインデックスの数と、ブロックの深さ
+struct Body {
+    locals: u32,
+    globals: u32,
+    funcs: u32,
+    types: u32,
+    depth: u32,
+}
+
+#[test]
+fn test_roundtrip_wast() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/wast");
+    let mut paths = std::fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "wat"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    assert!(!paths.is_empty());
+
+    for path in paths {
+        let wat = std::fs::read_to_string(&path).unwrap();
+        let (mut parsed, mut decoded) = roundtrip(&wat);
+        assert!(same(&mut parsed, &mut decoded), "{}:\n{:?}\n{:?}", path.display(), parsed, decoded);
+
+        // 識別子つきでテキストに戻しても、同じモジュールとして読み直せる
+        let mut parser = Parser::from_text(&wat);
+        parser.parse().unwrap();
+        for folded in [false, true] {
+            let options = WatOptions {
+                folded,
+                context: parser.contexts[0].clone(),
+                func_contexts: parser.func_contexts.clone(),
+            };
+            let printed = module_to_wat(&parser.module, &options).unwrap();
+            let (mut reparsed, _) = roundtrip(&printed);
+            assert!(same(&mut parsed, &mut reparsed), "{}:\n{}\n{:?}", path.display(), printed, reparsed);
+        }
+    }
+}
+
+#[test]
+fn test_roundtrip_generated() {
+    for seed in 1..200 {
+        let mut gen = Gen((seed as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        let mut module = gen.module();
+        normalize(&mut module);
+        // func_contextsはインポートした関数も含めたインデックスで引く
+        let imported_funcs = module.imports.iter().filter(|i| matches!(i.2, ImportDesc::Func(_))).count();
+        let mut func_contexts = vec![Context::default(); imported_funcs];
+        for func in &module.funcs {
+            func_contexts.push(gen.labels(func.2.expr().unwrap()));
+        }
+
+        for folded in [false, true] {
+            let options = WatOptions { folded, context: Context::default(), func_contexts: func_contexts.clone() };
+            let wat = module_to_wat(&module, &options).unwrap();
+
+            let (mut parsed, mut decoded) = roundtrip(&wat);
+            assert!(same(&mut parsed, &mut decoded), "seed {}:\n{}", seed, wat);
+
+            // テキストを経由しても、生成したモジュールと同じになる
+            assert!(same(&mut module, &mut decoded), "seed {}:\n{}\n{:?}", seed, wat, decoded);
+        }
+    }
+}
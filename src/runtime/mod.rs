@@ -3,9 +3,11 @@ mod structure;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::convert::TryInto;
+use std::collections::HashSet;
 
 use instr::*;
 use parser::*;
+use error::Error;
 
 pub use self::structure::*;
 
@@ -31,6 +33,8 @@ macro_rules! execute_ibinop {
 pub struct Runtime {
     pub store: Store,
     pub stack: Vec<StackEntry>,
+    // 検証済みの関数(最初に呼ばれた時に検証する)
+    validated: HashSet<FuncAddr>,
 }
 
 impl Runtime {
@@ -46,7 +50,8 @@ impl Runtime {
         runtime
     }
 
-    pub fn instantiate(&mut self, module: &Module, extern_vals: Vec<ExternVal>) {
+    // 関数本体のデコードに失敗したときはErrを返す(遅延デコードでは、最初に呼ばれた時に分かる)
+    pub fn instantiate(&mut self, module: &Module, extern_vals: Vec<ExternVal>) -> Result<(), Error> {
         // 5. Let "val^*" be the vector of <global> initialization <values> determined by "module" and "externval^n".
         // These may be calculated as follows.
 
@@ -72,8 +77,13 @@ impl Runtime {
             let func_addr = module_inst.borrow().func_addrs[module.start.clone().unwrap().0 as usize];
 
             // (c) <Invoke> the function instance at "funcaddr".
-            self.invoke_function(func_addr);
+            if let Err(e) = self.invoke_function(func_addr) {
+                // 途中で積んだラベルやフレームは残さない
+                self.stack.clear();
+                return Err(e);
+            }
         }
+        Ok(())
     }
 
     fn allocate_module(&mut self, module: &Module, extern_vals: Vec<ExternVal>, _vals: Vec<Val>) -> Rc<RefCell<ModuleInst>> {
@@ -122,7 +132,7 @@ impl Runtime {
         address
     }
 
-    fn invoke_function(&mut self, func_addr: FuncAddr) {
+    fn invoke_function(&mut self, func_addr: FuncAddr) -> Result<(), Error> {
         // 2. Let f be the <function instance>, S.'funcs'[a].
         let f = &self.store.funcs[func_addr];
 
@@ -135,7 +145,15 @@ impl Runtime {
                 let local_types = &code.1;
 
                 // 6. Let "instr^*" be the expression f.'code'.'body'.
-                let instrs = &code.2;
+                // 遅延デコードの場合は、ここで初めてデコードされる
+                let instrs = code.2.expr()?;
+                if self.validated.insert(func_addr) {
+                    let funcs = module_inst.borrow().func_addrs.len();
+                    if let Err(e) = validate_expr(instrs, local_types.len(), funcs, 1) {
+                        self.validated.remove(&func_addr);
+                        return Err(e);
+                    }
+                }
 
                 // 8. Pop the values "val^n" from the stack.
                 let mut stack_values = vec![];
@@ -169,7 +187,7 @@ impl Runtime {
 
                 // 12. <Execute> the instruction 'block'[t_2^m] "instr^*" 'end'.
                 let block_instr = Instr::Block(ft.1.clone(), instrs.clone());
-                self.execute_instr(block_instr);
+                self.execute_instr(block_instr)?;
             },
 
             FuncInst::Host { func_type: _ft, host_code } => {
@@ -186,13 +204,14 @@ impl Runtime {
         }
 
         self.return_from_function();
+        Ok(())
     }
 
-    fn execute_instr(&mut self, instr: Instr) {
+    fn execute_instr(&mut self, instr: Instr) -> Result<(), Error> {
         match instr {
-            Instr::If(rt, instrs1, instrs2) => self.execute_if(rt, &instrs1, &instrs2),
-            Instr::Block(rt, instrs) => self.execute_block(rt, &instrs),
-            Instr::Call(x) => self.execute_call(x.try_into().unwrap()),
+            Instr::If(rt, instrs1, instrs2) => return self.execute_if(rt, &instrs1, &instrs2),
+            Instr::Block(rt, instrs) => return self.execute_block(rt, &instrs),
+            Instr::Call(x) => return self.execute_call(x.try_into().unwrap()),
 
             Instr::LocalGet(idx) => self.execute_local_get(idx.try_into().unwrap()),
 
@@ -204,9 +223,10 @@ impl Runtime {
             Instr::IRelOp(vs, irelop) => self.execute_irelop(vs, &irelop),
             _ => {},
         }
+        Ok(())
     }
 
-    fn execute_if(&mut self, result_type: ResultType, expr1: &Expr, expr2: &Expr) {
+    fn execute_if(&mut self, result_type: ResultType, expr1: &Expr, expr2: &Expr) -> Result<(), Error> {
         // 2. Pop the value i32.const 𝑐 from the stack.
         if let StackEntry::Val(Val::I32Const(c)) = self.stack.pop().unwrap() {
             // 3. Let 𝑛 be the arity |𝑡?| of the result type 𝑡?.
@@ -216,14 +236,15 @@ impl Runtime {
             let label = StackEntry::Label(n.try_into().unwrap(), vec![]);
 
             if c != 0 {
-                self.enter_expr(expr1, label);
+                self.enter_expr(expr1, label)?;
             } else {
-                self.enter_expr(expr2, label);
+                self.enter_expr(expr2, label)?;
             }
         }
+        Ok(())
     }
 
-    fn execute_block(&mut self, result_type: ResultType, expr: &Expr) {
+    fn execute_block(&mut self, result_type: ResultType, expr: &Expr) -> Result<(), Error> {
         // 1. Let "n" be the arity |t^?| of the <result type> "t^?".
         let n = result_type.len();
 
@@ -231,10 +252,10 @@ impl Runtime {
         let label = StackEntry::Label(n.try_into().unwrap(), vec![]);
 
         // 3. <Enter> the block "instr^*" with label L.
-        self.enter_expr(expr, label);
+        self.enter_expr(expr, label)
     }
 
-    fn execute_call(&mut self, x: FuncAddr) {
+    fn execute_call(&mut self, x: FuncAddr) -> Result<(), Error> {
         // 1. Let F be the current frame.
         let (_, current_frame) = self.get_current_frame();
 
@@ -242,7 +263,7 @@ impl Runtime {
         let func_addr = current_frame.module.borrow_mut().func_addrs[x];
 
         // 4. <Invoke> the function instance at address a.
-        self.invoke_function(func_addr);
+        self.invoke_function(func_addr)
     }
 
     fn execute_local_get(&mut self, idx: usize) {
@@ -306,17 +327,18 @@ impl Runtime {
         }
     }
 
-    fn enter_expr(&mut self, expr: &Expr, label: StackEntry) {
+    fn enter_expr(&mut self, expr: &Expr, label: StackEntry) -> Result<(), Error> {
 
         // 1. Push L to the stack.
         self.stack.push(label);
 
         // 2. Jump to the start of the instruction sequence <instr^*>.
         for instr in expr.0.iter() {
-            self.execute_instr(instr.clone());
+            self.execute_instr(instr.clone())?;
         }
 
         self.exit_exprs();
+        Ok(())
     }
 
     fn exit_exprs(&mut self) {
@@ -386,6 +408,30 @@ impl Runtime {
     }
 }
 
+// 実行中に範囲外を参照しないように、インデックスだけを確かめる
+// labelsは外側のラベルの数(関数本体のブロックを含む)
+fn validate_expr(expr: &Expr, locals: usize, funcs: usize, labels: usize) -> Result<(), Error> {
+    for instr in &expr.0 {
+        match instr {
+            Instr::Block(_, expr) | Instr::Loop(_, expr) => validate_expr(expr, locals, funcs, labels + 1)?,
+            Instr::If(_, expr1, expr2) => {
+                validate_expr(expr1, locals, funcs, labels + 1)?;
+                validate_expr(expr2, locals, funcs, labels + 1)?;
+            },
+            Instr::Br(l) | Instr::BrIf(l) if *l as usize >= labels => return Err(Error::Invalid("unknown label")),
+            Instr::BrTable(ls, l) if ls.iter().chain(Some(l)).any(|l| *l as usize >= labels) => {
+                return Err(Error::Invalid("unknown label"));
+            },
+            Instr::Call(x) if *x as usize >= funcs => return Err(Error::Invalid("unknown function")),
+            Instr::LocalGet(x) | Instr::LocalSet(x) | Instr::LocalTee(x) if *x as usize >= locals => {
+                return Err(Error::Invalid("unknown local"));
+            },
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
 use std::fmt::Debug;
 use self::structure::ModuleInst;
 
@@ -420,8 +466,8 @@ impl Debug for ModuleInst {
 impl Debug for FuncInst {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            FuncInst::Normal{ func_type: ft, module: _, code: Func(_, locals, Expr(instrs)) } => {
-                write!(f, "NORMAL<type:{:?} locals:{:?} {:?}>", ft, locals, instrs)
+            FuncInst::Normal{ func_type: ft, module: _, code: Func(_, locals, body) } => {
+                write!(f, "NORMAL<type:{:?} locals:{:?} {:?}>", ft, locals, body)
             }
             FuncInst::Host{ func_type: ft, host_code: hc } => {
                 write!(f, "HOST<type:{:?} {}>", ft, hc)
@@ -429,3 +475,33 @@ impl Debug for FuncInst {
         }
     }
 }
+
+#[test]
+fn test_invoke_broken_lazy_body() {
+    let bytes = [
+        0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00,
+        0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+        0x03, 0x02, 0x01, 0x00,
+        // start section: func 0
+        0x08, 0x01, 0x00,
+        // code section: 不正なオペコード
+        0x0A, 0x05, 0x01, 0x03, 0x00, 0xFF, 0x0B,
+    ];
+    let module = ::module_decode_lazy(&bytes[..]).unwrap();
+
+    // 本体は呼ばれた時にデコードされ、エラーはpanicせずに返る
+    let mut rt = Runtime::new(None);
+    match rt.instantiate(&module, vec![]) {
+        Err(Error::Decode(e)) => assert_eq!(e.kind, ::decoder::DecodeErrorKind::InvalidOpcode(0xFF)),
+        res => panic!("{:?}", res),
+    }
+    assert!(rt.stack.is_empty());
+
+    // デコードできても、検証に通らなければ実行しない
+    let mut bytes = bytes[..bytes.len() - 7].to_vec();
+    // code section: local.get 5 drop
+    bytes.extend(&[0x0A, 0x07, 0x01, 0x05, 0x00, 0x20, 0x05, 0x1A, 0x0B]);
+    let module = ::module_decode_lazy(&bytes[..]).unwrap();
+    let mut rt = Runtime::new(None);
+    assert_eq!(rt.instantiate(&module, vec![]), Err(Error::Invalid("unknown local")));
+}
@@ -0,0 +1,32 @@
+(module
+  ;; labels: named outside unnamed, shadowed names
+  (func $f (param i32) (result i32)
+    block $a (result i32)
+      block
+        local.get 0
+        br_if $a
+        br 1
+      end
+      loop $l
+        block $a
+          br $a
+          br 2
+        end
+        br_if $l
+      end
+      i32.const 0
+    end
+  )
+  (func
+    block $out
+      i32.const 1
+      if
+        block
+          br $out
+        end
+      else
+        br 0
+      end
+    end
+  )
+)